@@ -1,6 +1,7 @@
 /// Long-running example showing how to use the atproto-oauth crate with a web server
 mod schema;
 mod templates;
+#[allow(dead_code)]
 mod codegen;
 
 use atproto_oauth::{
@@ -8,7 +9,13 @@ use atproto_oauth::{
     OAuthClientBuilder, AtprotoOAuthClient, AuthorizeOptions, CallbackParams, 
     KnownScope, Scope, Handle, Did,
     // Database and agent types
-    Agent, PoolBuilder, Pool,
+    Agent, Pool, open_database,
+    // Signed session cookies and the authenticated-user extractor
+    SessionCookies, SessionCookieKey, AuthenticatedUser,
+    // Per-device OAuth sessions
+    new_session_id, with_session_id, DeviceSession,
+    // Handle verification
+    IdentityVerifier,
     // Storage types - not needed anymore
     // Web framework types
     Query, State, Redirect, Router,
@@ -23,7 +30,7 @@ use axum::{
     http::{StatusCode, HeaderMap},
     response::Html,
     // Form handling
-    extract::Form,
+    extract::{Form, FromRef},
 };
 use schema::{create_tables_in_database, BlogPostFromDb};
 use templates::{HomeTemplate, SuccessTemplate, ErrorTemplate, UserInfo, BlogListTemplate, BlogCreateTemplate, BlogEditTemplate, BlogViewTemplate, BlogPostInfo};
@@ -38,10 +45,25 @@ use serde::{Deserialize, Serialize};
 struct AppState {
     oauth_client: Arc<AtprotoOAuthClient>,
     db_pool: Arc<Pool>,
+    session_cookies: Arc<SessionCookies>,
+    identity_verifier: Arc<IdentityVerifier>,
+}
+
+// Let the AuthenticatedUser extractor pull what it needs out of the app state
+impl FromRef<AppState> for Arc<AtprotoOAuthClient> {
+    fn from_ref(state: &AppState) -> Self {
+        state.oauth_client.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<SessionCookies> {
+    fn from_ref(state: &AppState) -> Self {
+        state.session_cookies.clone()
+    }
 }
 
 async fn register_custom_lexicon(
-    agent: &Agent<impl SessionManager + Send + Sync>,
+    agent: &Agent<impl SessionManager + Sync>,
     did: &str, 
     lexicon_nsid: &str
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
@@ -88,10 +110,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("🚀 Starting AT Protocol OAuth Example Server");
 
     // Create database connection
-    let db_pool = PoolBuilder::new()
-        .path("oauth_example.sqlite3")
-        .open()
-        .await?;
+    let db_pool = open_database("oauth_example.sqlite3").await?;
 
     // Create database tables - this example shows how to integrate OAuth tables 
     // with your application-specific schema. See schema.rs for implementation details.
@@ -99,19 +118,32 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("✅ Database initialized");
 
     // Build OAuth client with the builder pattern
-    let oauth_client = OAuthClientBuilder::new()
+    let oauth_builder = OAuthClientBuilder::new()
         .host("127.0.0.1")
         .port(3000)
-        .db_pool(db_pool.clone())
-        .build()?;
+        .db_pool(db_pool.clone());
+    // Handles are only displayed once they resolve back to the user's DID
+    let identity_verifier = oauth_builder.identity_verifier()?;
+    let oauth_client = oauth_builder.build()?;
 
     println!("✅ OAuth client created successfully!");
     println!("🔗 Redirect URI: http://127.0.0.1:3000/oauth/callback");
 
-    // Create app state with both OAuth client and database pool
+    // Session cookies are signed so browsers cannot forge a DID. Set SESSION_SECRET
+    // (at least 32 bytes) to keep sessions valid across restarts.
+    let session_key = match std::env::var("SESSION_SECRET") {
+        Ok(secret) => SessionCookieKey::new("k1", secret)?,
+        Err(_) => SessionCookieKey::generate("k1")?,
+    };
+    // The example is served over plain http on 127.0.0.1, so cookies cannot be Secure
+    let session_cookies = SessionCookies::new(db_pool.clone(), session_key).secure(false);
+
+    // Create app state with the OAuth client, database pool and session cookies
     let app_state = AppState {
         oauth_client,
         db_pool: Arc::new(db_pool),
+        session_cookies: Arc::new(session_cookies),
+        identity_verifier: Arc::new(identity_verifier),
     };
 
     // Create router with OAuth and blog CRUD endpoints
@@ -155,50 +187,28 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 #[derive(Clone, Debug)]
 struct SessionData {
     did: String,
+    /// The device session the request is authenticated with
+    session_id: String,
 }
 
-/// Extract session data from request headers or cookies
+/// Extract session data from the signed session cookie or an `Authorization: Bearer` token
 async fn extract_session(
     headers: HeaderMap,
-    State(_app_state): State<AppState>,
+    State(app_state): State<AppState>,
 ) -> Result<SessionData, StatusCode> {
-    // Try to get DID from Authorization header first
-    let did_str = if let Some(auth_header) = headers.get("Authorization") {
-        // Bearer token authentication (for API endpoints)
-        auth_header
-            .to_str()
-            .ok()
-            .and_then(|s| s.strip_prefix("Bearer "))
-            .map(|s| s.to_string())
-    } else if let Some(cookie_header) = headers.get("Cookie") {
-        // Cookie-based authentication (for form endpoints)
-        cookie_header
-            .to_str()
-            .ok()
-            .and_then(|cookies| {
-                // Parse cookies to find session_did
-                for cookie in cookies.split(';') {
-                    let cookie = cookie.trim();
-                    if let Some(did) = cookie.strip_prefix("session_did=") {
-                        return Some(did.to_string());
-                    }
-                }
-                None
-            })
-    } else {
-        None
-    };
+    let session = app_state
+        .session_cookies
+        .session_from_headers(&headers)
+        .await
+        .map_err(|e| {
+            println!("[AUTH][FAIL] {}", e);
+            StatusCode::UNAUTHORIZED
+        })?;
 
-    let did_str = did_str.ok_or(StatusCode::UNAUTHORIZED)?;
-    
-    // Validate DID format
-    if !did_str.starts_with("did:") {
-        return Err(StatusCode::UNAUTHORIZED);
-    }
-    
-    // Just return the DID - we'll create agents on demand when needed
+    // Keep the device session so agents created on demand use this device's tokens
     Ok(SessionData {
-        did: did_str,
+        did: session.did.to_string(),
+        session_id: session.session_id,
     })
 }
 
@@ -288,7 +298,7 @@ async fn login_handler(
     })?;
 
     // Start OAuth flow
-    match (&*app_state.oauth_client).authorize(
+    match app_state.oauth_client.authorize(
         &handle,
         AuthorizeOptions {
             scopes: vec![
@@ -326,7 +336,9 @@ async fn callback_handler(
     let state_preview = params.state.as_ref().map(|s| s.chars().take(8).collect::<String>()).unwrap_or_else(|| "<none>".to_string());
     println!("[CALLBACK][START] uri='{}' code_preview='{}' state_preview='{}' timestamp={}ms", original_uri.0, code_preview, state_preview, chrono::Utc::now().timestamp_millis());
     
-    match (&*app_state.oauth_client).callback(params).await {
+    // Each login gets its own device session, so logging in elsewhere keeps this one intact
+    let session_id = new_session_id();
+    match with_session_id(session_id.clone(), app_state.oauth_client.callback(params)).await {
         Ok((session, _)) => {
             println!("[CALLBACK][SUCCESS] Session established in {}ms", start.elapsed().as_millis());
             
@@ -334,6 +346,15 @@ async fn callback_handler(
             let user_info = match session.did().await {
                 Some(did) => {
                     println!("[CALLBACK][SESSION] DID={}", did.as_str());
+
+                    // The profile's handle is only a claim; show it once it resolves back to the DID
+                    let verified_handle = match app_state.identity_verifier.verify_did(&did).await {
+                        Ok(identity) => identity.handle_or_invalid().to_string(),
+                        Err(e) => {
+                            println!("[CALLBACK][IDENTITY][WARN] verification failed error={}", e);
+                            atproto_oauth::INVALID_HANDLE.to_string()
+                        }
+                    };
                     
                     // Create agent to fetch profile
                     let agent = Agent::new(session);
@@ -351,9 +372,9 @@ async fn callback_handler(
                         .await
                     {
                         Ok(profile) => {
-                            println!("[CALLBACK][PROFILE][SUCCESS] handle={} followers={:?} follows={:?}", profile.handle.as_str(), profile.followers_count, profile.follows_count);
+                            println!("[CALLBACK][PROFILE][SUCCESS] handle={} followers={:?} follows={:?}", verified_handle, profile.followers_count, profile.follows_count);
                             Some(UserInfo {
-                                handle: Some(profile.handle.as_str().to_string()),
+                                handle: Some(verified_handle),
                                 display_name: profile.display_name.clone(),
                                 did: Some(did.as_str().to_string()),
                                 followers_count: profile.followers_count.map(|c| c as u32),
@@ -365,7 +386,7 @@ async fn callback_handler(
                         Err(e) => {
                             println!("[CALLBACK][PROFILE][WARN] fetch failed error={}", e);
                             Some(UserInfo {
-                                handle: None,
+                                handle: Some(verified_handle),
                                 display_name: None,
                                 did: Some(did.as_str().to_string()),
                                 followers_count: None,
@@ -385,11 +406,13 @@ async fn callback_handler(
             // Create response with session cookie
             let mut headers = HeaderMap::new();
             
-            // Set session cookie with the DID
+            // Set a signed session cookie bound to the stored OAuth session
             if let Some(ref info) = user_info {
                 if let Some(ref did) = info.did {
-                    let cookie_value = format!("session_did={}; Path=/; HttpOnly; SameSite=Lax", did);
-                    headers.insert("Set-Cookie", cookie_value.parse().unwrap());
+                    if let Ok(did) = Did::new(did.clone()) {
+                        let cookie_value = app_state.session_cookies.issue(&did, &session_id);
+                        headers.insert("Set-Cookie", cookie_value.parse().unwrap());
+                    }
                 }
             }
 
@@ -415,6 +438,7 @@ async fn callback_handler(
 }
 
 /// Creates a sample blog post to demonstrate the generated codegen types
+#[allow(dead_code)]
 async fn create_sample_blog_post(pool: &atproto_oauth::Pool, author_did: &str) -> Result<(), Box<dyn std::error::Error>> {
     println!("🔬 Creating sample blog post using generated codegen types...");
 
@@ -485,21 +509,13 @@ The lexicon ensures type safety and validation according to the AT Protocol sche
 
 /// Create a new blog post and store it both locally and on the PDS
 async fn create_blog_post(
-    headers: HeaderMap,
+    user: AuthenticatedUser,
     State(app_state): State<AppState>,
     Json(request): Json<CreateBlogPostRequest>,
 ) -> Result<Json<BlogPostResponse>, (StatusCode, Json<ApiError>)> {
-    // Authenticate user
-    let session = extract_session(headers, State(app_state.clone())).await.map_err(|_| {
-        (StatusCode::UNAUTHORIZED, Json(ApiError {
-            error: "unauthorized".to_string(),
-            message: "Authentication required".to_string(),
-        }))
-    })?;
-
     // Generate a unique record key (rkey) for this blog post
     let rkey = format!("post-{}", chrono::Utc::now().timestamp_millis());
-    let uri = format!("at://{}/com.crabdance.nandi.post/{}", session.did, rkey);
+    let uri = format!("at://{}/com.crabdance.nandi.post/{}", user.did.as_str(), rkey);
 
     // Create BlogPostRecordData from request
     let record_data = BlogPostRecordData {
@@ -515,7 +531,7 @@ async fn create_blog_post(
     // Convert to database model
     let blog_post = BlogPostFromDb::from_codegen_record_data(
         uri.clone(),
-        session.did.clone(),
+        user.did.to_string(),
         &record_data
     ).map_err(|e| {
         (StatusCode::INTERNAL_SERVER_ERROR, Json(ApiError {
@@ -524,9 +540,25 @@ async fn create_blog_post(
         }))
     })?;
 
-    // TODO: Store in PDS using AT Protocol (requires proper authenticated Agent)
-    // For now, we'll just store locally in the database
     println!("📝 Creating blog post: {}", blog_post.title);
+
+    // Store in the user's PDS with the authenticated agent
+    let mut record_value = serde_json::to_value(&record_data).unwrap_or_else(|_| serde_json::json!({}));
+    if let serde_json::Value::Object(obj) = &mut record_value {
+        obj.insert("$type".to_string(), serde_json::Value::String("com.crabdance.nandi.post".to_string()));
+    }
+    let create_record_input = atrium_api::com::atproto::repo::create_record::InputData {
+        repo: user.did.clone().into(),
+        collection: Nsid::new("com.crabdance.nandi.post".to_string()).unwrap(),
+        rkey: Some(RecordKey::new(rkey.clone()).unwrap()),
+        validate: Some(false),
+        swap_commit: None,
+        record: record_value.try_into_unknown().unwrap(),
+    };
+    match user.agent.api.com.atproto.repo.create_record(create_record_input.into()).await {
+        Ok(response) => println!("✅ Stored blog post in PDS: {}", response.data.uri),
+        Err(e) => println!("⚠️  Failed to store blog post in PDS (saving locally anyway): {}", e),
+    }
     
     // Store locally in database
     let db_pool_arc = Arc::new(app_state.db_pool.clone());
@@ -679,9 +711,13 @@ async fn update_blog_post(
         // Only proceed if this is our custom collection (avoid touching unrelated URIs)
         if collection == "com.crabdance.nandi.post" {
             if let Ok(did_parsed) = Did::new(session.did.clone()) {
-                match app_state.oauth_client.restore(&did_parsed).await {
+                let restored = with_session_id(
+                    session.session_id.clone(),
+                    app_state.oauth_client.restore(&did_parsed),
+                ).await;
+                match restored {
                     Ok(oauth_session) => {
-                        let agent = Agent::new(oauth_session);
+                        let agent = Agent::new(DeviceSession::new(oauth_session, session.session_id.clone()));
 
                         // Build record JSON and inject $type
                         let mut record_value = serde_json::to_value(&record_data).unwrap_or_else(|_| serde_json::json!({}));
@@ -876,7 +912,7 @@ async fn list_published_posts(
         })?;
 
     // Convert to response format
-    let responses: Vec<BlogPostResponse> = posts.iter().map(|p| BlogPostResponse::from(p)).collect();
+    let responses: Vec<BlogPostResponse> = posts.iter().map(BlogPostResponse::from).collect();
 
     Ok(Json(responses))
 }
@@ -983,7 +1019,7 @@ async fn blog_create_form_handler_post(
     let session = match extract_session(headers, State(app_state.clone())).await {
         Ok(session) => session,
         Err(_) => {
-            println!("[BLOG][CREATE][AUTH][FAIL] no session_did elapsed_ms={}", start.elapsed().as_millis());
+            println!("[BLOG][CREATE][AUTH][FAIL] no session elapsed_ms={}", start.elapsed().as_millis());
             return Ok(Redirect::to("/posts?error=Auth%20required"));
         }
     };
@@ -1042,10 +1078,14 @@ async fn blog_create_form_handler_post(
         }
     })?;
     
-    match app_state.oauth_client.restore(&did_parsed).await {
+    let restored = with_session_id(
+        session.session_id.clone(),
+        app_state.oauth_client.restore(&did_parsed),
+    ).await;
+    match restored {
         Ok(oauth_session) => {
-            // Create agent from the restored OAuth session
-            let agent = Agent::new(oauth_session);
+            // Create agent from the restored OAuth session, bound to this device's session
+            let agent = Agent::new(DeviceSession::new(oauth_session, session.session_id.clone()));
             
             // First, try to register our custom lexicon
             let lexicon_nsid = "com.crabdance.nandi.post";
@@ -1209,13 +1249,21 @@ struct UpdateBlogPostForm {
 
 /// Handle form submission to update a blog post
 async fn blog_edit_form_handler_post(
+    headers: HeaderMap,
     State(app_state): State<AppState>,
     axum::extract::Path(uri): axum::extract::Path<String>,
     Form(form): Form<UpdateBlogPostForm>,
 ) -> Result<Redirect, ErrorTemplate> {
     let start = std::time::Instant::now();
     println!("[BLOG][EDIT_FORM][START] uri='{}' ts={}ms", uri, chrono::Utc::now().timestamp_millis());
-    // (Future) enforce auth here as well (e.g. compare session cookie DID to post DID)
+    // The PDS sync below uses the tokens of the device session making the request
+    let session = match extract_session(headers, State(app_state.clone())).await {
+        Ok(session) => session,
+        Err(_) => {
+            println!("[BLOG][EDIT_FORM][AUTH][FAIL] no session elapsed_ms={}", start.elapsed().as_millis());
+            return Ok(Redirect::to("/posts?error=Auth%20required"));
+        }
+    };
     // Load the existing post from database
     let db_pool_arc = Arc::new(app_state.db_pool.clone());
     let posts = BlogPostFromDb::load_latest_posts(&db_pool_arc).await
@@ -1286,11 +1334,16 @@ async fn blog_edit_form_handler_post(
             }
         })?;
 
-    // Attempt to sync to PDS (best-effort, non-fatal). We use the post's author DID.
-    if let Ok(did_parsed) = Did::new(updated_post.author_did.clone()) {
-        match app_state.oauth_client.restore(&did_parsed).await {
+    // Attempt to sync to PDS (best-effort, non-fatal) when the post's author made the request
+    let author_did = Did::new(updated_post.author_did.clone()).ok().filter(|did| did.as_str() == session.did);
+    if let Some(did_parsed) = author_did {
+        let restored = with_session_id(
+            session.session_id.clone(),
+            app_state.oauth_client.restore(&did_parsed),
+        ).await;
+        match restored {
             Ok(oauth_session) => {
-                let agent = Agent::new(oauth_session);
+                let agent = Agent::new(DeviceSession::new(oauth_session, session.session_id.clone()));
                 // Derive collection and rkey from URI at://did/collection/rkey
                 let parts: Vec<&str> = updated_post.uri.split('/').collect();
                 if parts.len() >= 5 { // at:, '', did, collection, rkey
@@ -1373,8 +1426,6 @@ async fn blog_edit_form_handler_post(
     Ok(Redirect::to("/posts?success=Updated%20post"))
 }
 
-/// Display the delete confirmation for a blog post
-
 /// Handle form submission to delete a blog post
 async fn blog_delete_form_handler_post(
     State(app_state): State<AppState>,
@@ -20,8 +20,8 @@ impl From<crate::codegen::com::crabdance::nandi::post::RecordData> for KnownReco
         KnownRecord::CodegenComCrabdanceNandiPost(Box::new(record_data.into()))
     }
 }
-impl Into<atrium_api::types::Unknown> for KnownRecord {
-    fn into(self) -> atrium_api::types::Unknown {
-        atrium_api::types::TryIntoUnknown::try_into_unknown(&self).unwrap()
+impl From<KnownRecord> for atrium_api::types::Unknown {
+    fn from(val: KnownRecord) -> Self {
+        atrium_api::types::TryIntoUnknown::try_into_unknown(&val).unwrap()
     }
 }
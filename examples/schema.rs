@@ -1,5 +1,6 @@
 /// Example database schema implementation showing how to integrate OAuth tables
 /// with your application-specific tables using generated lexicon types.
+use atproto_oauth::{Migration, MigrationError, Migrator, FIRST_APPLICATION_MIGRATION};
 use async_sqlite::{
     Pool, rusqlite,
     rusqlite::{Error, Row},
@@ -14,32 +15,14 @@ use crate::codegen::com::crabdance::nandi::post::{Record as BlogPostRecord, Reco
 use crate::codegen::record::KnownRecord;
 
 /// Creates all tables needed for this example application.
-/// This shows how to combine OAuth tables with your own application schema.
-pub async fn create_tables_in_database(pool: &Pool) -> Result<(), async_sqlite::Error> {
-    pool.conn(move |conn| {
-        conn.execute("PRAGMA foreign_keys = ON", []).unwrap();
-
-        // OAuth tables - these are required for the OAuth functionality
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS auth_session (
-            key TEXT PRIMARY KEY,
-            session TEXT NOT NULL
-        )",
-            [],
-        )
-        .unwrap();
-
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS auth_state (
-            key TEXT PRIMARY KEY,
-            state TEXT NOT NULL
-        )",
-            [],
-        )
-        .unwrap();
-
+/// This shows how to combine OAuth tables with your own application schema: application
+/// migrations are registered on the same [Migrator] that applies the OAuth migrations.
+pub async fn create_tables_in_database(pool: &Pool) -> Result<(), MigrationError> {
+    Migrator::new()
         // Application-specific tables - this is an example of your own schema
-        conn.execute(
+        .migration(Migration::sql(
+            FIRST_APPLICATION_MIGRATION,
+            "create_blog_posts",
             "CREATE TABLE IF NOT EXISTS blog_posts (
             uri TEXT PRIMARY KEY,
             authorDid TEXT NOT NULL,
@@ -52,13 +35,9 @@ pub async fn create_tables_in_database(pool: &Pool) -> Result<(), async_sqlite::
             updatedAt INTEGER NOT NULL,
             indexedAt INTEGER NOT NULL
         )",
-            [],
-        )
-        .unwrap();
-        
-        Ok(())
-    })
-    .await?;
+        ))
+        .run(pool)
+        .await?;
     Ok(())
 }
 
@@ -78,6 +57,7 @@ pub struct BlogPostFromDb {
     pub handle: Option<String>,
 }
 
+#[allow(dead_code)]
 impl BlogPostFromDb {
     /// Creates a new [BlogPostFromDb] from lexicon record
     pub fn new(uri: String, author_did: String, title: String, content: String) -> Self {
@@ -110,7 +90,7 @@ impl BlogPostFromDb {
             tags: tags_json,
             published: record.data.published.unwrap_or(false),
             created_at: (*record.data.created_at.as_ref()).into(),
-            updated_at: record.data.updated_at.as_ref().map(|dt| (*dt.as_ref()).into()).unwrap_or_else(|| chrono::Utc::now()),
+            updated_at: record.data.updated_at.as_ref().map(|dt| (*dt.as_ref()).into()).unwrap_or_else(chrono::Utc::now),
             indexed_at: chrono::Utc::now(),
             handle: None,
         })
@@ -129,7 +109,7 @@ impl BlogPostFromDb {
             tags: tags_json,
             published: data.published.unwrap_or(false),
             created_at: (*data.created_at.as_ref()).into(),
-            updated_at: data.updated_at.as_ref().map(|dt| (*dt.as_ref()).into()).unwrap_or_else(|| chrono::Utc::now()),
+            updated_at: data.updated_at.as_ref().map(|dt| (*dt.as_ref()).into()).unwrap_or_else(chrono::Utc::now),
             indexed_at: chrono::Utc::now(),
             handle: None,
         })
@@ -207,7 +187,7 @@ impl BlogPostFromDb {
     pub async fn save(&self, pool: &Arc<Pool>) -> Result<(), async_sqlite::Error> {
         let cloned_self = self.clone();
         pool.conn(move |conn| {
-            Ok(conn.execute(
+            conn.execute(
                 "INSERT INTO blog_posts (uri, authorDid, title, content, summary, tags, published, createdAt, updatedAt, indexedAt) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
                 [
                     &cloned_self.uri,
@@ -221,7 +201,7 @@ impl BlogPostFromDb {
                     &cloned_self.updated_at.timestamp().to_string(),
                     &cloned_self.indexed_at.timestamp().to_string(),
                 ],
-            )?)
+            )
         })
             .await?;
         Ok(())
@@ -286,7 +266,7 @@ impl BlogPostFromDb {
     pub async fn load_latest_posts(
         pool: &Arc<Pool>,
     ) -> Result<Vec<Self>, async_sqlite::Error> {
-        Ok(pool
+        pool
             .conn(move |conn| {
                 let mut stmt =
                     conn.prepare("SELECT * FROM blog_posts ORDER BY indexedAt DESC LIMIT 10")?;
@@ -300,14 +280,14 @@ impl BlogPostFromDb {
                 }
                 Ok(posts)
             })
-            .await?)
+            .await
     }
 
     /// Loads only published blog posts
     pub async fn load_published_posts(
         pool: &Arc<Pool>,
     ) -> Result<Vec<Self>, async_sqlite::Error> {
-        Ok(pool
+        pool
             .conn(move |conn| {
                 let mut stmt =
                     conn.prepare("SELECT * FROM blog_posts WHERE published = 1 ORDER BY createdAt DESC LIMIT 20")?;
@@ -321,7 +301,7 @@ impl BlogPostFromDb {
                 }
                 Ok(posts)
             })
-            .await?)
+            .await
     }
 
     /// Loads the logged-in user's latest blog post
@@ -334,7 +314,7 @@ impl BlogPostFromDb {
             let mut stmt = conn.prepare(
                 "SELECT * FROM blog_posts WHERE authorDid = ?1 ORDER BY createdAt DESC LIMIT 1",
             )?;
-            stmt.query_row([did.as_str()], |row| Self::map_from_row(row))
+            stmt.query_row([did.as_str()], Self::map_from_row)
                 .map(Some)
                 .or_else(|err| {
                     if err == rusqlite::Error::QueryReturnedNoRows {
@@ -357,7 +337,7 @@ impl BlogPostFromDb {
             let mut stmt = conn.prepare(
                 "SELECT * FROM blog_posts WHERE uri = ?1",
             )?;
-            stmt.query_row([uri.as_str()], |row| Self::map_from_row(row))
+            stmt.query_row([uri.as_str()], Self::map_from_row)
                 .map(Some)
                 .or_else(|err| {
                     if err == rusqlite::Error::QueryReturnedNoRows {
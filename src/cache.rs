@@ -0,0 +1,148 @@
+/// Bounded in-memory cache with per-entry expiry, used by the caching resolvers
+///
+/// Entries either hold a value or record that the key does not exist (negative caching). When
+/// the cache is full, expired entries are dropped first, then the entry closest to expiry.
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Hit and miss counters of one cache, for monitoring
+#[derive(Debug, Default)]
+pub struct CacheStats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl CacheStats {
+    /// Lookups answered from the cache, including cached negative results
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Lookups that had to be resolved
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+struct CacheEntry<V> {
+    /// `None` records that the key does not exist
+    value: Option<V>,
+    expires_at: Instant,
+}
+
+pub(crate) struct TtlCache<K, V> {
+    entries: Mutex<HashMap<K, CacheEntry<V>>>,
+    max_entries: usize,
+    stats: Arc<CacheStats>,
+}
+
+impl<K, V> TtlCache<K, V>
+where
+    K: Clone + Eq + Hash,
+    V: Clone,
+{
+    /// Creates a cache holding at most `max_entries`; 0 disables caching
+    pub(crate) fn new(max_entries: usize, stats: Arc<CacheStats>) -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            max_entries,
+            stats,
+        }
+    }
+
+    /// Looks up `key`, counting a hit or a miss. `Some(None)` is a cached negative result.
+    pub(crate) fn get(&self, key: &K) -> Option<Option<V>> {
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        let cached = match entries.get(key) {
+            Some(entry) if entry.expires_at > Instant::now() => Some(entry.value.clone()),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        };
+        let counter = match cached {
+            Some(_) => &self.stats.hits,
+            None => &self.stats.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        cached
+    }
+
+    /// Caches `value` for `ttl`
+    pub(crate) fn insert(&self, key: K, value: Option<V>, ttl: Duration) {
+        self.insert_until(key, value, Instant::now() + ttl);
+    }
+
+    /// Caches `value` until `expires_at`
+    pub(crate) fn insert_until(&self, key: K, value: Option<V>, expires_at: Instant) {
+        let now = Instant::now();
+        if self.max_entries == 0 || expires_at <= now {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        if entries.len() >= self.max_entries && !entries.contains_key(&key) {
+            entries.retain(|_, entry| entry.expires_at > now);
+        }
+        if entries.len() >= self.max_entries && !entries.contains_key(&key) {
+            let soonest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.expires_at)
+                .map(|(key, _)| key.clone());
+            if let Some(soonest) = soonest {
+                entries.remove(&soonest);
+            }
+        }
+        entries.insert(key, CacheEntry { value, expires_at });
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_expires_entries_and_counts_lookups() {
+        let stats = Arc::new(CacheStats::default());
+        let cache = TtlCache::new(10, stats.clone());
+        assert_eq!(cache.get(&"alice"), None);
+
+        cache.insert("alice", Some(1), Duration::from_secs(60));
+        cache.insert("nobody", None, Duration::from_secs(60));
+        cache.insert("expired", Some(2), Duration::ZERO);
+        assert_eq!(cache.get(&"alice"), Some(Some(1)));
+        assert_eq!(cache.get(&"nobody"), Some(None));
+        assert_eq!(cache.get(&"expired"), None);
+        assert_eq!((stats.hits(), stats.misses()), (2, 2));
+    }
+
+    #[test]
+    fn test_cache_evicts_entry_closest_to_expiry() {
+        let cache = TtlCache::new(2, Arc::new(CacheStats::default()));
+        cache.insert("short", Some(1), Duration::from_secs(10));
+        cache.insert("long", Some(2), Duration::from_secs(600));
+        cache.insert("new", Some(3), Duration::from_secs(300));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&"short"), None);
+        assert_eq!(cache.get(&"long"), Some(Some(2)));
+        assert_eq!(cache.get(&"new"), Some(Some(3)));
+    }
+
+    #[test]
+    fn test_disabled_cache_stores_nothing() {
+        let cache = TtlCache::new(0, Arc::new(CacheStats::default()));
+        cache.insert("alice", Some(1), Duration::from_secs(60));
+        assert_eq!(cache.get(&"alice"), None);
+    }
+}
@@ -1,79 +1,383 @@
+use crate::{device::ClientInfo, error::DbError};
 use async_sqlite::{
-    Pool, rusqlite,
-    rusqlite::{Error, Row},
+    JournalMode, Pool, PoolBuilder,
+    rusqlite::{params_from_iter, types::Value, Connection, Error, Row, TransactionBehavior},
 };
 use atrium_api::types::string::Did;
-use chrono::{DateTime, Datelike, Utc};
-use rusqlite::types::Type;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::{fmt::Debug, sync::Arc};
+use std::{collections::HashSet, fmt::Debug, path::Path, time::Duration};
 
-/// Creates the OAuth-specific tables in the database.
-/// This creates the minimal tables needed for OAuth functionality.
-/// Applications should create their own schema setup function that includes these tables.
-pub async fn create_oauth_tables(pool: &Pool) -> Result<(), async_sqlite::Error> {
-    pool.conn(move |conn| {
-        conn.execute("PRAGMA foreign_keys = ON", []).unwrap();
+/// How a [Migration] changes the schema
+#[derive(Clone)]
+pub enum MigrationStep {
+    /// One or more SQL statements executed as a batch
+    Sql(String),
+    /// A function for changes that plain SQL cannot express, e.g. conditional column additions
+    Function(fn(&Connection) -> Result<(), Error>),
+}
 
-        // auth_session
-        conn.execute(
+/// A numbered schema change, applied at most once per database
+#[derive(Clone)]
+pub struct Migration {
+    pub version: u32,
+    pub name: String,
+    pub step: MigrationStep,
+}
+
+impl Migration {
+    /// Creates a migration that executes `sql` as a batch
+    pub fn sql(version: u32, name: impl Into<String>, sql: impl Into<String>) -> Self {
+        Self {
+            version,
+            name: name.into(),
+            step: MigrationStep::Sql(sql.into()),
+        }
+    }
+
+    /// Creates a migration that runs `function` against the connection
+    pub fn function(
+        version: u32,
+        name: impl Into<String>,
+        function: fn(&Connection) -> Result<(), Error>,
+    ) -> Self {
+        Self {
+            version,
+            name: name.into(),
+            step: MigrationStep::Function(function),
+        }
+    }
+
+    fn apply(&self, conn: &Connection) -> Result<(), Error> {
+        match &self.step {
+            MigrationStep::Sql(sql) => conn.execute_batch(sql),
+            MigrationStep::Function(function) => function(conn),
+        }
+    }
+}
+
+impl Debug for Migration {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Migration")
+            .field("version", &self.version)
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum MigrationError {
+    #[error("Duplicate migration version {0}")]
+    DuplicateVersion(u32),
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] async_sqlite::Error),
+}
+
+/// Versions below this are reserved for the OAuth migrations shipped with this crate
+pub const FIRST_APPLICATION_MIGRATION: u32 = 1000;
+
+/// The migrations that create and evolve the OAuth tables
+pub fn oauth_migrations() -> Vec<Migration> {
+    vec![
+        Migration::sql(
+            1,
+            "create_oauth_tables",
             "CREATE TABLE IF NOT EXISTS auth_session (
-            key TEXT PRIMARY KEY,
-            session TEXT NOT NULL
-        )",
-            [],
-        )
-        .unwrap();
+                key TEXT PRIMARY KEY,
+                session TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS auth_state (
+                key TEXT PRIMARY KEY,
+                state TEXT NOT NULL
+            );",
+        ),
+        // auth_state rows from before states expired get 0 and are treated as expired
+        Migration::function(2, "auth_state_created_at", |conn| {
+            add_column_if_missing(conn, "auth_state", "created_at", "INTEGER NOT NULL DEFAULT 0")
+        }),
+        // Existing sessions become the default device session of their DID
+        Migration::sql(
+            3,
+            "auth_session_per_device",
+            "CREATE TABLE auth_session_per_device (
+                key TEXT NOT NULL,
+                session_id TEXT NOT NULL,
+                session TEXT NOT NULL,
+                updated_at INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY (key, session_id)
+            );
+            INSERT INTO auth_session_per_device (key, session_id, session, updated_at)
+                SELECT key, 'default', session, CAST(strftime('%s', 'now') AS INTEGER)
+                FROM auth_session;
+            DROP TABLE auth_session;
+            ALTER TABLE auth_session_per_device RENAME TO auth_session;",
+        ),
+        // Lets concurrent token refreshes detect that another writer replaced the session
+        Migration::sql(
+            4,
+            "auth_session_version",
+            "ALTER TABLE auth_session ADD COLUMN version INTEGER NOT NULL DEFAULT 0;",
+        ),
+        // Existing sessions count as created and last used at their last update
+        Migration::sql(
+            5,
+            "auth_session_metadata",
+            "ALTER TABLE auth_session ADD COLUMN created_at INTEGER NOT NULL DEFAULT 0;
+            ALTER TABLE auth_session ADD COLUMN last_used_at INTEGER NOT NULL DEFAULT 0;
+            ALTER TABLE auth_session ADD COLUMN user_agent TEXT;
+            ALTER TABLE auth_session ADD COLUMN ip_address TEXT;
+            UPDATE auth_session SET created_at = updated_at, last_used_at = updated_at;",
+        ),
+    ]
+}
 
-        // auth_state
+/// Adds a column unless the table already has it.
+/// Useful for migrating databases whose tables predate migration tracking.
+pub fn add_column_if_missing(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<(), Error> {
+    let exists = conn
+        .prepare("SELECT 1 FROM pragma_table_info(?1) WHERE name = ?2")?
+        .exists([table, column])?;
+    if !exists {
         conn.execute(
-            "CREATE TABLE IF NOT EXISTS auth_state (
-            key TEXT PRIMARY KEY,
-            state TEXT NOT NULL
-        )",
+            &format!("ALTER TABLE {table} ADD COLUMN {column} {definition}"),
             [],
-        )
-        .unwrap();
-        Ok(())
-    })
-    .await?;
+        )?;
+    }
+    Ok(())
+}
+
+/// Applies the OAuth migrations plus any application migrations in version order,
+/// recording each applied version in the `schema_migrations` table.
+///
+/// Application migrations must use versions from [FIRST_APPLICATION_MIGRATION] upwards so
+/// that future OAuth migrations shipped with this crate never collide with them.
+pub struct Migrator {
+    migrations: Vec<Migration>,
+}
+
+impl Migrator {
+    /// Creates a migrator containing the OAuth migrations
+    pub fn new() -> Self {
+        Self {
+            migrations: oauth_migrations(),
+        }
+    }
+
+    /// Registers an application migration
+    pub fn migration(mut self, migration: Migration) -> Self {
+        self.migrations.push(migration);
+        self
+    }
+
+    /// Applies every migration that has not been applied yet, returning the applied versions.
+    /// All pending migrations run in a single transaction.
+    pub async fn run(&self, pool: &Pool) -> Result<Vec<u32>, MigrationError> {
+        let mut migrations = self.migrations.clone();
+        migrations.sort_by_key(|migration| migration.version);
+        if let Some(pair) = migrations
+            .windows(2)
+            .find(|pair| pair[0].version == pair[1].version)
+        {
+            return Err(MigrationError::DuplicateVersion(pair[0].version));
+        }
+
+        let applied = pool
+            .conn_mut(move |conn| {
+                conn.execute("PRAGMA foreign_keys = ON", [])?;
+                let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                tx.execute(
+                    "CREATE TABLE IF NOT EXISTS schema_migrations (
+                    version INTEGER PRIMARY KEY,
+                    name TEXT NOT NULL,
+                    applied_at INTEGER NOT NULL
+                )",
+                    [],
+                )?;
+                let done = tx
+                    .prepare("SELECT version FROM schema_migrations")?
+                    .query_map([], |row| row.get::<_, u32>(0))?
+                    .collect::<Result<HashSet<_>, _>>()?;
+
+                let mut applied = Vec::new();
+                for migration in migrations.iter().filter(|m| !done.contains(&m.version)) {
+                    migration.apply(&tx)?;
+                    tx.execute(
+                        "INSERT INTO schema_migrations (version, name, applied_at) VALUES (?1, ?2, ?3)",
+                        (migration.version, &migration.name, Utc::now().timestamp()),
+                    )?;
+                    applied.push(migration.version);
+                }
+                tx.commit()?;
+                Ok(applied)
+            })
+            .await?;
+        for version in &applied {
+            log::info!("Applied schema migration {version}");
+        }
+        Ok(applied)
+    }
+}
+
+impl Default for Migrator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Path that makes [open_database] open an in-memory database
+pub const IN_MEMORY_DATABASE: &str = ":memory:";
+
+/// How long a connection waits for a lock held by another connection before failing
+pub const DEFAULT_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Opens a connection pool for the SQLite database at `path`, creating the file if needed.
+///
+/// File databases use WAL journaling so readers do not block the writer. Every connection
+/// waits up to [DEFAULT_BUSY_TIMEOUT] for locks and enforces foreign keys. [IN_MEMORY_DATABASE]
+/// opens an in-memory database on a single connection, as every further connection would see
+/// its own empty database. The pool can be shared with the application's own tables; apply
+/// the migrations with a [Migrator] or [create_oauth_tables].
+pub async fn open_database(path: impl AsRef<Path>) -> Result<Pool, DbError> {
+    let path = path.as_ref();
+    let pool = if path == Path::new(IN_MEMORY_DATABASE) {
+        PoolBuilder::new().num_conns(1).open().await?
+    } else {
+        PoolBuilder::new()
+            .path(path)
+            .journal_mode(JournalMode::Wal)
+            .open()
+            .await?
+    };
+    for result in pool
+        .conn_for_each(|conn| {
+            conn.busy_timeout(DEFAULT_BUSY_TIMEOUT)?;
+            conn.pragma_update(None, "foreign_keys", "ON")
+        })
+        .await
+    {
+        result?;
+    }
+    Ok(pool)
+}
+
+/// Opens an in-memory database, see [open_database]
+pub async fn open_in_memory_database() -> Result<Pool, DbError> {
+    open_database(IN_MEMORY_DATABASE).await
+}
+
+/// Creates or upgrades the OAuth-specific tables in the database.
+/// This applies the OAuth migrations only; applications with their own tables should
+/// register them on a [Migrator] instead so both share one ordered sequence.
+pub async fn create_oauth_tables(pool: &Pool) -> Result<(), DbError> {
+    Migrator::new().run(pool).await?;
     Ok(())
 }
 
+/// Session id of sessions stored without a device session scope, and of sessions stored
+/// before sessions were kept per device
+pub const DEFAULT_SESSION_ID: &str = "default";
+
+/// Columns read by [AuthSession::map_from_row]
+const SESSION_COLUMNS: &str = "key, session_id, session, updated_at, version, created_at, \
+    last_used_at, user_agent, ip_address";
 
-/// AuthSession table data type
+/// AuthSession table data type, one row per DID and device session
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct AuthSession {
     pub key: String,
+    pub session_id: String,
     pub session: String,
+    /// When the session was last written, i.e. logged in or refreshed
+    pub updated_at: DateTime<Utc>,
+    /// Incremented on every write; 0 for a session that has not been stored yet
+    pub version: i64,
+    pub created_at: DateTime<Utc>,
+    /// When the session was last used, see [touch](AuthSession::touch)
+    pub last_used_at: DateTime<Utc>,
+    /// User agent of the client that logged in
+    pub user_agent: Option<String>,
+    /// IP address of the client that logged in
+    pub ip_address: Option<String>,
 }
 
 impl AuthSession {
-    /// Creates a new [AuthSession]
-    pub fn new<V>(key: String, session: V) -> Self
+    /// Creates a new [AuthSession] for the [DEFAULT_SESSION_ID]
+    pub fn new<V>(key: String, session: V) -> Result<Self, DbError>
     where
         V: Serialize,
     {
-        let session = serde_json::to_string(&session).unwrap();
-        Self {
+        let session = serde_json::to_string(&session)?;
+        let now = Utc::now();
+        Ok(Self {
             key: key.to_string(),
+            session_id: DEFAULT_SESSION_ID.to_string(),
             session,
-        }
+            updated_at: now,
+            version: 0,
+            created_at: now,
+            last_used_at: now,
+            user_agent: None,
+            ip_address: None,
+        })
+    }
+
+    /// Sets the device session id
+    pub fn with_session_id(mut self, session_id: impl Into<String>) -> Self {
+        self.session_id = session_id.into();
+        self
+    }
+
+    /// Sets the user agent and IP address of the client that logged in
+    pub fn with_client_info(mut self, client_info: ClientInfo) -> Self {
+        self.user_agent = client_info.user_agent;
+        self.ip_address = client_info.ip_address;
+        self
+    }
+
+    /// Returns true if the session was last used more than `timeout` ago
+    pub fn is_idle(&self, timeout: chrono::Duration) -> bool {
+        Utc::now() - self.last_used_at > timeout
     }
 
     /// Helper to map from [Row] to [AuthSession]
     fn map_from_row(row: &Row) -> Result<Self, Error> {
         let key: String = row.get(0)?;
-        let session: String = row.get(1)?;
-        Ok(Self { key, session })
+        let session_id: String = row.get(1)?;
+        let session: String = row.get(2)?;
+        let updated_at: i64 = row.get(3)?;
+        let version: i64 = row.get(4)?;
+        let created_at: i64 = row.get(5)?;
+        let last_used_at: i64 = row.get(6)?;
+        Ok(Self {
+            key,
+            session_id,
+            session,
+            updated_at: DateTime::from_timestamp(updated_at, 0)
+                .ok_or(Error::IntegralValueOutOfRange(3, updated_at))?,
+            version,
+            created_at: DateTime::from_timestamp(created_at, 0)
+                .ok_or(Error::IntegralValueOutOfRange(5, created_at))?,
+            last_used_at: DateTime::from_timestamp(last_used_at, 0)
+                .ok_or(Error::IntegralValueOutOfRange(6, last_used_at))?,
+            user_agent: row.get(7)?,
+            ip_address: row.get(8)?,
+        })
     }
 
-    /// Gets a session by the users did(key)
-    pub async fn get_by_did(pool: &Pool, did: String) -> Result<Option<Self>, async_sqlite::Error> {
-        let did = Did::new(did).unwrap();
+    /// Gets the most recently updated session of the users did(key)
+    pub async fn get_by_did(pool: &Pool, did: String) -> Result<Option<Self>, DbError> {
+        let did = Did::new(did.clone()).map_err(|_| DbError::InvalidDid(did))?;
         pool.conn(move |conn| {
-            let mut stmt = conn.prepare("SELECT * FROM auth_session WHERE key = ?1")?;
-            stmt.query_row([did.as_str()], |row| Self::map_from_row(row))
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {SESSION_COLUMNS} FROM auth_session WHERE key = ?1
+                ORDER BY updated_at DESC, rowid DESC LIMIT 1"
+            ))?;
+            stmt.query_row([did.as_str()], Self::map_from_row)
                 .map(Some)
                 .or_else(|err| {
                     if err == Error::QueryReturnedNoRows {
@@ -84,37 +388,168 @@ impl AuthSession {
                 })
         })
         .await
+        .map_err(DbError::from)
     }
 
-    /// Saves or updates the session by its did(key)
-    pub async fn save_or_update(&self, pool: &Pool) -> Result<(), async_sqlite::Error> {
-        let cloned_self = self.clone();
+    /// Gets one device session of the users did(key)
+    pub async fn get(
+        pool: &Pool,
+        did: String,
+        session_id: String,
+    ) -> Result<Option<Self>, DbError> {
         pool.conn(move |conn| {
-            //We check to see if the session already exists, if so we need to update not insert
-            let mut stmt = conn.prepare("SELECT COUNT(*) FROM auth_session WHERE key = ?1")?;
-            let count: i64 = stmt.query_row([&cloned_self.key], |row| row.get(0))?;
-            match count > 0 {
-                true => {
-                    let mut update_stmt =
-                        conn.prepare("UPDATE auth_session SET session = ?2 WHERE key = ?1")?;
-                    update_stmt.execute([&cloned_self.key, &cloned_self.session])?;
-                    Ok(())
-                }
-                false => {
-                    conn.execute(
-                        "INSERT INTO auth_session (key, session) VALUES (?1, ?2)",
-                        [&cloned_self.key, &cloned_self.session],
-                    )?;
-                    Ok(())
-                }
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {SESSION_COLUMNS} FROM auth_session WHERE key = ?1 AND session_id = ?2"
+            ))?;
+            stmt.query_row([&did, &session_id], Self::map_from_row)
+                .map(Some)
+                .or_else(|err| {
+                    if err == Error::QueryReturnedNoRows {
+                        Ok(None)
+                    } else {
+                        Err(err)
+                    }
+                })
+        })
+        .await
+        .map_err(DbError::from)
+    }
+
+    /// Lists all device sessions of the users did(key), most recently updated first
+    pub async fn list_for_did(pool: &Pool, did: String) -> Result<Vec<Self>, DbError> {
+        pool.conn(move |conn| {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {SESSION_COLUMNS} FROM auth_session WHERE key = ?1
+                ORDER BY updated_at DESC, rowid DESC"
+            ))?;
+            let sessions = stmt.query_map([&did], Self::map_from_row)?;
+            sessions.collect()
+        })
+        .await
+        .map_err(DbError::from)
+    }
+
+    /// Lists the device sessions of every DID
+    pub async fn list_all(pool: &Pool) -> Result<Vec<Self>, DbError> {
+        pool.conn(move |conn| {
+            let mut stmt = conn.prepare(&format!("SELECT {SESSION_COLUMNS} FROM auth_session"))?;
+            let sessions = stmt.query_map([], Self::map_from_row)?;
+            sessions.collect()
+        })
+        .await
+        .map_err(DbError::from)
+    }
+
+    /// Inserts or updates the session by its did(key) and session id in one atomic upsert,
+    /// regardless of its version
+    pub async fn save_or_update(&self, pool: &Pool) -> Result<(), DbError> {
+        let cloned_self = self.clone();
+        pool.conn(move |conn| cloned_self.upsert(conn)).await?;
+        Ok(())
+    }
+
+    /// Writes the session only if the stored row still has this session's
+    /// [version](AuthSession::version), or does not exist when the version is 0.
+    ///
+    /// The check and the write run in an immediate transaction, which holds the database write
+    /// lock, so of two writers that read the same version only the first one succeeds. Returns
+    /// false if another writer changed or deleted the row in the meantime.
+    pub async fn compare_and_swap(&self, pool: &Pool) -> Result<bool, DbError> {
+        let cloned_self = self.clone();
+        pool.conn_mut(move |conn| {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let stored_version = tx
+                .query_row(
+                    "SELECT version FROM auth_session WHERE key = ?1 AND session_id = ?2",
+                    [&cloned_self.key, &cloned_self.session_id],
+                    |row| row.get::<_, i64>(0),
+                )
+                .or_else(|err| {
+                    if err == Error::QueryReturnedNoRows {
+                        Ok(0)
+                    } else {
+                        Err(err)
+                    }
+                })?;
+            if stored_version != cloned_self.version {
+                return Ok(false);
             }
+            cloned_self.upsert(&tx)?;
+            tx.commit()?;
+            Ok(true)
+        })
+        .await
+        .map_err(DbError::from)
+    }
+
+    /// Inserts the session, or updates the existing row and increments its version.
+    /// An update keeps the creation and last use times, and the client info unless given.
+    fn upsert(&self, conn: &Connection) -> Result<(), Error> {
+        conn.execute(
+            "INSERT INTO auth_session (key, session_id, session, updated_at, version,
+                created_at, last_used_at, user_agent, ip_address)
+            VALUES (?1, ?2, ?3, ?4, 1, ?5, ?6, ?7, ?8)
+            ON CONFLICT (key, session_id) DO UPDATE SET
+                session = excluded.session,
+                updated_at = excluded.updated_at,
+                version = auth_session.version + 1,
+                user_agent = COALESCE(excluded.user_agent, auth_session.user_agent),
+                ip_address = COALESCE(excluded.ip_address, auth_session.ip_address)",
+            (
+                &self.key,
+                &self.session_id,
+                &self.session,
+                self.updated_at.timestamp(),
+                self.created_at.timestamp(),
+                self.last_used_at.timestamp(),
+                &self.user_agent,
+                &self.ip_address,
+            ),
+        )?;
+        Ok(())
+    }
+
+    /// Records that one device session of the did was used now
+    pub async fn touch(pool: &Pool, did: String, session_id: String) -> Result<(), DbError> {
+        pool.conn(move |conn| {
+            let mut stmt = conn.prepare(
+                "UPDATE auth_session SET last_used_at = ?3 WHERE key = ?1 AND session_id = ?2",
+            )?;
+            stmt.execute((&did, &session_id, Utc::now().timestamp()))
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Lists the sessions matching `query`, most recently used first
+    pub async fn query(pool: &Pool, query: &SessionQuery) -> Result<Vec<Self>, DbError> {
+        let (sql, params) = query.to_sql();
+        pool.conn(move |conn| {
+            let mut stmt = conn.prepare(&sql)?;
+            let sessions = stmt.query_map(params_from_iter(params), Self::map_from_row)?;
+            sessions.collect()
+        })
+        .await
+        .map_err(DbError::from)
+    }
+
+    /// Deletes one device session of the did
+    pub async fn delete(
+        pool: &Pool,
+        did: String,
+        session_id: String,
+    ) -> Result<(), DbError> {
+        pool.conn(move |conn| {
+            let mut stmt =
+                conn.prepare("DELETE FROM auth_session WHERE key = ?1 AND session_id = ?2")?;
+            stmt.execute([&did, &session_id])
         })
         .await?;
         Ok(())
     }
 
-    /// Deletes the session by did
-    pub async fn delete_by_did(pool: &Pool, did: String) -> Result<(), async_sqlite::Error> {
+    /// Deletes all device sessions of the did
+    pub async fn delete_by_did(pool: &Pool, did: String) -> Result<(), DbError> {
         pool.conn(move |conn| {
             let mut stmt = conn.prepare("DELETE FROM auth_session WHERE key = ?1")?;
             stmt.execute([&did])
@@ -124,7 +559,7 @@ impl AuthSession {
     }
 
     /// Deletes all the sessions
-    pub async fn delete_all(pool: &Pool) -> Result<(), async_sqlite::Error> {
+    pub async fn delete_all(pool: &Pool) -> Result<(), DbError> {
         pool.conn(move |conn| {
             let mut stmt = conn.prepare("DELETE FROM auth_session")?;
             stmt.execute([])
@@ -134,38 +569,164 @@ impl AuthSession {
     }
 }
 
+/// Filter for [AuthSession::query]. Every condition that is set must match.
+#[derive(Debug, Clone, Default)]
+pub struct SessionQuery {
+    did: Option<String>,
+    created_after: Option<DateTime<Utc>>,
+    created_before: Option<DateTime<Utc>>,
+    used_after: Option<DateTime<Utc>>,
+    used_before: Option<DateTime<Utc>>,
+    user_agent_contains: Option<String>,
+    ip_address: Option<String>,
+    limit: Option<u32>,
+}
+
+impl SessionQuery {
+    /// Creates a query matching every session
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only sessions of `did`
+    pub fn did(mut self, did: impl Into<String>) -> Self {
+        self.did = Some(did.into());
+        self
+    }
+
+    /// Only sessions created after `time`
+    pub fn created_after(mut self, time: DateTime<Utc>) -> Self {
+        self.created_after = Some(time);
+        self
+    }
+
+    /// Only sessions created before `time`
+    pub fn created_before(mut self, time: DateTime<Utc>) -> Self {
+        self.created_before = Some(time);
+        self
+    }
+
+    /// Only sessions used after `time`
+    pub fn used_after(mut self, time: DateTime<Utc>) -> Self {
+        self.used_after = Some(time);
+        self
+    }
+
+    /// Only sessions not used since `time`, e.g. to find idle sessions
+    pub fn used_before(mut self, time: DateTime<Utc>) -> Self {
+        self.used_before = Some(time);
+        self
+    }
+
+    /// Only sessions whose user agent contains `text`
+    pub fn user_agent_contains(mut self, text: impl Into<String>) -> Self {
+        self.user_agent_contains = Some(text.into());
+        self
+    }
+
+    /// Only sessions logged in from `ip_address`
+    pub fn ip_address(mut self, ip_address: impl Into<String>) -> Self {
+        self.ip_address = Some(ip_address.into());
+        self
+    }
+
+    /// Return at most `limit` sessions
+    pub fn limit(mut self, limit: u32) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Builds the SELECT statement and its parameters
+    fn to_sql(&self) -> (String, Vec<Value>) {
+        let mut conditions = Vec::new();
+        let mut params = Vec::new();
+        // Numbers the `?` placeholder of each condition after its parameter
+        let mut condition = |sql: &str, value: Value| {
+            params.push(value);
+            conditions.push(sql.replace('?', &format!("?{}", params.len())));
+        };
+        if let Some(did) = &self.did {
+            condition("key = ?", Value::Text(did.clone()));
+        }
+        if let Some(time) = self.created_after {
+            condition("created_at > ?", Value::Integer(time.timestamp()));
+        }
+        if let Some(time) = self.created_before {
+            condition("created_at < ?", Value::Integer(time.timestamp()));
+        }
+        if let Some(time) = self.used_after {
+            condition("last_used_at > ?", Value::Integer(time.timestamp()));
+        }
+        if let Some(time) = self.used_before {
+            condition("last_used_at < ?", Value::Integer(time.timestamp()));
+        }
+        if let Some(text) = &self.user_agent_contains {
+            condition("instr(user_agent, ?) > 0", Value::Text(text.clone()));
+        }
+        if let Some(ip_address) = &self.ip_address {
+            condition("ip_address = ?", Value::Text(ip_address.clone()));
+        }
+
+        let mut sql = format!("SELECT {SESSION_COLUMNS} FROM auth_session");
+        if !conditions.is_empty() {
+            sql.push_str(" WHERE ");
+            sql.push_str(&conditions.join(" AND "));
+        }
+        sql.push_str(" ORDER BY last_used_at DESC, rowid DESC");
+        if let Some(limit) = self.limit {
+            sql.push_str(&format!(" LIMIT {limit}"));
+        }
+        (sql, params)
+    }
+}
+
 /// AuthState table datatype
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct AuthState {
     pub key: String,
     pub state: String,
+    pub created_at: DateTime<Utc>,
 }
 
 impl AuthState {
     /// Creates a new [AuthState]
-    pub fn new<V>(key: String, state: V) -> Self
+    pub fn new<V>(key: String, state: V) -> Result<Self, DbError>
     where
         V: Serialize,
     {
-        let state = serde_json::to_string(&state).unwrap();
-        Self {
+        let state = serde_json::to_string(&state)?;
+        Ok(Self {
             key: key.to_string(),
             state,
-        }
+            created_at: Utc::now(),
+        })
+    }
+
+    /// Returns true if the state was created more than `ttl` ago
+    pub fn is_expired(&self, ttl: chrono::Duration) -> bool {
+        Utc::now() - self.created_at > ttl
     }
 
     /// Helper to map from [Row] to [AuthState]
     fn map_from_row(row: &Row) -> Result<Self, Error> {
         let key: String = row.get(0)?;
         let state: String = row.get(1)?;
-        Ok(Self { key, state })
+        let created_at: i64 = row.get(2)?;
+        let created_at = DateTime::from_timestamp(created_at, 0)
+            .ok_or(Error::IntegralValueOutOfRange(2, created_at))?;
+        Ok(Self {
+            key,
+            state,
+            created_at,
+        })
     }
 
     /// Gets a state by the users key
-    pub async fn get_by_key(pool: &Pool, key: String) -> Result<Option<Self>, async_sqlite::Error> {
+    pub async fn get_by_key(pool: &Pool, key: String) -> Result<Option<Self>, DbError> {
         pool.conn(move |conn| {
-            let mut stmt = conn.prepare("SELECT * FROM auth_state WHERE key = ?1")?;
-            stmt.query_row([key.as_str()], |row| Self::map_from_row(row))
+            let mut stmt =
+                conn.prepare("SELECT key, state, created_at FROM auth_state WHERE key = ?1")?;
+            stmt.query_row([key.as_str()], Self::map_from_row)
                 .map(Some)
                 .or_else(|err| {
                     if err == Error::QueryReturnedNoRows {
@@ -176,26 +737,29 @@ impl AuthState {
                 })
         })
         .await
+        .map_err(DbError::from)
     }
 
     /// Saves or updates the state by its key
-    pub async fn save_or_update(&self, pool: &Pool) -> Result<(), async_sqlite::Error> {
+    pub async fn save_or_update(&self, pool: &Pool) -> Result<(), DbError> {
         let cloned_self = self.clone();
         pool.conn(move |conn| {
+            let created_at = cloned_self.created_at.timestamp();
             //We check to see if the state already exists, if so we need to update
             let mut stmt = conn.prepare("SELECT COUNT(*) FROM auth_state WHERE key = ?1")?;
             let count: i64 = stmt.query_row([&cloned_self.key], |row| row.get(0))?;
             match count > 0 {
                 true => {
-                    let mut update_stmt =
-                        conn.prepare("UPDATE auth_state SET state = ?2 WHERE key = ?1")?;
-                    update_stmt.execute([&cloned_self.key, &cloned_self.state])?;
+                    let mut update_stmt = conn.prepare(
+                        "UPDATE auth_state SET state = ?2, created_at = ?3 WHERE key = ?1",
+                    )?;
+                    update_stmt.execute((&cloned_self.key, &cloned_self.state, created_at))?;
                     Ok(())
                 }
                 false => {
                     conn.execute(
-                        "INSERT INTO auth_state (key, state) VALUES (?1, ?2)",
-                        [&cloned_self.key, &cloned_self.state],
+                        "INSERT INTO auth_state (key, state, created_at) VALUES (?1, ?2, ?3)",
+                        (&cloned_self.key, &cloned_self.state, created_at),
                     )?;
                     Ok(())
                 }
@@ -205,7 +769,7 @@ impl AuthState {
         Ok(())
     }
 
-    pub async fn delete_by_key(pool: &Pool, key: String) -> Result<(), async_sqlite::Error> {
+    pub async fn delete_by_key(pool: &Pool, key: String) -> Result<(), DbError> {
         pool.conn(move |conn| {
             let mut stmt = conn.prepare("DELETE FROM auth_state WHERE key = ?1")?;
             stmt.execute([&key])
@@ -214,7 +778,7 @@ impl AuthState {
         Ok(())
     }
 
-    pub async fn delete_all(pool: &Pool) -> Result<(), async_sqlite::Error> {
+    pub async fn delete_all(pool: &Pool) -> Result<(), DbError> {
         pool.conn(move |conn| {
             let mut stmt = conn.prepare("DELETE FROM auth_state")?;
             stmt.execute([])
@@ -222,4 +786,299 @@ impl AuthState {
         .await?;
         Ok(())
     }
+
+    /// Deletes all states created before `cutoff`, returning how many were removed
+    pub async fn delete_created_before(
+        pool: &Pool,
+        cutoff: DateTime<Utc>,
+    ) -> Result<usize, DbError> {
+        pool.conn(move |conn| {
+            let mut stmt = conn.prepare("DELETE FROM auth_state WHERE created_at < ?1")?;
+            stmt.execute([cutoff.timestamp()])
+        })
+        .await
+        .map_err(DbError::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn test_pool() -> Pool {
+        async_sqlite::PoolBuilder::new()
+            .num_conns(1)
+            .open()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_open_database_sets_pragmas() {
+        let dir = std::env::temp_dir().join(format!("atproto-oauth-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let pool = open_database(dir.join("oauth.sqlite3")).await.unwrap();
+        let (journal_mode, busy_timeout, foreign_keys) = pool
+            .conn(|conn| {
+                Ok((
+                    conn.query_row("PRAGMA journal_mode", [], |row| row.get::<_, String>(0))?,
+                    conn.query_row("PRAGMA busy_timeout", [], |row| row.get::<_, i64>(0))?,
+                    conn.query_row("PRAGMA foreign_keys", [], |row| row.get::<_, i64>(0))?,
+                ))
+            })
+            .await
+            .unwrap();
+        assert_eq!(journal_mode, "wal");
+        assert_eq!(busy_timeout, DEFAULT_BUSY_TIMEOUT.as_millis() as i64);
+        assert_eq!(foreign_keys, 1);
+        pool.close().await.unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+
+        let pool = open_in_memory_database().await.unwrap();
+        create_oauth_tables(&pool).await.unwrap();
+        assert!(AuthSession::list_all(&pool).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_migrations_apply_once() {
+        let pool = test_pool().await;
+        let migrator = Migrator::new().migration(Migration::sql(
+            FIRST_APPLICATION_MIGRATION,
+            "create_notes",
+            "CREATE TABLE notes (id INTEGER PRIMARY KEY)",
+        ));
+        assert_eq!(
+            migrator.run(&pool).await.unwrap(),
+            vec![1, 2, 3, 4, 5, FIRST_APPLICATION_MIGRATION]
+        );
+        assert!(migrator.run(&pool).await.unwrap().is_empty());
+
+        let tables: i64 = pool
+            .conn(|conn| {
+                conn.query_row(
+                    "SELECT COUNT(*) FROM sqlite_master WHERE name IN ('auth_session', 'auth_state', 'notes')",
+                    [],
+                    |row| row.get(0),
+                )
+            })
+            .await
+            .unwrap();
+        assert_eq!(tables, 3);
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_versions_rejected() {
+        let pool = test_pool().await;
+        let result = Migrator::new()
+            .migration(Migration::sql(1, "clash", "SELECT 1"))
+            .run(&pool)
+            .await;
+        assert!(matches!(result, Err(MigrationError::DuplicateVersion(1))));
+    }
+
+    #[tokio::test]
+    async fn test_failed_migration_rolls_back() {
+        let pool = test_pool().await;
+        let result = Migrator::new()
+            .migration(Migration::sql(FIRST_APPLICATION_MIGRATION, "broken", "NOT SQL"))
+            .run(&pool)
+            .await;
+        assert!(matches!(result, Err(MigrationError::DatabaseError(_))));
+
+        let auth_state_exists = pool
+            .conn(|conn| {
+                conn.prepare("SELECT 1 FROM sqlite_master WHERE name = 'auth_state'")?
+                    .exists([])
+            })
+            .await
+            .unwrap();
+        assert!(!auth_state_exists);
+    }
+
+    #[tokio::test]
+    async fn test_upgrades_untracked_tables() {
+        let pool = test_pool().await;
+        pool.conn(|conn| {
+            conn.execute_batch(
+                "CREATE TABLE auth_session (key TEXT PRIMARY KEY, session TEXT NOT NULL);
+                CREATE TABLE auth_state (key TEXT PRIMARY KEY, state TEXT NOT NULL);
+                INSERT INTO auth_state (key, state) VALUES ('old', '{}');
+                INSERT INTO auth_session (key, session) VALUES ('did:plc:old', '{}');",
+            )
+        })
+        .await
+        .unwrap();
+
+        create_oauth_tables(&pool).await.unwrap();
+        let state = AuthState::get_by_key(&pool, "old".to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(state.created_at.timestamp(), 0);
+        let session = AuthSession::get_by_did(&pool, "did:plc:old".to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(session.session_id, DEFAULT_SESSION_ID);
+    }
+
+    #[tokio::test]
+    async fn test_sessions_per_device() {
+        let pool = test_pool().await;
+        create_oauth_tables(&pool).await.unwrap();
+        let did = "did:plc:abc123".to_string();
+        for (session_id, age) in [("laptop", 10), ("phone", 5)] {
+            let mut session = AuthSession::new(did.clone(), session_id)
+                .unwrap()
+                .with_session_id(session_id);
+            session.updated_at = Utc::now() - chrono::Duration::minutes(age);
+            session.save_or_update(&pool).await.unwrap();
+        }
+
+        let sessions = AuthSession::list_for_did(&pool, did.clone()).await.unwrap();
+        let ids: Vec<_> = sessions.iter().map(|s| s.session_id.as_str()).collect();
+        assert_eq!(ids, vec!["phone", "laptop"]);
+        let active = AuthSession::get_by_did(&pool, did.clone()).await.unwrap().unwrap();
+        assert_eq!(active.session_id, "phone");
+
+        AuthSession::delete(&pool, did.clone(), "phone".to_string())
+            .await
+            .unwrap();
+        let laptop = AuthSession::get(&pool, did.clone(), "laptop".to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(laptop.session, "\"laptop\"");
+        assert_eq!(AuthSession::list_for_did(&pool, did).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_did_is_an_error() {
+        let pool = test_pool().await;
+        create_oauth_tables(&pool).await.unwrap();
+        let result = AuthSession::get_by_did(&pool, "not a did".to_string()).await;
+        assert!(matches!(result, Err(DbError::InvalidDid(did)) if did == "not a did"));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_timestamp_is_an_error() {
+        let pool = test_pool().await;
+        create_oauth_tables(&pool).await.unwrap();
+        let did = "did:plc:abc123".to_string();
+        AuthSession::new(did.clone(), "session")
+            .unwrap()
+            .save_or_update(&pool)
+            .await
+            .unwrap();
+        pool.conn(|conn| conn.execute("UPDATE auth_session SET last_used_at = ?1", [i64::MAX]))
+            .await
+            .unwrap();
+        let result = AuthSession::get_by_did(&pool, did).await;
+        assert!(matches!(
+            result,
+            Err(DbError::DatabaseError(async_sqlite::Error::Rusqlite(
+                Error::IntegralValueOutOfRange(6, i64::MAX)
+            )))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_session_compare_and_swap() {
+        let pool = test_pool().await;
+        create_oauth_tables(&pool).await.unwrap();
+        let did = "did:plc:abc123".to_string();
+
+        let session = AuthSession::new(did.clone(), "first").unwrap();
+        assert!(session.compare_and_swap(&pool).await.unwrap());
+        // A second writer that also expected no row loses
+        assert!(!AuthSession::new(did.clone(), "second")
+            .unwrap()
+            .compare_and_swap(&pool)
+            .await
+            .unwrap());
+
+        let mut stored = AuthSession::get_by_did(&pool, did.clone()).await.unwrap().unwrap();
+        assert_eq!(stored.version, 1);
+        let mut concurrent = stored.clone();
+        stored.session = "refreshed".to_string();
+        assert!(stored.compare_and_swap(&pool).await.unwrap());
+        concurrent.session = "stale".to_string();
+        assert!(!concurrent.compare_and_swap(&pool).await.unwrap());
+
+        let stored = AuthSession::get_by_did(&pool, did.clone()).await.unwrap().unwrap();
+        assert_eq!(stored.session, "refreshed");
+        assert_eq!(stored.version, 2);
+
+        AuthSession::new(did.clone(), "overwrite")
+            .unwrap()
+            .save_or_update(&pool)
+            .await
+            .unwrap();
+        let stored = AuthSession::get_by_did(&pool, did).await.unwrap().unwrap();
+        assert_eq!(stored.version, 3);
+    }
+
+    #[tokio::test]
+    async fn test_session_metadata_and_query() {
+        let pool = test_pool().await;
+        create_oauth_tables(&pool).await.unwrap();
+        let rows = [
+            ("did:plc:alice", "laptop", "Mozilla/5.0 Firefox/128.0", "203.0.113.1", 30),
+            ("did:plc:alice", "phone", "Mozilla/5.0 Mobile Safari", "203.0.113.2", 2),
+            ("did:plc:bob", "laptop", "Mozilla/5.0 Firefox/130.0", "198.51.100.9", 1),
+        ];
+        for (did, session_id, user_agent, ip_address, days_unused) in rows {
+            let mut session = AuthSession::new(did.to_string(), session_id)
+                .unwrap()
+                .with_session_id(session_id)
+                .with_client_info(ClientInfo {
+                    user_agent: Some(user_agent.to_string()),
+                    ip_address: Some(ip_address.to_string()),
+                });
+            session.last_used_at = Utc::now() - chrono::Duration::days(days_unused);
+            session.save_or_update(&pool).await.unwrap();
+        }
+
+        let all = AuthSession::query(&pool, &SessionQuery::new()).await.unwrap();
+        let order: Vec<_> = all.iter().map(|s| (s.key.as_str(), s.session_id.as_str())).collect();
+        assert_eq!(
+            order,
+            vec![
+                ("did:plc:bob", "laptop"),
+                ("did:plc:alice", "phone"),
+                ("did:plc:alice", "laptop"),
+            ]
+        );
+
+        let firefox = SessionQuery::new().user_agent_contains("Firefox");
+        assert_eq!(AuthSession::query(&pool, &firefox).await.unwrap().len(), 2);
+        let alice_firefox = firefox.clone().did("did:plc:alice");
+        let found = AuthSession::query(&pool, &alice_firefox).await.unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].ip_address.as_deref(), Some("203.0.113.1"));
+        let stale = SessionQuery::new().used_before(Utc::now() - chrono::Duration::days(7));
+        assert_eq!(AuthSession::query(&pool, &stale).await.unwrap().len(), 1);
+        let by_ip = SessionQuery::new().ip_address("198.51.100.9");
+        assert_eq!(AuthSession::query(&pool, &by_ip).await.unwrap()[0].key, "did:plc:bob");
+        assert_eq!(AuthSession::query(&pool, &SessionQuery::new().limit(1)).await.unwrap().len(), 1);
+
+        // Refreshing keeps the metadata, using the session moves last_used_at
+        AuthSession::new("did:plc:alice".to_string(), "refreshed")
+            .unwrap()
+            .with_session_id("laptop")
+            .save_or_update(&pool)
+            .await
+            .unwrap();
+        AuthSession::touch(&pool, "did:plc:alice".to_string(), "laptop".to_string())
+            .await
+            .unwrap();
+        let laptop = AuthSession::get(&pool, "did:plc:alice".to_string(), "laptop".to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(laptop.user_agent.as_deref(), Some("Mozilla/5.0 Firefox/128.0"));
+        assert!(!laptop.is_idle(chrono::Duration::days(1)));
+        assert!(laptop.created_at <= laptop.updated_at);
+        assert_eq!(AuthSession::query(&pool, &stale).await.unwrap().len(), 0);
+    }
 }
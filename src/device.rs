@@ -0,0 +1,336 @@
+/// Per-device OAuth sessions
+///
+/// atrium's session store contract is keyed by DID only, so a second login for the same DID would
+/// replace the first device's tokens and DPoP key. The SQLite store keys sessions by
+/// `(DID, session id)` instead and reads the session id from a task-local scope set with
+/// [with_session_id]. Outside of a scope the store reads and writes the most recently used
+/// session of the DID and deletes all of them. [DeviceSession] keeps the scope around every
+/// request an agent makes, so token refreshes are written back to the right device. A
+/// [with_client_info] scope around the OAuth callback records who logged in with the new device
+/// session. [DeviceSessionStore] looks up the device sessions of a store directly, e.g. to revoke
+/// them.
+///
+/// A session id scope also marks its device session as in use. The background session refresher
+/// skips sessions in use, since atrium may be refreshing them lazily at the same time, and scopes
+/// entered while the refresher renews a session wait until it is done.
+use async_trait::async_trait;
+use atrium_api::{
+    agent::{CloneWithProxy, Configure, SessionManager},
+    types::string::Did,
+    xrpc::{
+        http::{Request, Response},
+        types::AuthorizationToken,
+        HttpClient, OutputDataOrBytes, XrpcClient, XrpcRequest,
+    },
+};
+use atrium_oauth::store::session::Session;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand::RngCore;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    fmt::Debug,
+    future::Future,
+    sync::{Arc, LazyLock, Mutex},
+};
+use tokio::sync::{OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock};
+
+tokio::task_local! {
+    static SESSION_ID: String;
+    /// DID and version of the session last read or written in the session id scope
+    static SESSION_VERSION: Cell<Option<(String, i64)>>;
+    static CLIENT_INFO: ClientInfo;
+}
+
+/// Details of the client that logged in, stored with its device session
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+}
+
+/// Locks of the device sessions in use or being refreshed, by session id. Scopes share the read
+/// lock of their session and the refresher takes the write lock.
+static SESSION_LOCKS: LazyLock<Mutex<HashMap<String, Arc<RwLock<()>>>>> =
+    LazyLock::new(Default::default);
+
+/// A held lock of a device session, removed from [SESSION_LOCKS] once nobody holds or waits for it
+pub(crate) struct SessionLock {
+    session_id: String,
+    guard: Option<SessionLockGuard>,
+}
+
+/// Guards that are only held, to release the lock when dropped
+enum SessionLockGuard {
+    InUse { _guard: OwnedRwLockReadGuard<()> },
+    Refreshing { _guard: OwnedRwLockWriteGuard<()> },
+}
+
+impl SessionLock {
+    fn lock_of(session_id: &str) -> Arc<RwLock<()>> {
+        let mut locks = SESSION_LOCKS.lock().unwrap();
+        locks.entry(session_id.to_string()).or_default().clone()
+    }
+
+    /// Marks `session_id` as in use, waiting while the refresher renews it
+    async fn in_use(session_id: String) -> Self {
+        let guard = Self::lock_of(&session_id).read_owned().await;
+        Self {
+            session_id,
+            guard: Some(SessionLockGuard::InUse { _guard: guard }),
+        }
+    }
+
+    /// Marks `session_id` as being refreshed, unless a scope is using it. This never waits, so
+    /// nested scopes of one session cannot deadlock behind a waiting refresh.
+    pub(crate) fn try_refreshing(session_id: &str) -> Option<Self> {
+        let guard = Self::lock_of(session_id).try_write_owned().ok()?;
+        Some(Self {
+            session_id: session_id.to_string(),
+            guard: Some(SessionLockGuard::Refreshing { _guard: guard }),
+        })
+    }
+}
+
+impl Drop for SessionLock {
+    fn drop(&mut self) {
+        self.guard.take();
+        let mut locks = SESSION_LOCKS.lock().unwrap();
+        if locks
+            .get(&self.session_id)
+            .is_some_and(|lock| Arc::strong_count(lock) == 1)
+        {
+            locks.remove(&self.session_id);
+        }
+    }
+}
+
+/// Runs `future` with session store calls bound to the device session `session_id`
+pub async fn with_session_id<F: Future>(session_id: impl Into<String>, future: F) -> F::Output {
+    let session_id = session_id.into();
+    let _in_use = SessionLock::in_use(session_id.clone()).await;
+    let future = SESSION_VERSION.scope(Cell::new(None), future);
+    SESSION_ID.scope(session_id, future).await
+}
+
+/// The device session id of the current scope, if any
+pub fn current_session_id() -> Option<String> {
+    SESSION_ID.try_with(Clone::clone).ok()
+}
+
+/// Remembers the version of `did`'s session as read or written in the current scope
+pub(crate) fn record_session_version(did: &str, version: i64) {
+    let _ = SESSION_VERSION.try_with(|slot| slot.set(Some((did.to_string(), version))));
+}
+
+/// Takes the version of `did`'s session recorded in the current scope, if any
+pub(crate) fn take_session_version(did: &str) -> Option<i64> {
+    SESSION_VERSION
+        .try_with(Cell::take)
+        .ok()
+        .flatten()
+        .filter(|(recorded_did, _)| recorded_did == did)
+        .map(|(_, version)| version)
+}
+
+/// Runs `future` with sessions stored by the session store recording `client_info`
+pub async fn with_client_info<F: Future>(client_info: ClientInfo, future: F) -> F::Output {
+    CLIENT_INFO.scope(client_info, future).await
+}
+
+/// The client info of the current scope, if any
+pub fn current_client_info() -> Option<ClientInfo> {
+    CLIENT_INFO.try_with(Clone::clone).ok()
+}
+
+/// Generates a random id for a new device session
+pub fn new_session_id() -> String {
+    let mut id = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut id);
+    URL_SAFE_NO_PAD.encode(id)
+}
+
+/// Direct access to the device sessions of a session store
+///
+/// Stores keyed by DID only have one session per DID, which every device id refers to.
+#[async_trait]
+pub trait DeviceSessionStore: Send + Sync {
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    /// The stored session of the device `session_id` of `did`, if any
+    async fn device_session(&self, did: &Did, session_id: &str)
+        -> Result<Option<Session>, Self::Error>;
+
+    /// The ids of all stored device sessions of `did`
+    async fn device_session_ids(&self, did: &Did) -> Result<Vec<String>, Self::Error>;
+
+    /// Deletes the device session `session_id` of `did`, if it exists
+    async fn delete_device_session(&self, did: &Did, session_id: &str) -> Result<(), Self::Error>;
+
+    /// Records that the device session `session_id` of `did` was used, e.g. by a request
+    /// authenticated with it. Returns false if the session does not exist.
+    async fn touch_device_session(&self, did: &Did, session_id: &str) -> Result<bool, Self::Error> {
+        Ok(self.device_session(did, session_id).await?.is_some())
+    }
+}
+
+/// A session manager whose requests, including token refreshes, run in the scope of one device
+/// session
+pub struct DeviceSession<M> {
+    inner: M,
+    session_id: String,
+}
+
+impl<M> DeviceSession<M> {
+    /// Wraps `inner` so it always uses the device session `session_id`
+    pub fn new(inner: M, session_id: impl Into<String>) -> Self {
+        Self {
+            inner,
+            session_id: session_id.into(),
+        }
+    }
+
+    /// The device session id
+    pub fn session_id(&self) -> &str {
+        &self.session_id
+    }
+
+    /// The wrapped session manager
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    async fn scoped<F: Future>(&self, future: F) -> F::Output {
+        with_session_id(self.session_id.clone(), future).await
+    }
+}
+
+impl<M> HttpClient for DeviceSession<M>
+where
+    M: HttpClient + Send + Sync,
+{
+    async fn send_http(
+        &self,
+        request: Request<Vec<u8>>,
+    ) -> Result<Response<Vec<u8>>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        self.scoped(self.inner.send_http(request)).await
+    }
+}
+
+impl<M> XrpcClient for DeviceSession<M>
+where
+    M: XrpcClient + Send + Sync,
+{
+    fn base_uri(&self) -> String {
+        self.inner.base_uri()
+    }
+
+    async fn authorization_token(&self, is_refresh: bool) -> Option<AuthorizationToken> {
+        self.scoped(self.inner.authorization_token(is_refresh))
+            .await
+    }
+
+    async fn atproto_proxy_header(&self) -> Option<String> {
+        self.inner.atproto_proxy_header().await
+    }
+
+    async fn atproto_accept_labelers_header(&self) -> Option<Vec<String>> {
+        self.inner.atproto_accept_labelers_header().await
+    }
+
+    async fn send_xrpc<P, I, O, E>(
+        &self,
+        request: &XrpcRequest<P, I>,
+    ) -> Result<OutputDataOrBytes<O>, atrium_api::xrpc::Error<E>>
+    where
+        P: Serialize + Send + Sync,
+        I: Serialize + Send + Sync,
+        O: DeserializeOwned + Send + Sync,
+        E: DeserializeOwned + Send + Sync + Debug,
+    {
+        self.scoped(self.inner.send_xrpc(request)).await
+    }
+}
+
+impl<M> SessionManager for DeviceSession<M>
+where
+    M: SessionManager + Send + Sync,
+{
+    async fn did(&self) -> Option<Did> {
+        self.inner.did().await
+    }
+}
+
+impl<M: Configure> Configure for DeviceSession<M> {
+    fn configure_endpoint(&self, endpoint: String) {
+        self.inner.configure_endpoint(endpoint);
+    }
+    fn configure_labelers_header(&self, labeler_dids: Option<Vec<(Did, bool)>>) {
+        self.inner.configure_labelers_header(labeler_dids);
+    }
+    fn configure_proxy_header(&self, did: Did, service_type: impl AsRef<str>) {
+        self.inner.configure_proxy_header(did, service_type);
+    }
+}
+
+impl<M: CloneWithProxy> CloneWithProxy for DeviceSession<M> {
+    fn clone_with_proxy(&self, did: Did, service_type: impl AsRef<str>) -> Self {
+        Self {
+            inner: self.inner.clone_with_proxy(did, service_type),
+            session_id: self.session_id.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_session_id_scope() {
+        assert_eq!(current_session_id(), None);
+        let id = with_session_id("device-1", async { current_session_id() }).await;
+        assert_eq!(id.as_deref(), Some("device-1"));
+        assert_eq!(current_session_id(), None);
+        assert_ne!(new_session_id(), new_session_id());
+    }
+
+    #[tokio::test]
+    async fn test_session_version_is_scoped() {
+        record_session_version("did:plc:abc123", 1);
+        assert_eq!(take_session_version("did:plc:abc123"), None);
+        let version = with_session_id("device-1", async {
+            record_session_version("did:plc:abc123", 3);
+            assert_eq!(take_session_version("did:plc:other"), None);
+            record_session_version("did:plc:abc123", 4);
+            let version = take_session_version("did:plc:abc123");
+            assert_eq!(take_session_version("did:plc:abc123"), None);
+            version
+        })
+        .await;
+        assert_eq!(version, Some(4));
+    }
+
+    #[tokio::test]
+    async fn test_refresh_excludes_scopes() {
+        with_session_id("device-in-use", async {
+            assert!(SessionLock::try_refreshing("device-in-use").is_none());
+            // Nested scopes of the session only share its lock
+            with_session_id("device-in-use", async {}).await;
+            assert!(SessionLock::try_refreshing("device-idle").is_some());
+        })
+        .await;
+
+        let refreshing = SessionLock::try_refreshing("device-in-use").unwrap();
+        let scope = tokio::spawn(with_session_id("device-in-use", async {}));
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        assert!(
+            !scope.is_finished(),
+            "scope entered during a refresh must wait for it"
+        );
+        drop(refreshing);
+        scope.await.unwrap();
+        assert!(!SESSION_LOCKS.lock().unwrap().contains_key("device-in-use"));
+    }
+}
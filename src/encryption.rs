@@ -0,0 +1,263 @@
+/// Encryption at rest for stored sessions and states
+///
+/// Stored sessions contain DPoP private keys and refresh tokens. [StorageEncryption] seals them
+/// with AES-256-GCM before they are written, binding each value to its table and row key so a
+/// ciphertext cannot be replayed under another DID or device session. Values carry the id of the
+/// key that sealed them, so keys can be rotated by keeping the previous key around for decryption.
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand::RngCore;
+use thiserror::Error;
+
+/// Length of an AES-256 key in bytes
+const KEY_LEN: usize = 32;
+/// Length of an AES-GCM nonce in bytes
+const NONCE_LEN: usize = 12;
+/// Prefix of encrypted values. Plaintext values are JSON and can never start with it.
+const PREFIX: &str = "enc1.";
+
+#[derive(Error, Debug)]
+pub enum EncryptionError {
+    #[error("Encryption key must be exactly {KEY_LEN} bytes")]
+    InvalidKeyLength,
+    #[error("Key id must be non-empty and must not contain '.'")]
+    InvalidKeyId,
+    #[error("Value was encrypted with unknown key '{0}'")]
+    UnknownKey(String),
+    #[error("Encrypted value is malformed")]
+    Malformed,
+    #[error("Failed to encrypt value")]
+    EncryptionFailed,
+    #[error("Failed to decrypt value")]
+    DecryptionFailed,
+    #[error("Value is encrypted but no encryption key is configured")]
+    NotConfigured,
+}
+
+/// AES-256 key used to encrypt stored values
+#[derive(Clone)]
+pub struct EncryptionKey {
+    id: String,
+    key: [u8; KEY_LEN],
+}
+
+impl EncryptionKey {
+    /// Creates a key from an id and exactly 32 bytes of key material
+    pub fn new(id: impl Into<String>, key: impl AsRef<[u8]>) -> Result<Self, EncryptionError> {
+        let id = id.into();
+        if id.is_empty() || id.contains('.') {
+            return Err(EncryptionError::InvalidKeyId);
+        }
+        let key = key
+            .as_ref()
+            .try_into()
+            .map_err(|_| EncryptionError::InvalidKeyLength)?;
+        Ok(Self { id, key })
+    }
+
+    /// Creates a key with random key material.
+    /// Values encrypted with it can no longer be read once the process restarts.
+    pub fn generate(id: impl Into<String>) -> Result<Self, EncryptionError> {
+        let mut key = [0u8; KEY_LEN];
+        rand::thread_rng().fill_bytes(&mut key);
+        Self::new(id, key)
+    }
+
+    /// The id stored alongside values encrypted with this key
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new(&self.key.into())
+    }
+}
+
+impl std::fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EncryptionKey")
+            .field("id", &self.id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Encrypts values with the current key and decrypts with the current or a previous key
+///
+/// Values written before encryption was enabled are still readable and are encrypted the next
+/// time they are written.
+#[derive(Clone, Debug)]
+pub struct StorageEncryption {
+    key: EncryptionKey,
+    previous_keys: Vec<EncryptionKey>,
+}
+
+impl StorageEncryption {
+    /// Encrypt new values with `key`
+    pub fn new(key: EncryptionKey) -> Self {
+        Self {
+            key,
+            previous_keys: Vec::new(),
+        }
+    }
+
+    /// Decrypt values sealed with a previous key, so stored data survives a key rotation
+    pub fn previous_key(mut self, key: EncryptionKey) -> Self {
+        self.previous_keys.push(key);
+        self
+    }
+
+    /// Returns true if `stored` is an encrypted value
+    pub fn is_encrypted(stored: &str) -> bool {
+        stored.starts_with(PREFIX)
+    }
+
+    /// Encrypts `plaintext`, binding it to `context` (e.g. the table and row key)
+    pub fn encrypt(&self, context: &str, plaintext: &str) -> Result<String, EncryptionError> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let ciphertext = self
+            .key
+            .cipher()
+            .encrypt(
+                &Nonce::from(nonce),
+                Payload {
+                    msg: plaintext.as_bytes(),
+                    aad: context.as_bytes(),
+                },
+            )
+            .map_err(|_| EncryptionError::EncryptionFailed)?;
+        Ok(format!(
+            "{PREFIX}{}.{}.{}",
+            self.key.id,
+            URL_SAFE_NO_PAD.encode(nonce),
+            URL_SAFE_NO_PAD.encode(ciphertext)
+        ))
+    }
+
+    /// Decrypts a value produced by [encrypt](StorageEncryption::encrypt) with the same `context`.
+    /// Plaintext values are returned unchanged.
+    pub fn decrypt(&self, context: &str, stored: &str) -> Result<String, EncryptionError> {
+        let Some(sealed) = stored.strip_prefix(PREFIX) else {
+            return Ok(stored.to_string());
+        };
+        let mut parts = sealed.split('.');
+        let (Some(key_id), Some(nonce), Some(ciphertext), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(EncryptionError::Malformed);
+        };
+        let key = std::iter::once(&self.key)
+            .chain(&self.previous_keys)
+            .find(|key| key.id == key_id)
+            .ok_or_else(|| EncryptionError::UnknownKey(key_id.to_string()))?;
+        let nonce: [u8; NONCE_LEN] = URL_SAFE_NO_PAD
+            .decode(nonce)
+            .map_err(|_| EncryptionError::Malformed)?
+            .try_into()
+            .map_err(|_| EncryptionError::Malformed)?;
+        let ciphertext = URL_SAFE_NO_PAD
+            .decode(ciphertext)
+            .map_err(|_| EncryptionError::Malformed)?;
+        let plaintext = key
+            .cipher()
+            .decrypt(
+                &Nonce::from(nonce),
+                Payload {
+                    msg: &ciphertext,
+                    aad: context.as_bytes(),
+                },
+            )
+            .map_err(|_| EncryptionError::DecryptionFailed)?;
+        String::from_utf8(plaintext).map_err(|_| EncryptionError::Malformed)
+    }
+}
+
+/// Seals `value` if encryption is configured
+pub(crate) fn seal(
+    encryption: Option<&StorageEncryption>,
+    context: &str,
+    value: String,
+) -> Result<String, EncryptionError> {
+    match encryption {
+        Some(encryption) => encryption.encrypt(context, &value),
+        None => Ok(value),
+    }
+}
+
+/// Opens `stored` if it is encrypted, failing if no key is configured to read it
+pub(crate) fn open(
+    encryption: Option<&StorageEncryption>,
+    context: &str,
+    stored: String,
+) -> Result<String, EncryptionError> {
+    match encryption {
+        Some(encryption) => encryption.decrypt(context, &stored),
+        None if StorageEncryption::is_encrypted(&stored) => Err(EncryptionError::NotConfigured),
+        None => Ok(stored),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_round_trip() {
+        let encryption = StorageEncryption::new(EncryptionKey::generate("k1").unwrap());
+        let sealed = encryption
+            .encrypt("auth_session:did:plc:abc", r#"{"secret":1}"#)
+            .unwrap();
+        assert!(sealed.starts_with("enc1.k1."));
+        assert!(!sealed.contains("secret"));
+        assert_eq!(
+            encryption
+                .decrypt("auth_session:did:plc:abc", &sealed)
+                .unwrap(),
+            r#"{"secret":1}"#
+        );
+        assert!(matches!(
+            encryption.decrypt("auth_session:did:plc:other", &sealed),
+            Err(EncryptionError::DecryptionFailed)
+        ));
+        assert_eq!(encryption.decrypt("any", "{}").unwrap(), "{}");
+    }
+
+    #[test]
+    fn test_key_rotation() {
+        let old_key = EncryptionKey::generate("old").unwrap();
+        let sealed = StorageEncryption::new(old_key.clone())
+            .encrypt("ctx", "value")
+            .unwrap();
+
+        let rotated = StorageEncryption::new(EncryptionKey::generate("new").unwrap());
+        assert!(matches!(
+            rotated.decrypt("ctx", &sealed),
+            Err(EncryptionError::UnknownKey(id)) if id == "old"
+        ));
+        let rotated = rotated.previous_key(old_key);
+        assert_eq!(rotated.decrypt("ctx", &sealed).unwrap(), "value");
+        assert!(rotated
+            .encrypt("ctx", "value")
+            .unwrap()
+            .starts_with("enc1.new."));
+    }
+
+    #[test]
+    fn test_key_validation() {
+        assert!(matches!(
+            EncryptionKey::new("k1", [0u8; 16]),
+            Err(EncryptionError::InvalidKeyLength)
+        ));
+        assert!(matches!(
+            EncryptionKey::new("a.b", [0u8; 32]),
+            Err(EncryptionError::InvalidKeyId)
+        ));
+        assert!(matches!(
+            open(None, "ctx", "enc1.k1.x.y".to_string()),
+            Err(EncryptionError::NotConfigured)
+        ));
+    }
+}
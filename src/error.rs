@@ -0,0 +1,17 @@
+/// Errors shared across the crate
+use crate::db::MigrationError;
+use thiserror::Error;
+
+/// Errors of the database layer: the [AuthSession](crate::db::AuthSession) and
+/// [AuthState](crate::db::AuthState) models, opening databases and creating the OAuth tables
+#[derive(Error, Debug)]
+pub enum DbError {
+    #[error("Invalid DID: {0}")]
+    InvalidDid(String),
+    #[error("Serialization error: {0}")]
+    SerializationError(#[from] serde_json::Error),
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] async_sqlite::Error),
+    #[error("Migration failed: {0}")]
+    MigrationError(#[from] MigrationError),
+}
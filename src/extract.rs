@@ -0,0 +1,230 @@
+/// Axum extractors for authenticated requests
+///
+/// [AuthenticatedUser] validates the signed application session, restores the user's OAuth
+/// session for the device the session belongs to and hands the handler an [Agent] that performs
+/// XRPC calls on the user's behalf.
+use crate::{
+    device::{self, DeviceSession, DeviceSessionStore},
+    oauth::{AtprotoOAuthClient, AtprotoOAuthSession},
+    session::{SessionCookieError, SessionCookies},
+    storage::{SqliteSessionStore, SqliteStateStore},
+};
+use atrium_api::{agent::Agent, types::string::Did};
+use atrium_common::store::Store;
+use atrium_oauth::store::{
+    session::{Session, SessionStore},
+    state::StateStore,
+};
+use axum::{
+    async_trait,
+    extract::{FromRef, FromRequestParts},
+    http::{request::Parts, StatusCode},
+    response::{IntoResponse, Response},
+};
+use std::{marker::PhantomData, sync::Arc};
+use thiserror::Error;
+
+/// Reasons an [AuthenticatedUser] could not be extracted; all respond with 401
+#[derive(Error, Debug)]
+pub enum AuthRejection {
+    #[error("Authentication required: {0}")]
+    Unauthenticated(#[from] SessionCookieError),
+    #[error("Failed to restore OAuth session: {0}")]
+    RestoreFailed(#[from] atrium_oauth::Error),
+}
+
+impl IntoResponse for AuthRejection {
+    fn into_response(self) -> Response {
+        log::debug!("Rejecting request: {self}");
+        (StatusCode::UNAUTHORIZED, self.to_string()).into_response()
+    }
+}
+
+/// A request authenticated by a signed session whose OAuth session could be restored
+///
+/// The application state must expose the session cookie layer and the OAuth client through
+/// [FromRef]. Clients with other stores than SQLite name them as type parameters, e.g.
+/// `AuthenticatedUser<PostgresStateStore, PostgresSessionStore>`:
+///
+/// ```ignore
+/// impl FromRef<AppState> for Arc<SessionCookies> { ... }
+/// impl FromRef<AppState> for Arc<AtprotoOAuthClient> { ... }
+///
+/// async fn handler(user: AuthenticatedUser) { user.agent.api.com.atproto... }
+/// ```
+pub struct AuthenticatedUser<S0 = SqliteStateStore, S1 = SqliteSessionStore>
+where
+    S1: SessionStore + Send + Sync + 'static,
+    S1::Error: std::error::Error + Send + Sync + 'static,
+{
+    pub did: Did,
+    /// The device session this request belongs to
+    pub session_id: String,
+    pub agent: Agent<DeviceSession<AtprotoOAuthSession<S1>>>,
+    state_store: PhantomData<fn() -> S0>,
+}
+
+#[async_trait]
+impl<S, S0, S1> FromRequestParts<S> for AuthenticatedUser<S0, S1>
+where
+    S: Send + Sync,
+    S0: StateStore + Send + Sync + 'static,
+    S1: SessionStore + DeviceSessionStore + Send + Sync + 'static,
+    S0::Error: std::error::Error + Send + Sync + 'static,
+    <S1 as Store<Did, Session>>::Error: std::error::Error + Send + Sync + 'static,
+    Arc<SessionCookies<S1>>: FromRef<S>,
+    Arc<AtprotoOAuthClient<S0, S1>>: FromRef<S>,
+{
+    type Rejection = AuthRejection;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let session_cookies = Arc::<SessionCookies<S1>>::from_ref(state);
+        let session = session_cookies.session_from_headers(&parts.headers).await?;
+
+        let client = Arc::<AtprotoOAuthClient<S0, S1>>::from_ref(state);
+        let oauth_session =
+            device::with_session_id(session.session_id.clone(), client.restore(&session.did))
+                .await?;
+        Ok(Self {
+            did: session.did,
+            agent: Agent::new(DeviceSession::new(
+                oauth_session,
+                session.session_id.clone(),
+            )),
+            session_id: session.session_id,
+            state_store: PhantomData,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{db::create_oauth_tables, oauth::OAuthClientBuilder, session::SessionCookieKey};
+    use axum::{body::Body, http::Request, routing::get, Router};
+    use tower::ServiceExt;
+
+    #[derive(Clone)]
+    struct AppState {
+        client: Arc<AtprotoOAuthClient>,
+        session_cookies: Arc<SessionCookies>,
+    }
+
+    impl FromRef<AppState> for Arc<AtprotoOAuthClient> {
+        fn from_ref(state: &AppState) -> Self {
+            state.client.clone()
+        }
+    }
+
+    impl FromRef<AppState> for Arc<SessionCookies> {
+        fn from_ref(state: &AppState) -> Self {
+            state.session_cookies.clone()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rejects_unauthenticated_requests() {
+        let pool = async_sqlite::PoolBuilder::new()
+            .num_conns(1)
+            .open()
+            .await
+            .unwrap();
+        create_oauth_tables(&pool).await.unwrap();
+        let session_cookies = Arc::new(SessionCookies::new(
+            pool.clone(),
+            SessionCookieKey::generate("k1").unwrap(),
+        ));
+        let state = AppState {
+            client: OAuthClientBuilder::new().db_pool(pool).build().unwrap(),
+            session_cookies: session_cookies.clone(),
+        };
+        let router = Router::new()
+            .route(
+                "/",
+                get(|user: AuthenticatedUser| async move { user.did.to_string() }),
+            )
+            .with_state(state);
+
+        let response = router
+            .clone()
+            .oneshot(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // A validly signed token without a stored OAuth session is still rejected
+        let token = session_cookies.token_for(
+            &Did::new("did:plc:abc123".to_string()).unwrap(),
+            crate::db::DEFAULT_SESSION_ID,
+        );
+        let response = router
+            .oneshot(
+                Request::get("/")
+                    .header("Authorization", format!("Bearer {token}"))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[cfg(feature = "redis-storage")]
+    #[tokio::test]
+    async fn test_extracts_with_other_stores() {
+        use crate::redis_store::{tests::test_connection, RedisSessionStore, RedisStateStore};
+
+        #[derive(Clone)]
+        struct RedisAppState {
+            client: Arc<AtprotoOAuthClient<RedisStateStore, RedisSessionStore>>,
+            session_cookies: Arc<SessionCookies<RedisSessionStore>>,
+        }
+
+        impl FromRef<RedisAppState> for Arc<AtprotoOAuthClient<RedisStateStore, RedisSessionStore>> {
+            fn from_ref(state: &RedisAppState) -> Self {
+                state.client.clone()
+            }
+        }
+
+        impl FromRef<RedisAppState> for Arc<SessionCookies<RedisSessionStore>> {
+            fn from_ref(state: &RedisAppState) -> Self {
+                state.session_cookies.clone()
+            }
+        }
+
+        let connection = test_connection().await;
+        let session_cookies = Arc::new(SessionCookies::with_store(
+            RedisSessionStore::new(connection.clone()),
+            SessionCookieKey::generate("k1").unwrap(),
+        ));
+        let state = RedisAppState {
+            client: OAuthClientBuilder::new().build_redis(connection).unwrap(),
+            session_cookies: session_cookies.clone(),
+        };
+        let router = Router::new()
+            .route(
+                "/",
+                get(
+                    |user: AuthenticatedUser<RedisStateStore, RedisSessionStore>| async move {
+                        user.did.to_string()
+                    },
+                ),
+            )
+            .with_state(state);
+
+        let token = session_cookies.token_for(
+            &Did::new("did:plc:nosession".to_string()).unwrap(),
+            crate::db::DEFAULT_SESSION_ID,
+        );
+        let response = router
+            .oneshot(
+                Request::get("/")
+                    .header("Authorization", format!("Bearer {token}"))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,301 @@
+/// Bidirectional verification of atproto identities
+///
+/// A DID document claims handles in `alsoKnownAs`, but the claim only counts if the handle
+/// resolves back to the DID, through its `_atproto` DNS TXT record or its
+/// `/.well-known/atproto-did` endpoint. Applications must check this before displaying a
+/// handle; [IdentityVerifier] does so and shows `handle.invalid` for unverified identities.
+use crate::oauth::{ClientDidResolver, ClientHandleResolver};
+use atrium_api::did_doc::DidDocument;
+use atrium_api::types::string::{Did, Handle};
+use atrium_identity::{did::DidResolver, handle::HandleResolver};
+use thiserror::Error;
+
+/// Handle displayed for identities without a verified handle
+pub const INVALID_HANDLE: &str = "handle.invalid";
+
+#[derive(Error, Debug)]
+pub enum IdentityError {
+    #[error("Failed to resolve DID document: {0}")]
+    DidResolution(atrium_identity::Error),
+    #[error("Failed to resolve handle: {0}")]
+    HandleResolution(atrium_identity::Error),
+    #[error("DID document of {did} has the id {id}")]
+    DocumentMismatch { did: String, id: String },
+    #[error("Handle {0} does not belong to the DID it resolves to")]
+    UnverifiedHandle(String),
+}
+
+/// An identity whose handle, if any, resolves back to its DID
+#[derive(Debug, Clone)]
+pub struct VerifiedIdentity {
+    pub did: Did,
+    /// A handle of the DID document that resolves back to the DID: the one verified by
+    /// [verify_handle](IdentityVerifier::verify_handle), otherwise the first one
+    pub handle: Option<Handle>,
+    /// Endpoint of the PDS hosting the account
+    pub pds_endpoint: Option<String>,
+    /// Multibase encoded public key of the `#atproto` verification method
+    pub signing_key: Option<String>,
+    pub document: DidDocument,
+}
+
+impl VerifiedIdentity {
+    /// The verified handle, or [INVALID_HANDLE]
+    pub fn handle_or_invalid(&self) -> &str {
+        self.handle.as_ref().map_or(INVALID_HANDLE, Handle::as_str)
+    }
+}
+
+/// Returns the handles claimed in the `alsoKnownAs` of `document`, in order
+pub fn claimed_handles(document: &DidDocument) -> Vec<Handle> {
+    document
+        .also_known_as
+        .iter()
+        .flatten()
+        .filter_map(|aka| aka.strip_prefix("at://"))
+        .filter_map(|handle| Handle::new(handle.to_lowercase()).ok())
+        .collect()
+}
+
+/// Resolves DIDs and handles and checks that they point at each other
+pub struct IdentityVerifier<D = ClientDidResolver, H = ClientHandleResolver> {
+    did_resolver: D,
+    handle_resolver: H,
+}
+
+impl<D, H> IdentityVerifier<D, H>
+where
+    D: DidResolver + Send + Sync,
+    H: HandleResolver + Send + Sync,
+{
+    /// Creates a verifier from a DID and a handle resolver. See
+    /// [identity_verifier](crate::oauth::OAuthClientBuilder::identity_verifier) for one that
+    /// resolves like the OAuth client.
+    pub fn new(did_resolver: D, handle_resolver: H) -> Self {
+        Self {
+            did_resolver,
+            handle_resolver,
+        }
+    }
+
+    /// Resolves the document of `did`, checking that it describes `did`
+    async fn resolve_document(&self, did: &Did) -> Result<DidDocument, IdentityError> {
+        let document = self
+            .did_resolver
+            .resolve(did)
+            .await
+            .map_err(IdentityError::DidResolution)?;
+        if document.id != did.as_str() {
+            return Err(IdentityError::DocumentMismatch {
+                did: did.to_string(),
+                id: document.id,
+            });
+        }
+        Ok(document)
+    }
+
+    /// Resolves the document of `did` and verifies the handles it claims. An identity without
+    /// a handle that resolves back to `did` is returned with `handle: None`.
+    pub async fn verify_did(&self, did: &Did) -> Result<VerifiedIdentity, IdentityError> {
+        let document = self.resolve_document(did).await?;
+        let mut verified_handle = None;
+        for handle in claimed_handles(&document) {
+            match self.handle_resolver.resolve(&handle).await {
+                Ok(resolved) if resolved == *did => {
+                    verified_handle = Some(handle);
+                    break;
+                }
+                Ok(resolved) => log::debug!(
+                    "Handle {} of {} resolves to {}",
+                    handle.as_str(),
+                    did.as_str(),
+                    resolved.as_str()
+                ),
+                Err(e) => log::debug!(
+                    "Handle {} of {} does not resolve: {e}",
+                    handle.as_str(),
+                    did.as_str()
+                ),
+            }
+        }
+        Ok(verified_identity(did.clone(), verified_handle, document))
+    }
+
+    /// Resolves `handle` and verifies that the document of its DID claims it. The identity is
+    /// returned with `handle`, even if the document claims other verified handles before it.
+    pub async fn verify_handle(
+        &self,
+        handle: &Handle,
+    ) -> Result<VerifiedIdentity, IdentityError> {
+        // Handles are case-insensitive and claimed_handles lowercases them
+        let handle = Handle::new(handle.as_str().to_lowercase())
+            .map_err(|_| IdentityError::UnverifiedHandle(handle.to_string()))?;
+        let did = self
+            .handle_resolver
+            .resolve(&handle)
+            .await
+            .map_err(IdentityError::HandleResolution)?;
+        let document = self.resolve_document(&did).await?;
+        if !claimed_handles(&document).contains(&handle) {
+            return Err(IdentityError::UnverifiedHandle(handle.to_string()));
+        }
+        Ok(verified_identity(did, Some(handle), document))
+    }
+}
+
+fn verified_identity(did: Did, handle: Option<Handle>, document: DidDocument) -> VerifiedIdentity {
+    VerifiedIdentity {
+        did,
+        handle,
+        pds_endpoint: document.get_pds_endpoint(),
+        signing_key: document
+            .get_signing_key()
+            .and_then(|key| key.public_key_multibase.clone()),
+        document,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use atrium_common::resolver::Resolver;
+    use crate::overrides::{OverrideResolver, ResolverOverrides};
+
+    /// Fails every lookup, so only the overrides resolve
+    struct OfflineDids;
+
+    impl Resolver for OfflineDids {
+        type Input = Did;
+        type Output = DidDocument;
+        type Error = atrium_identity::Error;
+
+        async fn resolve(&self, _: &Did) -> Result<DidDocument, Self::Error> {
+            Err(atrium_identity::Error::NotFound)
+        }
+    }
+
+    impl DidResolver for OfflineDids {}
+
+    struct OfflineHandles;
+
+    impl Resolver for OfflineHandles {
+        type Input = Handle;
+        type Output = Did;
+        type Error = atrium_identity::Error;
+
+        async fn resolve(&self, _: &Handle) -> Result<Did, Self::Error> {
+            Err(atrium_identity::Error::NotFound)
+        }
+    }
+
+    impl HandleResolver for OfflineHandles {}
+
+    fn handle(handle: &str) -> Handle {
+        Handle::new(handle.to_string()).unwrap()
+    }
+
+    fn did(did: &str) -> Did {
+        Did::new(did.to_string()).unwrap()
+    }
+
+    fn document(id: &str, also_known_as: &[&str]) -> DidDocument {
+        DidDocument {
+            context: None,
+            id: id.to_string(),
+            also_known_as: Some(also_known_as.iter().map(|aka| aka.to_string()).collect()),
+            verification_method: None,
+            service: None,
+        }
+    }
+
+    fn verifier(
+        overrides: &ResolverOverrides,
+    ) -> IdentityVerifier<OverrideResolver<OfflineDids>, OverrideResolver<OfflineHandles>> {
+        IdentityVerifier::new(
+            OverrideResolver::did_documents(OfflineDids, overrides),
+            OverrideResolver::handles(OfflineHandles, overrides),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_verified_handle() {
+        let overrides = ResolverOverrides::new().account(
+            handle("alice.test"),
+            did("did:plc:alice"),
+            "http://localhost:2583",
+        );
+        let identity = verifier(&overrides)
+            .verify_did(&did("did:plc:alice"))
+            .await
+            .unwrap();
+        assert_eq!(identity.handle_or_invalid(), "alice.test");
+        assert_eq!(identity.pds_endpoint.as_deref(), Some("http://localhost:2583"));
+
+        let identity = verifier(&overrides)
+            .verify_handle(&handle("alice.test"))
+            .await
+            .unwrap();
+        assert_eq!(identity.did.as_str(), "did:plc:alice");
+    }
+
+    #[tokio::test]
+    async fn test_handle_claimed_by_another_did_is_invalid() {
+        // mallory claims alice.test, which resolves to alice
+        let overrides = ResolverOverrides::new()
+            .account(handle("alice.test"), did("did:plc:alice"), "http://localhost:2583")
+            .account(handle("alice.test"), did("did:plc:mallory"), "http://localhost:2583")
+            .handle(handle("alice.test"), did("did:plc:alice"));
+        let identity = verifier(&overrides)
+            .verify_did(&did("did:plc:mallory"))
+            .await
+            .unwrap();
+        assert_eq!(identity.handle, None);
+        assert_eq!(identity.handle_or_invalid(), INVALID_HANDLE);
+
+        // A handle that does not resolve at all is invalid as well
+        let overrides = ResolverOverrides::new()
+            .did_document(document("did:plc:bob", &["at://bob.test"]))
+            .unwrap();
+        let identity = verifier(&overrides)
+            .verify_did(&did("did:plc:bob"))
+            .await
+            .unwrap();
+        assert_eq!(identity.handle_or_invalid(), INVALID_HANDLE);
+        assert!(matches!(
+            verifier(&overrides).verify_handle(&handle("bob.test")).await,
+            Err(IdentityError::HandleResolution(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_verify_handle_accepts_any_claimed_handle() {
+        // Both handles resolve to alice, the second one is verified on its own
+        let overrides = ResolverOverrides::new()
+            .did_document(document("did:plc:alice", &["at://alice.test", "at://Alice.Example"]))
+            .unwrap()
+            .handle(handle("alice.test"), did("did:plc:alice"))
+            .handle(handle("alice.example"), did("did:plc:alice"))
+            .handle(handle("unclaimed.test"), did("did:plc:alice"));
+        let identity = verifier(&overrides)
+            .verify_handle(&handle("Alice.Example"))
+            .await
+            .unwrap();
+        assert_eq!(identity.did.as_str(), "did:plc:alice");
+        assert_eq!(identity.handle_or_invalid(), "alice.example");
+
+        // A handle resolving to the DID still has to be claimed by its document
+        assert!(matches!(
+            verifier(&overrides).verify_handle(&handle("unclaimed.test")).await,
+            Err(IdentityError::UnverifiedHandle(_))
+        ));
+    }
+
+    #[test]
+    fn test_claimed_handles() {
+        let document = document(
+            "did:plc:alice",
+            &["at://Alice.Test", "https://alice.example.com", "at://not a handle"],
+        );
+        assert_eq!(claimed_handles(&document), vec![handle("alice.test")]);
+    }
+}
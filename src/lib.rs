@@ -1,26 +1,93 @@
-/// A reusable crate for AT Protocol OAuth functionality
-/// 
-/// This crate provides OAuth client setup, session/state storage, and DNS resolution
-/// components that can be reused across AT Protocol applications.
+//! A reusable crate for AT Protocol OAuth functionality
+//!
+//! This crate provides OAuth client setup, session/state storage, and DNS resolution
+//! components that can be reused across AT Protocol applications.
 
 pub mod oauth;
 pub mod storage;
 pub mod resolver;
+pub mod cache;
 pub mod db;
+pub mod device;
+pub mod encryption;
+pub mod error;
+pub mod extract;
+pub mod identity;
+pub mod keys;
+pub mod overrides;
+pub mod policy;
+pub mod revocation;
+pub mod router;
+pub mod session;
+pub mod tasks;
+pub mod token;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+#[cfg(feature = "postgres-storage")]
+pub mod postgres;
+#[cfg(feature = "redis-storage")]
+pub mod redis_store;
 
 // Re-export commonly used types and traits for convenience
-pub use oauth::{OAuthClientBuilder, AtprotoOAuthClient};
+pub use oauth::{
+    OAuthClientBuilder, AtprotoOAuthClient, AtprotoOAuthSession, CachedDidResolver,
+    CachedHandleResolver, ClientDidResolver, ClientHandleResolver,
+};
+pub use extract::{AuthRejection, AuthenticatedUser};
+pub use device::{
+    new_session_id, with_client_info, with_session_id, ClientInfo, DeviceSession, DeviceSessionStore,
+};
+pub use encryption::{EncryptionError, EncryptionKey, StorageEncryption};
+pub use error::DbError;
+pub use identity::{
+    claimed_handles, IdentityError, IdentityVerifier, VerifiedIdentity, INVALID_HANDLE,
+};
+pub use keys::{jwk_from_json, jwk_from_pem, KeyError};
+pub use overrides::{OverrideError, OverrideResolver, ResolverOverrides};
+pub use policy::{DidMethod, DidPolicy, PolicyResolver, PolicyViolation};
+pub use revocation::{revoke_all_sessions, revoke_session, RevocationError, RevocationSummary};
+pub use router::{
+    client_metadata_router, oauth_routes, NoopHooks, OAuthFlowError, OAuthHooks, OAuthRoutesConfig,
+    CALLBACK_PATH, CLIENT_METADATA_PATH, JWKS_PATH, LOGIN_PATH, LOGOUT_PATH,
+};
 pub use storage::{SqliteSessionStore, SqliteStateStore, SqliteStoreError};
-pub use resolver::HickoryDnsTxtResolver;
+pub use tasks::{
+    spawn_session_refresher, spawn_state_sweeper, RefreshFailureHandler, DEFAULT_REFRESH_MARGIN,
+};
+pub use token::{TokenClient, TokenError};
+#[cfg(feature = "postgres-storage")]
+pub use oauth::AtprotoPostgresOAuthClient;
+#[cfg(feature = "postgres-storage")]
+pub use postgres::{
+    create_postgres_oauth_tables, spawn_postgres_state_sweeper, PostgresSessionStore,
+    PostgresStateStore, PostgresStoreError,
+};
+#[cfg(feature = "redis-storage")]
+pub use oauth::AtprotoRedisOAuthClient;
+#[cfg(feature = "redis-storage")]
+pub use redis_store::{RedisSessionStore, RedisStateStore, RedisStoreError};
+pub use cache::CacheStats;
+pub use resolver::{
+    CachingResolver, DnsResolverError, HickoryDnsTxtResolver, HickoryDnsTxtResolverBuilder,
+    ResolverCacheConfig, ResolverCacheStats, DEFAULT_CACHE_ENTRIES, DEFAULT_CACHE_TTL,
+    DEFAULT_NEGATIVE_CACHE_TTL,
+};
+pub use session::{SessionCookieError, SessionCookieKey, SessionCookies, SessionData};
 
 // Re-export OAuth database models and helper functions for custom schema implementations
-pub use db::{create_oauth_tables, AuthSession, AuthState};
+pub use db::{
+    create_oauth_tables, oauth_migrations, open_database, open_in_memory_database, AuthSession,
+    AuthState, Migration, MigrationError, Migrator, SessionQuery, DEFAULT_BUSY_TIMEOUT,
+    DEFAULT_SESSION_ID, FIRST_APPLICATION_MIGRATION, IN_MEMORY_DATABASE,
+};
 
 // Re-export key external types that users will need
 pub use atrium_oauth::{
     OAuthClient, OAuthClientConfig, Scope, KnownScope, AuthorizeOptions, CallbackParams,
-    AtprotoLocalhostClientMetadata, DefaultHttpClient, OAuthResolverConfig
+    AtprotoClientMetadata, AtprotoLocalhostClientMetadata, AuthMethod, GrantType,
+    DefaultHttpClient, OAuthResolverConfig
 };
+pub use jose_jwk::Jwk;
 pub use atrium_api::types::string::{Did, Handle};
 pub use atrium_identity::{
     did::{CommonDidResolver, CommonDidResolverConfig, DEFAULT_PLC_DIRECTORY_URL},
@@ -1,18 +1,39 @@
 /// OAuth client builder and utilities for AT Protocol
 use crate::{
-    resolver::HickoryDnsTxtResolver,
-    storage::{SqliteSessionStore, SqliteStateStore},
+    db::{self, IN_MEMORY_DATABASE},
+    encryption::{EncryptionKey, StorageEncryption},
+    error::DbError,
+    identity::IdentityVerifier,
+    keys::{self, KeyError},
+    overrides::{OverrideResolver, ResolverOverrides},
+    policy::{DidPolicy, PolicyResolver},
+    resolver::{
+        CachingResolver, DnsResolverError, HickoryDnsTxtResolver, HickoryDnsTxtResolverBuilder,
+        ResolverCacheConfig,
+    },
+    storage::{SqliteSessionStore, SqliteStateStore, DEFAULT_STATE_TTL},
+    tasks::{self, RefreshFailureHandler, DEFAULT_REFRESH_MARGIN},
+    token::{TokenClient, TokenError},
 };
+#[cfg(feature = "postgres-storage")]
+use crate::postgres::{self, PostgresSessionStore, PostgresStateStore};
+#[cfg(feature = "redis-storage")]
+use crate::redis_store::{RedisSessionStore, RedisStateStore};
 use async_sqlite::Pool;
+use atrium_api::types::string::Did;
 use atrium_identity::{
     did::{CommonDidResolver, CommonDidResolverConfig, DEFAULT_PLC_DIRECTORY_URL},
     handle::{AtprotoHandleResolver, AtprotoHandleResolverConfig},
 };
 use atrium_oauth::{
-    AtprotoLocalhostClientMetadata, DefaultHttpClient, KnownScope, OAuthClient, OAuthClientConfig,
-    OAuthResolverConfig, Scope,
+    AtprotoClientMetadata, AtprotoLocalhostClientMetadata, AuthMethod, DefaultHttpClient,
+    GrantType, KnownScope, OAuthClient, OAuthClientConfig, OAuthResolverConfig, OAuthSession,
+    Scope,
 };
-use std::sync::Arc;
+use atrium_oauth::store::{session::SessionStore, state::StateStore};
+use axum::http::Uri;
+use jose_jwk::Jwk;
+use std::{path::PathBuf, sync::Arc};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -21,23 +42,109 @@ pub enum OAuthClientError {
     ClientCreationError(#[from] atrium_oauth::Error),
     #[error("Invalid configuration: {0}")]
     InvalidConfiguration(String),
+    #[error("Invalid signing key: {0}")]
+    InvalidSigningKey(#[from] KeyError),
+    #[error("Failed to open database: {0}")]
+    DatabaseError(#[from] DbError),
+    #[error("Failed to create DNS resolver: {0}")]
+    DnsResolverError(#[from] DnsResolverError),
 }
 
-/// Type alias for a commonly used OAuth client configuration
-pub type AtprotoOAuthClient = OAuthClient<
-    SqliteStateStore,
-    SqliteSessionStore,
-    CommonDidResolver<DefaultHttpClient>,
-    AtprotoHandleResolver<HickoryDnsTxtResolver, DefaultHttpClient>,
+/// DID resolver of [AtprotoOAuthClient], caching DID documents
+pub type CachedDidResolver = CachingResolver<CommonDidResolver<DefaultHttpClient>>;
+
+/// Handle resolver of [AtprotoOAuthClient], caching resolved handles and TXT lookups
+pub type CachedHandleResolver =
+    CachingResolver<AtprotoHandleResolver<HickoryDnsTxtResolver, DefaultHttpClient>>;
+
+/// DID resolver of [AtprotoOAuthClient], enforcing the [DidPolicy] and answering from the
+/// [ResolverOverrides] first
+pub type ClientDidResolver = PolicyResolver<OverrideResolver<CachedDidResolver>>;
+
+/// Handle resolver of [AtprotoOAuthClient], answering from the [ResolverOverrides] first
+pub type ClientHandleResolver = OverrideResolver<CachedHandleResolver>;
+
+/// Type alias for a commonly used OAuth client configuration.
+/// Sessions and states are stored in SQLite unless other stores are given.
+pub type AtprotoOAuthClient<S0 = SqliteStateStore, S1 = SqliteSessionStore> =
+    OAuthClient<S0, S1, ClientDidResolver, ClientHandleResolver>;
+
+/// Type alias for an OAuth client whose sessions and states are stored in PostgreSQL
+#[cfg(feature = "postgres-storage")]
+pub type AtprotoPostgresOAuthClient = AtprotoOAuthClient<PostgresStateStore, PostgresSessionStore>;
+
+/// Type alias for an OAuth client whose sessions and states are stored in Redis
+#[cfg(feature = "redis-storage")]
+pub type AtprotoRedisOAuthClient = AtprotoOAuthClient<RedisStateStore, RedisSessionStore>;
+
+/// Signing key as supplied to the builder, decoded when the client is built
+enum SigningKeySource {
+    Jwk(Box<Jwk>),
+    JwkJson(String),
+    Pem { kid: String, pem: String },
+}
+
+impl SigningKeySource {
+    fn into_jwk(self) -> Result<Jwk, KeyError> {
+        match self {
+            SigningKeySource::Jwk(jwk) => {
+                keys::validate_signing_key(&jwk)?;
+                Ok(*jwk)
+            }
+            SigningKeySource::JwkJson(json) => keys::jwk_from_json(&json),
+            SigningKeySource::Pem { kid, pem } => keys::jwk_from_pem(kid, &pem),
+        }
+    }
+}
+
+/// Type alias for the session produced by [AtprotoOAuthClient], usable with [atrium_api::agent::Agent]
+pub type AtprotoOAuthSession<S1 = SqliteSessionStore> =
+    OAuthSession<DefaultHttpClient, ClientDidResolver, ClientHandleResolver, S1>;
+
+/// Starts background work once the client was built, e.g. the session refresher, which sends
+/// its requests through the client's [TokenClient]
+type ClientTask = Box<dyn FnOnce(TokenClient) + Send>;
+
+/// Creates the state and session stores, and the tasks to start once the client was built
+type StoreFactory<S0, S1> = Box<
+    dyn FnOnce(&OAuthClientBuilder<S0, S1>) -> Result<(S0, S1, Vec<ClientTask>), OAuthClientError>
+        + Send,
 >;
 
 /// Builder for creating AT Protocol OAuth clients with sensible defaults
-pub struct OAuthClientBuilder {
+///
+/// By default this builds a public loopback client, which is suitable for local development.
+/// Setting a [client_id](OAuthClientBuilder::client_id) switches to a confidential client that
+/// authenticates with `private_key_jwt` using the configured signing keys.
+///
+/// Sessions and states are stored in SQLite using the [db_pool](OAuthClientBuilder::db_pool).
+/// Any other [StateStore] and [SessionStore] implementations can be supplied with
+/// [stores](OAuthClientBuilder::stores), e.g. atrium's memory stores in tests.
+pub struct OAuthClientBuilder<S0 = SqliteStateStore, S1 = SqliteSessionStore> {
     host: String,
     port: u16,
     db_pool: Option<Pool>,
+    database_path: Option<PathBuf>,
     scopes: Vec<Scope>,
     plc_directory_url: String,
+    client_id: Option<String>,
+    client_uri: Option<String>,
+    redirect_uris: Option<Vec<String>>,
+    jwks_uri: Option<String>,
+    signing_keys: Vec<SigningKeySource>,
+    state_ttl: chrono::Duration,
+    state_sweep_interval: Option<std::time::Duration>,
+    session_refresh_interval: Option<std::time::Duration>,
+    session_refresh_margin: chrono::Duration,
+    session_refresh_failure: Option<RefreshFailureHandler>,
+    session_idle_timeout: Option<chrono::Duration>,
+    resolver_cache: ResolverCacheConfig,
+    dns_resolver: HickoryDnsTxtResolverBuilder,
+    resolver_overrides: ResolverOverrides,
+    did_policy: Arc<DidPolicy>,
+    encryption_key: Option<EncryptionKey>,
+    previous_encryption_keys: Vec<EncryptionKey>,
+    stores: Option<StoreFactory<S0, S1>>,
 }
 
 impl OAuthClientBuilder {
@@ -47,11 +154,167 @@ impl OAuthClientBuilder {
             host: "127.0.0.1".to_string(),
             port: 8080,
             db_pool: None,
+            database_path: None,
             scopes: vec![
                 Scope::Known(KnownScope::Atproto),
                 Scope::Known(KnownScope::TransitionGeneric),
             ],
             plc_directory_url: DEFAULT_PLC_DIRECTORY_URL.to_string(),
+            client_id: None,
+            client_uri: None,
+            redirect_uris: None,
+            jwks_uri: None,
+            signing_keys: Vec::new(),
+            state_ttl: DEFAULT_STATE_TTL,
+            state_sweep_interval: None,
+            session_refresh_interval: None,
+            session_refresh_margin: DEFAULT_REFRESH_MARGIN,
+            session_refresh_failure: None,
+            session_idle_timeout: None,
+            resolver_cache: ResolverCacheConfig::default(),
+            dns_resolver: HickoryDnsTxtResolverBuilder::new(),
+            resolver_overrides: ResolverOverrides::default(),
+            did_policy: Arc::new(DidPolicy::default()),
+            encryption_key: None,
+            previous_encryption_keys: Vec::new(),
+            stores: Some(Box::new(Self::sqlite_stores)),
+        }
+    }
+
+    /// Creates the SQLite stores from the database pool. The state sweeper and session refresher
+    /// are returned as tasks, so they only start once the client was built.
+    fn sqlite_stores(
+        &self,
+    ) -> Result<(SqliteStateStore, SqliteSessionStore, Vec<ClientTask>), OAuthClientError> {
+        let db_pool = self.required_db_pool()?;
+        let mut client_tasks: Vec<ClientTask> = Vec::new();
+        if let Some(interval) = self.state_sweep_interval {
+            let (pool, ttl) = (db_pool.clone(), self.state_ttl);
+            client_tasks.push(Box::new(move |_| {
+                tasks::spawn_state_sweeper(pool, ttl, interval);
+            }));
+        }
+        let mut state_store = SqliteStateStore::new(db_pool.clone()).with_ttl(self.state_ttl);
+        let encryption = self.storage_encryption();
+        if let Some(encryption) = &encryption {
+            state_store = state_store.with_encryption(encryption.clone());
+        }
+        let session_store = self.session_store()?;
+
+        if let Some(interval) = self.session_refresh_interval {
+            let margin = self.session_refresh_margin;
+            let on_failure = self.session_refresh_failure.clone();
+            client_tasks.push(Box::new(move |tokens| {
+                tasks::spawn_session_refresher(
+                    tokens, db_pool, encryption, margin, interval, on_failure,
+                );
+            }));
+        }
+        Ok((state_store, session_store, client_tasks))
+    }
+
+    fn required_db_pool(&self) -> Result<Pool, OAuthClientError> {
+        self.db_pool
+            .clone()
+            .ok_or_else(|| OAuthClientError::InvalidConfiguration("Database pool is required".to_string()))
+    }
+
+    fn storage_encryption(&self) -> Option<Arc<StorageEncryption>> {
+        self.encryption_key.clone().map(|key| {
+            let encryption = self
+                .previous_encryption_keys
+                .iter()
+                .cloned()
+                .fold(StorageEncryption::new(key), StorageEncryption::previous_key);
+            Arc::new(encryption)
+        })
+    }
+
+    /// Creates a [SqliteSessionStore] reading the client's sessions with its encryption and idle
+    /// timeout, e.g. for [SessionCookies](crate::session::SessionCookies) or to
+    /// [revoke](crate::revocation::revoke_session) sessions
+    pub fn session_store(&self) -> Result<SqliteSessionStore, OAuthClientError> {
+        let mut session_store = SqliteSessionStore::new(self.required_db_pool()?);
+        if let Some(timeout) = self.session_idle_timeout {
+            session_store = session_store.with_idle_timeout(timeout);
+        }
+        if let Some(encryption) = self.storage_encryption() {
+            session_store = session_store.with_encryption(encryption);
+        }
+        Ok(session_store)
+    }
+
+    /// Open the SQLite database at `path` on [connect](OAuthClientBuilder::connect) instead of
+    /// using a [db_pool](OAuthClientBuilder::db_pool). The file is created if needed.
+    pub fn database_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.database_path = Some(path.into());
+        self
+    }
+
+    /// Keep sessions and states in an in-memory database opened on
+    /// [connect](OAuthClientBuilder::connect), e.g. for tests and local development
+    pub fn in_memory_database(self) -> Self {
+        self.database_path(IN_MEMORY_DATABASE)
+    }
+
+    /// Build the OAuth client after opening the database and applying the OAuth migrations.
+    ///
+    /// The database is opened from the [database_path](OAuthClientBuilder::database_path), see
+    /// [open_database](crate::db::open_database) for its settings, or taken from the
+    /// [db_pool](OAuthClientBuilder::db_pool). Migrations that were already applied are skipped.
+    pub async fn connect(mut self) -> Result<Arc<AtprotoOAuthClient>, OAuthClientError> {
+        let pool = match (self.database_path.take(), self.db_pool.take()) {
+            (Some(path), _) => db::open_database(path).await?,
+            (None, Some(pool)) => pool,
+            (None, None) => {
+                return Err(OAuthClientError::InvalidConfiguration(
+                    "A database path or pool is required".to_string(),
+                ))
+            }
+        };
+        db::create_oauth_tables(&pool).await?;
+        self.db_pool(pool).build()
+    }
+}
+
+impl<S0, S1> OAuthClientBuilder<S0, S1> {
+    /// Use custom state and session stores instead of the SQLite stores.
+    /// The database pool, state TTL, sweeper, session refresher, idle timeout and encryption
+    /// settings only apply to the SQLite stores. [build](OAuthClientBuilder::build) fails if the
+    /// session refresher, idle timeout or encryption keys are set, as they would be ignored.
+    pub fn stores<T0, T1>(self, state_store: T0, session_store: T1) -> OAuthClientBuilder<T0, T1>
+    where
+        T0: Send + 'static,
+        T1: Send + 'static,
+    {
+        OAuthClientBuilder {
+            host: self.host,
+            port: self.port,
+            db_pool: self.db_pool,
+            database_path: self.database_path,
+            scopes: self.scopes,
+            plc_directory_url: self.plc_directory_url,
+            client_id: self.client_id,
+            client_uri: self.client_uri,
+            redirect_uris: self.redirect_uris,
+            jwks_uri: self.jwks_uri,
+            signing_keys: self.signing_keys,
+            state_ttl: self.state_ttl,
+            state_sweep_interval: self.state_sweep_interval,
+            session_refresh_interval: self.session_refresh_interval,
+            session_refresh_margin: self.session_refresh_margin,
+            session_refresh_failure: self.session_refresh_failure,
+            session_idle_timeout: self.session_idle_timeout,
+            resolver_cache: self.resolver_cache,
+            dns_resolver: self.dns_resolver,
+            resolver_overrides: self.resolver_overrides,
+            did_policy: self.did_policy,
+            encryption_key: self.encryption_key,
+            previous_encryption_keys: self.previous_encryption_keys,
+            stores: Some(Box::new(move |builder| {
+                builder.reject_sqlite_settings()?;
+                Ok((state_store, session_store, Vec::new()))
+            })),
         }
     }
 
@@ -67,7 +330,8 @@ impl OAuthClientBuilder {
         self
     }
 
-    /// Set the database pool for session/state storage (required)
+    /// Set the database pool for session/state storage (required unless a
+    /// [database_path](OAuthClientBuilder::database_path) is set)
     pub fn db_pool(mut self, pool: Pool) -> Self {
         self.db_pool = Some(pool);
         self
@@ -85,41 +349,347 @@ impl OAuthClientBuilder {
         self
     }
 
-    /// Build the OAuth client
-    pub fn build(self) -> Result<Arc<AtprotoOAuthClient>, OAuthClientError> {
-        let db_pool = self
-            .db_pool
-            .ok_or_else(|| OAuthClientError::InvalidConfiguration("Database pool is required".to_string()))?;
+    /// Set the public `client_id` URL, making this a confidential client.
+    /// The URL must serve the client metadata document for this client.
+    pub fn client_id(mut self, client_id: impl Into<String>) -> Self {
+        self.client_id = Some(client_id.into());
+        self
+    }
+
+    /// Set the `client_uri` advertised in confidential client metadata
+    pub fn client_uri(mut self, client_uri: impl Into<String>) -> Self {
+        self.client_uri = Some(client_uri.into());
+        self
+    }
+
+    /// Set explicit redirect URIs (default: derived from host/port, or from the
+    /// `client_id` origin for confidential clients)
+    pub fn redirect_uris(mut self, redirect_uris: Vec<String>) -> Self {
+        self.redirect_uris = Some(redirect_uris);
+        self
+    }
+
+    /// Set a `jwks_uri` to advertise instead of embedding the public keys in the client metadata
+    pub fn jwks_uri(mut self, jwks_uri: impl Into<String>) -> Self {
+        self.jwks_uri = Some(jwks_uri.into());
+        self
+    }
+
+    /// Add an ES256 signing key for `private_key_jwt` client authentication
+    pub fn signing_key(mut self, jwk: Jwk) -> Self {
+        self.signing_keys.push(SigningKeySource::Jwk(Box::new(jwk)));
+        self
+    }
+
+    /// Add an ES256 signing key from a JWK JSON document (must include `kid` and `d`)
+    pub fn signing_key_jwk(mut self, json: impl Into<String>) -> Self {
+        self.signing_keys.push(SigningKeySource::JwkJson(json.into()));
+        self
+    }
+
+    /// Add an ES256 signing key from a PKCS#8 or SEC1 PEM document
+    pub fn signing_key_pem(mut self, kid: impl Into<String>, pem: impl Into<String>) -> Self {
+        self.signing_keys.push(SigningKeySource::Pem {
+            kid: kid.into(),
+            pem: pem.into(),
+        });
+        self
+    }
+
+    /// Set how long an authorization state stays valid (default: 1 hour)
+    pub fn state_ttl(mut self, ttl: chrono::Duration) -> Self {
+        self.state_ttl = ttl;
+        self
+    }
+
+    /// Spawn a background task on [build](OAuthClientBuilder::build) that deletes expired
+    /// authorization states every `interval`. Requires a running Tokio runtime.
+    pub fn state_sweep_interval(mut self, interval: std::time::Duration) -> Self {
+        self.state_sweep_interval = Some(interval);
+        self
+    }
+
+    /// Spawn a background task on [build](OAuthClientBuilder::build) that refreshes stored
+    /// sessions every `interval` before their access token expires, so idle users keep a valid
+    /// refresh token. Requires a running Tokio runtime.
+    pub fn session_refresh_interval(mut self, interval: std::time::Duration) -> Self {
+        self.session_refresh_interval = Some(interval);
+        self
+    }
+
+    /// Set how long before its access token expires a session is refreshed (default: 1 minute)
+    pub fn session_refresh_margin(mut self, margin: chrono::Duration) -> Self {
+        self.session_refresh_margin = margin;
+        self
+    }
+
+    /// Call `handler` with the DID, device session id and error of every session the session
+    /// refresher fails to refresh, e.g. to ask those users to log in again
+    pub fn on_session_refresh_failure<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&Did, &str, &TokenError) + Send + Sync + 'static,
+    {
+        self.session_refresh_failure = Some(Arc::new(handler));
+        self
+    }
+
+    /// Configure the caches of DNS TXT lookups, resolved handles and DID documents
+    /// (default: [ResolverCacheConfig::default]). Read the hit and miss counters from
+    /// [ResolverCacheConfig::stats] of a clone of `config`.
+    pub fn resolver_cache(mut self, config: ResolverCacheConfig) -> Self {
+        self.resolver_cache = config;
+        self
+    }
 
+    /// Set the nameservers, timeouts and retries of the DNS resolver used to resolve handles
+    /// (default: the system configuration), e.g. for containers without `/etc/resolv.conf`
+    pub fn dns_resolver(mut self, builder: HickoryDnsTxtResolverBuilder) -> Self {
+        self.dns_resolver = builder;
+        self
+    }
+
+    /// Resolve the handles and DIDs of `overrides` without DNS or HTTPS, e.g. to log in to a
+    /// local PDS in tests. Other handles and DIDs are resolved as usual.
+    pub fn resolver_overrides(mut self, overrides: ResolverOverrides) -> Self {
+        self.resolver_overrides = overrides;
+        self
+    }
+
+    /// Restrict the DIDs that may log in (default: did:plc and did:web, nothing blocked).
+    /// The policy is checked when the flow starts and in the callback before the session is
+    /// stored.
+    pub fn did_policy(mut self, policy: DidPolicy) -> Self {
+        self.did_policy = Arc::new(policy);
+        self
+    }
+
+    /// Sign out device sessions that were not used for `timeout` (default: sessions never idle
+    /// out). Applies to the SQLite session store, see [SqliteSessionStore::with_idle_timeout].
+    pub fn session_idle_timeout(mut self, timeout: chrono::Duration) -> Self {
+        self.session_idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Encrypt sessions and states stored in SQLite with `key` (default: stored as plaintext JSON)
+    pub fn encryption_key(mut self, key: EncryptionKey) -> Self {
+        self.encryption_key = Some(key);
+        self
+    }
+
+    /// Keep decrypting values encrypted with a previous key after rotating the encryption key
+    pub fn previous_encryption_key(mut self, key: EncryptionKey) -> Self {
+        self.previous_encryption_keys.push(key);
+        self
+    }
+
+    /// Returns true if the builder is configured for a confidential client
+    pub fn is_confidential(&self) -> bool {
+        self.client_id.is_some()
+    }
+
+    /// Redirect URIs for a confidential client, defaulting to `/oauth/callback` on the `client_id` origin
+    fn confidential_redirect_uris(&self, client_id: &str) -> Result<Vec<String>, OAuthClientError> {
+        if let Some(redirect_uris) = &self.redirect_uris {
+            return Ok(redirect_uris.clone());
+        }
+        let uri = client_id
+            .parse::<Uri>()
+            .map_err(|e| OAuthClientError::InvalidConfiguration(format!("Invalid client_id: {e}")))?;
+        match (uri.scheme_str(), uri.authority()) {
+            (Some(scheme), Some(authority)) => {
+                Ok(vec![format!("{scheme}://{authority}/oauth/callback")])
+            }
+            _ => Err(OAuthClientError::InvalidConfiguration(
+                "client_id must be an absolute URL".to_string(),
+            )),
+        }
+    }
+
+    /// Returns the sweep interval, failing early if there is no runtime to spawn the sweeper on
+    fn sweeper_interval(&self) -> Result<Option<std::time::Duration>, OAuthClientError> {
+        if self.state_sweep_interval.is_some() && tokio::runtime::Handle::try_current().is_err() {
+            return Err(OAuthClientError::InvalidConfiguration(
+                "The state sweeper requires a running Tokio runtime".to_string(),
+            ));
+        }
+        Ok(self.state_sweep_interval)
+    }
+
+    /// Fails if session settings that only the SQLite stores implement are set for other stores
+    fn reject_sqlite_settings(&self) -> Result<(), OAuthClientError> {
+        let sqlite_settings = [
+            ("session_refresh_interval", self.session_refresh_interval.is_some()),
+            ("session_idle_timeout", self.session_idle_timeout.is_some()),
+            ("encryption_key", self.encryption_key.is_some()),
+            ("previous_encryption_key", !self.previous_encryption_keys.is_empty()),
+        ];
+        let set: Vec<_> = sqlite_settings
+            .into_iter()
+            .filter_map(|(name, set)| set.then_some(name))
+            .collect();
+        if set.is_empty() {
+            return Ok(());
+        }
+        Err(OAuthClientError::InvalidConfiguration(format!(
+            "Only the SQLite stores support {}",
+            set.join(", ")
+        )))
+    }
+
+    /// Fails early if there is no runtime to spawn the session refresher on
+    fn check_refresher_runtime(&self) -> Result<(), OAuthClientError> {
+        if self.session_refresh_interval.is_some() && tokio::runtime::Handle::try_current().is_err() {
+            return Err(OAuthClientError::InvalidConfiguration(
+                "The session refresher requires a running Tokio runtime".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Creates the DID and handle resolvers from the overrides, DNS and cache configuration
+    fn resolvers(&self) -> Result<(ClientDidResolver, ClientHandleResolver), OAuthClientError> {
+        if !self.did_policy.allows_plc_directory(&self.plc_directory_url) {
+            return Err(OAuthClientError::InvalidConfiguration(format!(
+                "PLC directory {} is not allowed by the DID policy",
+                self.plc_directory_url
+            )));
+        }
         let http_client = Arc::new(DefaultHttpClient::default());
+        let cache = &self.resolver_cache;
+        let did_resolver = CommonDidResolver::new(CommonDidResolverConfig {
+            plc_directory_url: self.plc_directory_url.clone(),
+            http_client: http_client.clone(),
+        });
+        let handle_resolver = AtprotoHandleResolver::new(AtprotoHandleResolverConfig {
+            dns_txt_resolver: self.dns_resolver.clone().build()?.with_cache(cache),
+            http_client,
+        });
+        let did_resolver = CachingResolver::new(did_resolver, cache, cache.stats().did.clone());
+        let handle_resolver =
+            CachingResolver::new(handle_resolver, cache, cache.stats().handle.clone());
+        let overrides = &self.resolver_overrides;
+        let did_resolver = OverrideResolver::did_documents(did_resolver, overrides);
+        Ok((
+            PolicyResolver::new(did_resolver, self.did_policy.clone()),
+            OverrideResolver::handles(handle_resolver, overrides),
+        ))
+    }
+
+    /// Creates an [IdentityVerifier] resolving handles and DIDs like the client, e.g. to verify
+    /// the handle of a user after login. Its caches are separate from the client's but count
+    /// into the same [stats](ResolverCacheConfig::stats).
+    pub fn identity_verifier(&self) -> Result<IdentityVerifier, OAuthClientError> {
+        let (did_resolver, handle_resolver) = self.resolvers()?;
+        Ok(IdentityVerifier::new(did_resolver, handle_resolver))
+    }
+
+    /// Build the OAuth client with sessions and states stored in PostgreSQL.
+    /// The tables must exist, see [create_postgres_oauth_tables](crate::postgres::create_postgres_oauth_tables).
+    #[cfg(feature = "postgres-storage")]
+    pub fn build_postgres(
+        self,
+        pool: deadpool_postgres::Pool,
+    ) -> Result<Arc<AtprotoPostgresOAuthClient>, OAuthClientError> {
+        let (state_ttl, sweep_interval) = (self.state_ttl, self.state_sweep_interval);
+        let state_store = PostgresStateStore::new(pool.clone()).with_ttl(state_ttl);
+        let session_store = PostgresSessionStore::new(pool.clone());
+        let client = self.stores(state_store, session_store).build()?;
+        if let Some(interval) = sweep_interval {
+            postgres::spawn_postgres_state_sweeper(pool, state_ttl, interval);
+        }
+        Ok(client)
+    }
 
-        let config = OAuthClientConfig {
-            client_metadata: AtprotoLocalhostClientMetadata {
-                redirect_uris: Some(vec![format!(
-                    "http://{}:{}/oauth/callback",
-                    self.host, self.port
-                )]),
-                scopes: Some(self.scopes),
-            },
-            keys: None,
-            resolver: OAuthResolverConfig {
-                did_resolver: CommonDidResolver::new(CommonDidResolverConfig {
-                    plc_directory_url: self.plc_directory_url,
-                    http_client: http_client.clone(),
-                }),
-                handle_resolver: AtprotoHandleResolver::new(AtprotoHandleResolverConfig {
-                    dns_txt_resolver: HickoryDnsTxtResolver::default(),
-                    http_client: http_client.clone(),
-                }),
-                authorization_server_metadata: Default::default(),
-                protected_resource_metadata: Default::default(),
-            },
-            state_store: SqliteStateStore::new(db_pool.clone()),
-            session_store: SqliteSessionStore::new(db_pool),
+    /// Build the OAuth client with sessions and states stored in Redis.
+    /// Entries expire on their own, so no state sweeper is spawned.
+    #[cfg(feature = "redis-storage")]
+    pub fn build_redis(
+        self,
+        connection: redis::aio::ConnectionManager,
+    ) -> Result<Arc<AtprotoRedisOAuthClient>, OAuthClientError> {
+        let state_store = RedisStateStore::new(connection.clone()).with_ttl(self.state_ttl);
+        let session_store = RedisSessionStore::new(connection);
+        self.stores(state_store, session_store).build()
+    }
+
+    /// Build the OAuth client
+    pub fn build(mut self) -> Result<Arc<AtprotoOAuthClient<S0, S1>>, OAuthClientError>
+    where
+        S0: StateStore + 'static,
+        S1: SessionStore + Send + Sync + 'static,
+        S1::Error: std::error::Error + Send + Sync + 'static,
+    {
+        self.sweeper_interval()?;
+        self.check_refresher_runtime()?;
+        let stores = self.stores.take().ok_or_else(|| {
+            OAuthClientError::InvalidConfiguration("State and session stores are required".to_string())
+        })?;
+        let (state_store, session_store, client_tasks) = stores(&self)?;
+
+        let (did_resolver, handle_resolver) = self.resolvers()?;
+        let resolver = OAuthResolverConfig {
+            did_resolver,
+            handle_resolver,
+            authorization_server_metadata: Default::default(),
+            protected_resource_metadata: Default::default(),
         };
 
-        let client = OAuthClient::new(config)?;
-        Ok(Arc::new(client))
+        let (client, signing_keys) = match &self.client_id {
+            Some(client_id) => {
+                let redirect_uris = self.confidential_redirect_uris(client_id)?;
+                let keys = self
+                    .signing_keys
+                    .into_iter()
+                    .map(SigningKeySource::into_jwk)
+                    .collect::<Result<Vec<_>, _>>()?;
+                if keys.is_empty() {
+                    return Err(OAuthClientError::InvalidConfiguration(
+                        "Confidential clients require at least one signing key".to_string(),
+                    ));
+                }
+                let client = OAuthClient::new(OAuthClientConfig {
+                    client_metadata: AtprotoClientMetadata {
+                        client_id: client_id.clone(),
+                        client_uri: self.client_uri,
+                        redirect_uris,
+                        token_endpoint_auth_method: AuthMethod::PrivateKeyJwt,
+                        grant_types: vec![GrantType::AuthorizationCode, GrantType::RefreshToken],
+                        scopes: self.scopes,
+                        jwks_uri: self.jwks_uri,
+                        token_endpoint_auth_signing_alg: Some("ES256".to_string()),
+                    },
+                    keys: Some(keys.clone()),
+                    resolver,
+                    state_store,
+                    session_store,
+                })?;
+                (client, keys)
+            }
+            None => {
+                let client = OAuthClient::new(OAuthClientConfig {
+                    client_metadata: AtprotoLocalhostClientMetadata {
+                        redirect_uris: Some(self.redirect_uris.unwrap_or_else(|| {
+                            vec![format!("http://{}:{}/oauth/callback", self.host, self.port)]
+                        })),
+                        scopes: Some(self.scopes),
+                    },
+                    keys: None,
+                    resolver,
+                    state_store,
+                    session_store,
+                })?;
+                (client, Vec::new())
+            }
+        };
+        let client = Arc::new(client);
+        let tokens = signing_keys
+            .into_iter()
+            .fold(TokenClient::new(&client.client_metadata), TokenClient::signing_key);
+        tokens.register();
+        for client_task in client_tasks {
+            client_task(tokens.clone());
+        }
+        Ok(client)
     }
 }
 
@@ -152,4 +722,199 @@ mod tests {
         assert_eq!(builder.port, 3000);
         assert_eq!(builder.plc_directory_url, "https://custom-plc.example.com");
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_build_confidential_client() {
+        let pool = async_sqlite::PoolBuilder::new().open().await.unwrap();
+        let client = OAuthClientBuilder::new()
+            .db_pool(pool)
+            .client_id("https://app.example.com/oauth/client-metadata.json")
+            .signing_key_pem("kid00", crate::keys::tests::TEST_PRIVATE_KEY)
+            .build()
+            .unwrap();
+
+        let metadata = &client.client_metadata;
+        assert_eq!(metadata.client_id, "https://app.example.com/oauth/client-metadata.json");
+        assert_eq!(metadata.redirect_uris, vec!["https://app.example.com/oauth/callback"]);
+        assert_eq!(metadata.token_endpoint_auth_method.as_deref(), Some("private_key_jwt"));
+        assert_eq!(metadata.token_endpoint_auth_signing_alg.as_deref(), Some("ES256"));
+        assert_eq!(client.jwks().keys.len(), 1);
+    }
+
+    #[test]
+    fn test_build_with_custom_stores() {
+        use atrium_oauth::store::{session::MemorySessionStore, state::MemoryStateStore};
+
+        let client = OAuthClientBuilder::new()
+            .stores(MemoryStateStore::default(), MemorySessionStore::default())
+            .build()
+            .unwrap();
+        assert_eq!(
+            client.client_metadata.redirect_uris,
+            vec!["http://127.0.0.1:8080/oauth/callback"]
+        );
+
+        let result = OAuthClientBuilder::new().build();
+        assert!(matches!(result, Err(OAuthClientError::InvalidConfiguration(_))));
+    }
+
+    #[tokio::test]
+    async fn test_custom_stores_reject_sqlite_settings() {
+        use atrium_oauth::store::{session::MemorySessionStore, state::MemoryStateStore};
+
+        let stores = || (MemoryStateStore::default(), MemorySessionStore::default());
+        let key = || EncryptionKey::generate("k1").unwrap();
+        let builders = [
+            OAuthClientBuilder::new().session_refresh_interval(std::time::Duration::from_secs(60)),
+            OAuthClientBuilder::new().session_idle_timeout(chrono::Duration::days(30)),
+            OAuthClientBuilder::new().encryption_key(key()),
+            OAuthClientBuilder::new().previous_encryption_key(key()),
+        ];
+        for builder in builders {
+            let (state_store, session_store) = stores();
+            let result = builder.stores(state_store, session_store).build();
+            assert!(matches!(result, Err(OAuthClientError::InvalidConfiguration(_))));
+        }
+
+        // Settings made after choosing the stores are rejected as well
+        let (state_store, session_store) = stores();
+        let result = OAuthClientBuilder::new()
+            .stores(state_store, session_store)
+            .encryption_key(key())
+            .build();
+        let Err(OAuthClientError::InvalidConfiguration(message)) = result else {
+            panic!("encryption key accepted for custom stores");
+        };
+        assert_eq!(message, "Only the SQLite stores support encryption_key");
+    }
+
+    #[tokio::test]
+    async fn test_connect_applies_migrations() {
+        let client = OAuthClientBuilder::new()
+            .in_memory_database()
+            .connect()
+            .await
+            .unwrap();
+        assert_eq!(
+            client.client_metadata.redirect_uris,
+            vec!["http://127.0.0.1:8080/oauth/callback"]
+        );
+
+        let pool = async_sqlite::PoolBuilder::new()
+            .num_conns(1)
+            .open()
+            .await
+            .unwrap();
+        OAuthClientBuilder::new()
+            .db_pool(pool.clone())
+            .connect()
+            .await
+            .unwrap();
+        assert!(crate::db::AuthSession::list_all(&pool).await.unwrap().is_empty());
+
+        let result = OAuthClientBuilder::new().connect().await;
+        assert!(matches!(result, Err(OAuthClientError::InvalidConfiguration(_))));
+    }
+
+    #[tokio::test]
+    async fn test_build_starts_session_refresher() {
+        let pool = crate::test_util::sqlite_test_pool().await;
+        let server = crate::token::tests::MockAuthorizationServer::spawn().await;
+        db::AuthSession::new("did:plc:abc123".to_string(), server.session(-5))
+            .unwrap()
+            .save_or_update(&pool)
+            .await
+            .unwrap();
+        let _client = OAuthClientBuilder::new()
+            .db_pool(pool.clone())
+            .session_refresh_interval(std::time::Duration::from_secs(3600))
+            .build()
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+
+        assert_eq!(server.token_requests.lock().unwrap().len(), 1);
+        let stored = db::AuthSession::get_by_did(&pool, "did:plc:abc123".to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        let session: serde_json::Value = serde_json::from_str(&stored.session).unwrap();
+        assert_eq!(session["token_set"]["access_token"], "access-1");
+        assert_eq!(session["token_set"]["refresh_token"], "refresh-1");
+    }
+
+    #[tokio::test]
+    async fn test_failed_build_starts_no_state_sweeper() {
+        let pool = crate::test_util::sqlite_test_pool().await;
+        let mut stale = db::AuthState::new("stale".to_string(), "{}").unwrap();
+        stale.created_at = chrono::Utc::now() - chrono::Duration::hours(2);
+        stale.save_or_update(&pool).await.unwrap();
+
+        let result = OAuthClientBuilder::new()
+            .db_pool(pool.clone())
+            .client_id("https://app.example.com/oauth/client-metadata.json")
+            .state_sweep_interval(std::time::Duration::from_millis(10))
+            .build();
+        assert!(matches!(result, Err(OAuthClientError::InvalidConfiguration(_))));
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(db::AuthState::get_by_key(&pool, "stale".to_string())
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_session_store_shares_encryption() {
+        use crate::device::DeviceSessionStore;
+        use atrium_common::store::Store;
+
+        let pool = crate::test_util::sqlite_test_pool().await;
+        let builder = OAuthClientBuilder::new()
+            .db_pool(pool.clone())
+            .encryption_key(EncryptionKey::generate("k1").unwrap());
+        let server = crate::token::tests::MockAuthorizationServer::spawn().await;
+        let session = server.session(60);
+        let did = session.token_set.sub.clone();
+        crate::device::with_session_id(
+            "laptop",
+            builder.session_store().unwrap().set(did.clone(), session),
+        )
+        .await
+        .unwrap();
+
+        let row = db::AuthSession::get(&pool, did.to_string(), "laptop".to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(row.session.starts_with("enc1.k1."));
+        let stored = builder
+            .session_store()
+            .unwrap()
+            .device_session(&did, "laptop")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.token_set.refresh_token.as_deref(), Some("refresh-0"));
+    }
+
+    #[tokio::test]
+    async fn test_build_confidential_client_requires_keys() {
+        let pool = async_sqlite::PoolBuilder::new().open().await.unwrap();
+        let result = OAuthClientBuilder::new()
+            .db_pool(pool)
+            .client_id("https://app.example.com/oauth/client-metadata.json")
+            .build();
+        assert!(matches!(result, Err(OAuthClientError::InvalidConfiguration(_))));
+    }
+
+    #[tokio::test]
+    async fn test_build_requires_allowed_plc_directory() {
+        let pool = async_sqlite::PoolBuilder::new().open().await.unwrap();
+        let policy = DidPolicy::new().plc_directories([DEFAULT_PLC_DIRECTORY_URL]);
+        let result = OAuthClientBuilder::new()
+            .db_pool(pool)
+            .plc_directory_url("https://plc.example.com")
+            .did_policy(policy)
+            .build();
+        assert!(matches!(result, Err(OAuthClientError::InvalidConfiguration(_))));
+    }
+}
@@ -0,0 +1,296 @@
+/// Fixed handle and DID resolution for tests and local development
+///
+/// Running the OAuth flow against a local PDS needs handles like `alice.test` to resolve
+/// without real DNS or HTTPS. [ResolverOverrides] maps handles to DIDs and DIDs to documents,
+/// from code, a JSON file or a closure. [OverrideResolver] answers from the overrides and
+/// falls back to the wrapped resolver for everything else.
+use atrium_api::did_doc::{DidDocument, Service};
+use atrium_api::types::string::{Did, Handle};
+use atrium_common::resolver::Resolver;
+use atrium_identity::{did::DidResolver, handle::HandleResolver};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum OverrideError {
+    #[error("Failed to read overrides file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid overrides file: {0}")]
+    Parse(#[from] serde_json::Error),
+    #[error("DID document has an invalid id: {0}")]
+    InvalidDocumentId(String),
+}
+
+/// Looks up an override, returning `None` to fall back to the wrapped resolver
+pub type OverrideFn<K, V> = Arc<dyn Fn(&K) -> Option<V> + Send + Sync>;
+
+/// Handle to DID and DID to document overrides
+///
+/// Fixed entries take precedence over the closures.
+#[derive(Clone, Default)]
+pub struct ResolverOverrides {
+    handles: HashMap<Handle, Did>,
+    did_documents: HashMap<Did, DidDocument>,
+    handle_fn: Option<OverrideFn<Handle, Did>>,
+    did_document_fn: Option<OverrideFn<Did, DidDocument>>,
+}
+
+/// Format of an overrides file
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OverridesFile {
+    #[serde(default)]
+    handles: HashMap<Handle, Did>,
+    #[serde(default)]
+    did_documents: Vec<DidDocument>,
+}
+
+impl ResolverOverrides {
+    /// Creates empty overrides
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads overrides from a JSON file of the form
+    /// `{"handles": {"alice.test": "did:plc:alice"}, "didDocuments": [...]}`
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, OverrideError> {
+        let file: OverridesFile = serde_json::from_slice(&std::fs::read(path)?)?;
+        file.did_documents
+            .into_iter()
+            .try_fold(Self::new().handles(file.handles), Self::did_document)
+    }
+
+    /// Resolve `handle` to `did`
+    pub fn handle(mut self, handle: Handle, did: Did) -> Self {
+        self.handles.insert(handle, did);
+        self
+    }
+
+    /// Resolve every handle of `handles` to its DID
+    pub fn handles(mut self, handles: impl IntoIterator<Item = (Handle, Did)>) -> Self {
+        self.handles.extend(handles);
+        self
+    }
+
+    /// Resolve the DID in the `id` of `document` to `document`
+    pub fn did_document(mut self, document: DidDocument) -> Result<Self, OverrideError> {
+        let did = Did::new(document.id.clone())
+            .map_err(|_| OverrideError::InvalidDocumentId(document.id.clone()))?;
+        self.did_documents.insert(did, document);
+        Ok(self)
+    }
+
+    /// Resolve `handle` to `did`, and `did` to a document naming `handle` and hosted on the PDS
+    /// at `pds_endpoint`, e.g. `http://localhost:2583`
+    pub fn account(self, handle: Handle, did: Did, pds_endpoint: impl Into<String>) -> Self {
+        let document = DidDocument {
+            context: Some(vec!["https://www.w3.org/ns/did/v1".to_string()]),
+            id: did.to_string(),
+            also_known_as: Some(vec![format!("at://{}", handle.as_str())]),
+            verification_method: None,
+            service: Some(vec![Service {
+                id: "#atproto_pds".to_string(),
+                r#type: "AtprotoPersonalDataServer".to_string(),
+                service_endpoint: pds_endpoint.into(),
+            }]),
+        };
+        let mut overrides = self.handle(handle, did.clone());
+        overrides.did_documents.insert(did, document);
+        overrides
+    }
+
+    /// Resolve handles with `resolve`, falling back to the wrapped resolver when it returns `None`
+    pub fn resolve_handles_with<F>(mut self, resolve: F) -> Self
+    where
+        F: Fn(&Handle) -> Option<Did> + Send + Sync + 'static,
+    {
+        self.handle_fn = Some(Arc::new(resolve));
+        self
+    }
+
+    /// Resolve DID documents with `resolve`, falling back to the wrapped resolver when it
+    /// returns `None`
+    pub fn resolve_did_documents_with<F>(mut self, resolve: F) -> Self
+    where
+        F: Fn(&Did) -> Option<DidDocument> + Send + Sync + 'static,
+    {
+        self.did_document_fn = Some(Arc::new(resolve));
+        self
+    }
+
+    fn handle_lookup(&self) -> Option<OverrideFn<Handle, Did>> {
+        lookup(self.handles.clone(), self.handle_fn.clone())
+    }
+
+    fn did_document_lookup(&self) -> Option<OverrideFn<Did, DidDocument>> {
+        lookup(self.did_documents.clone(), self.did_document_fn.clone())
+    }
+}
+
+/// Combines fixed entries and a closure, or returns `None` if there are no overrides
+fn lookup<K, V>(
+    entries: HashMap<K, V>,
+    resolve: Option<OverrideFn<K, V>>,
+) -> Option<OverrideFn<K, V>>
+where
+    K: Eq + std::hash::Hash + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    if entries.is_empty() {
+        return resolve;
+    }
+    Some(Arc::new(move |key: &K| {
+        entries
+            .get(key)
+            .cloned()
+            .or_else(|| resolve.as_ref().and_then(|resolve| resolve(key)))
+    }))
+}
+
+/// Answers from [ResolverOverrides] before asking the wrapped resolver
+pub struct OverrideResolver<R>
+where
+    R: Resolver,
+    R::Input: Sized,
+{
+    inner: R,
+    lookup: Option<OverrideFn<R::Input, R::Output>>,
+}
+
+impl<R> OverrideResolver<R>
+where
+    R: HandleResolver,
+{
+    /// Wraps a handle resolver with the handle overrides
+    pub fn handles(inner: R, overrides: &ResolverOverrides) -> Self {
+        Self {
+            inner,
+            lookup: overrides.handle_lookup(),
+        }
+    }
+}
+
+impl<R> OverrideResolver<R>
+where
+    R: DidResolver,
+{
+    /// Wraps a DID resolver with the DID document overrides
+    pub fn did_documents(inner: R, overrides: &ResolverOverrides) -> Self {
+        Self {
+            inner,
+            lookup: overrides.did_document_lookup(),
+        }
+    }
+}
+
+impl<R> Resolver for OverrideResolver<R>
+where
+    R: Resolver + Send + Sync,
+    R::Input: Sized + Send + Sync,
+    R::Output: Send,
+{
+    type Input = R::Input;
+    type Output = R::Output;
+    type Error = R::Error;
+
+    async fn resolve(&self, input: &Self::Input) -> Result<Self::Output, Self::Error> {
+        if let Some(output) = self.lookup.as_ref().and_then(|lookup| lookup(input)) {
+            return Ok(output);
+        }
+        self.inner.resolve(input).await
+    }
+}
+
+impl<R> DidResolver for OverrideResolver<R> where R: DidResolver + Send + Sync {}
+
+impl<R> HandleResolver for OverrideResolver<R> where R: HandleResolver + Send + Sync {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fails every lookup, standing in for resolvers without network access
+    struct Offline;
+
+    impl Resolver for Offline {
+        type Input = Handle;
+        type Output = Did;
+        type Error = atrium_identity::Error;
+
+        async fn resolve(&self, _: &Handle) -> Result<Did, Self::Error> {
+            Err(atrium_identity::Error::NotFound)
+        }
+    }
+
+    impl HandleResolver for Offline {}
+
+    fn handle(handle: &str) -> Handle {
+        Handle::new(handle.to_string()).unwrap()
+    }
+
+    fn did(did: &str) -> Did {
+        Did::new(did.to_string()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_overrides_take_precedence_over_the_wrapped_resolver() {
+        let overrides = ResolverOverrides::new()
+            .handle(handle("alice.test"), did("did:plc:alice"))
+            .resolve_handles_with(|handle| {
+                let name = handle.as_str().strip_suffix(".local")?;
+                Did::new(format!("did:plc:{name}")).ok()
+            });
+        let resolver = OverrideResolver::handles(Offline, &overrides);
+
+        let alice = resolver.resolve(&handle("alice.test")).await.unwrap();
+        assert_eq!(alice.as_str(), "did:plc:alice");
+        let bob = resolver.resolve(&handle("bob.local")).await.unwrap();
+        assert_eq!(bob.as_str(), "did:plc:bob");
+        assert!(resolver.resolve(&handle("carol.example.com")).await.is_err());
+    }
+
+    #[test]
+    fn test_overrides_from_file() {
+        let path = std::env::temp_dir().join(format!("overrides-{}.json", rand::random::<u64>()));
+        std::fs::write(
+            &path,
+            r##"{
+                "handles": {"alice.test": "did:plc:alice"},
+                "didDocuments": [{
+                    "id": "did:plc:alice",
+                    "alsoKnownAs": ["at://alice.test"],
+                    "service": [{
+                        "id": "#atproto_pds",
+                        "type": "AtprotoPersonalDataServer",
+                        "serviceEndpoint": "http://localhost:2583"
+                    }]
+                }]
+            }"##,
+        )
+        .unwrap();
+        let overrides = ResolverOverrides::from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let resolve_handle = overrides.handle_lookup().unwrap();
+        assert_eq!(resolve_handle(&handle("alice.test")), Some(did("did:plc:alice")));
+        let resolve_document = overrides.did_document_lookup().unwrap();
+        let document = resolve_document(&did("did:plc:alice")).unwrap();
+        assert_eq!(document.also_known_as, Some(vec!["at://alice.test".to_string()]));
+    }
+
+    #[test]
+    fn test_account_describes_local_pds() {
+        let overrides = ResolverOverrides::new().account(
+            handle("alice.test"),
+            did("did:plc:alice"),
+            "http://localhost:2583",
+        );
+        let document = overrides.did_document_lookup().unwrap()(&did("did:plc:alice")).unwrap();
+        let service = &document.service.unwrap()[0];
+        assert_eq!(service.service_endpoint, "http://localhost:2583");
+        assert!(ResolverOverrides::new().handle_lookup().is_none());
+    }
+}
@@ -0,0 +1,259 @@
+/// Restrictions on the DIDs that may log in
+///
+/// [DidPolicy] decides which DID methods are accepted, which PLC directories the client may
+/// use, and which DIDs and PDS hosts are blocked. [PolicyResolver] enforces it on every DID
+/// resolution, which the OAuth client runs both when starting the flow and in the callback,
+/// when it verifies the issuer of the token response before storing the session.
+use atrium_api::did_doc::DidDocument;
+use atrium_api::types::string::Did;
+use atrium_common::resolver::Resolver;
+use atrium_identity::did::DidResolver;
+use axum::http::Uri;
+use std::collections::HashSet;
+use std::sync::Arc;
+use thiserror::Error;
+
+/// DID methods understood by the DID resolver
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DidMethod {
+    Plc,
+    Web,
+}
+
+impl DidMethod {
+    /// The method of `did`, or `None` for methods the resolver does not support
+    pub fn of(did: &Did) -> Option<Self> {
+        match did.as_str().split(':').nth(1) {
+            Some("plc") => Some(DidMethod::Plc),
+            Some("web") => Some(DidMethod::Web),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum PolicyViolation {
+    #[error("DID method of {0} is not allowed")]
+    MethodNotAllowed(String),
+    #[error("{0} is blocked")]
+    BlockedDid(String),
+    #[error("PDS host {host} of {did} is blocked")]
+    BlockedPdsHost { did: String, host: String },
+}
+
+/// Which DIDs may log in (default: did:plc and did:web, nothing blocked)
+#[derive(Debug, Clone)]
+pub struct DidPolicy {
+    methods: HashSet<DidMethod>,
+    plc_directories: Vec<String>,
+    blocked_dids: HashSet<String>,
+    blocked_pds_hosts: HashSet<String>,
+}
+
+impl DidPolicy {
+    /// Creates a policy accepting did:plc and did:web
+    pub fn new() -> Self {
+        Self {
+            methods: HashSet::from([DidMethod::Plc, DidMethod::Web]),
+            plc_directories: Vec::new(),
+            blocked_dids: HashSet::new(),
+            blocked_pds_hosts: HashSet::new(),
+        }
+    }
+
+    /// Only accept DIDs of `methods`
+    pub fn methods(mut self, methods: impl IntoIterator<Item = DidMethod>) -> Self {
+        self.methods = methods.into_iter().collect();
+        self
+    }
+
+    /// Only allow the client to use these PLC directories (default: any).
+    /// Building the client fails if its `plc_directory_url` is not one of them.
+    pub fn plc_directories(mut self, urls: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.plc_directories = urls.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Reject `did`
+    pub fn block_did(mut self, did: &Did) -> Self {
+        self.blocked_dids.insert(did.to_string());
+        self
+    }
+
+    /// Reject DIDs hosted on the PDS at `host`, e.g. `pds.example.com`
+    pub fn block_pds_host(mut self, host: impl Into<String>) -> Self {
+        self.blocked_pds_hosts.insert(host.into().to_lowercase());
+        self
+    }
+
+    /// Returns true if the client may use the PLC directory at `url`
+    pub fn allows_plc_directory(&self, url: &str) -> bool {
+        let url = url.trim_end_matches('/');
+        self.plc_directories.is_empty()
+            || self
+                .plc_directories
+                .iter()
+                .any(|allowed| allowed.trim_end_matches('/') == url)
+    }
+
+    /// Checks the method and blocklist, before resolving `did`
+    pub fn check_did(&self, did: &Did) -> Result<(), PolicyViolation> {
+        if !DidMethod::of(did).is_some_and(|method| self.methods.contains(&method)) {
+            return Err(PolicyViolation::MethodNotAllowed(did.to_string()));
+        }
+        if self.blocked_dids.contains(did.as_str()) {
+            return Err(PolicyViolation::BlockedDid(did.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Checks the PDS host of the resolved document of `did`
+    pub fn check_document(
+        &self,
+        did: &Did,
+        document: &DidDocument,
+    ) -> Result<(), PolicyViolation> {
+        let host = document
+            .get_pds_endpoint()
+            .and_then(|endpoint| endpoint.parse::<Uri>().ok())
+            .and_then(|uri| uri.host().map(str::to_lowercase));
+        match host {
+            Some(host) if self.blocked_pds_hosts.contains(&host) => {
+                Err(PolicyViolation::BlockedPdsHost {
+                    did: did.to_string(),
+                    host,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Default for DidPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Enforces a [DidPolicy] around a DID resolver
+pub struct PolicyResolver<R> {
+    inner: R,
+    policy: Arc<DidPolicy>,
+}
+
+impl<R> PolicyResolver<R>
+where
+    R: DidResolver,
+{
+    /// Wraps `inner`, rejecting DIDs and documents that violate `policy`
+    pub fn new(inner: R, policy: Arc<DidPolicy>) -> Self {
+        Self { inner, policy }
+    }
+}
+
+impl<R> Resolver for PolicyResolver<R>
+where
+    R: DidResolver + Send + Sync,
+{
+    type Input = Did;
+    type Output = DidDocument;
+    type Error = atrium_identity::Error;
+
+    async fn resolve(&self, did: &Did) -> Result<DidDocument, Self::Error> {
+        self.policy.check_did(did).map_err(|violation| match violation {
+            PolicyViolation::MethodNotAllowed(_) => {
+                atrium_identity::Error::UnsupportedDidMethod(did.clone())
+            }
+            violation => atrium_identity::Error::DidDocument(violation.to_string()),
+        })?;
+        let document = self.inner.resolve(did).await?;
+        self.policy
+            .check_document(did, &document)
+            .map_err(|violation| atrium_identity::Error::DidDocument(violation.to_string()))?;
+        Ok(document)
+    }
+}
+
+impl<R> DidResolver for PolicyResolver<R> where R: DidResolver + Send + Sync {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::overrides::{OverrideResolver, ResolverOverrides};
+    use atrium_api::types::string::Handle;
+
+    struct Offline;
+
+    impl Resolver for Offline {
+        type Input = Did;
+        type Output = DidDocument;
+        type Error = atrium_identity::Error;
+
+        async fn resolve(&self, _: &Did) -> Result<DidDocument, Self::Error> {
+            Err(atrium_identity::Error::NotFound)
+        }
+    }
+
+    impl DidResolver for Offline {}
+
+    fn did(did: &str) -> Did {
+        Did::new(did.to_string()).unwrap()
+    }
+
+    #[test]
+    fn test_policy_checks_methods_and_blocklist() {
+        let policy = DidPolicy::new();
+        assert_eq!(policy.check_did(&did("did:plc:alice")), Ok(()));
+        assert_eq!(policy.check_did(&did("did:web:alice.example.com")), Ok(()));
+        assert!(policy.check_did(&did("did:key:z6Mkalice")).is_err());
+
+        let policy = DidPolicy::new()
+            .methods([DidMethod::Plc])
+            .block_did(&did("did:plc:mallory"));
+        assert_eq!(
+            policy.check_did(&did("did:web:alice.example.com")),
+            Err(PolicyViolation::MethodNotAllowed("did:web:alice.example.com".to_string()))
+        );
+        assert_eq!(
+            policy.check_did(&did("did:plc:mallory")),
+            Err(PolicyViolation::BlockedDid("did:plc:mallory".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_plc_directory_allowlist() {
+        assert!(DidPolicy::new().allows_plc_directory("https://plc.example.com"));
+        let policy = DidPolicy::new().plc_directories(["https://plc.directory/"]);
+        assert!(policy.allows_plc_directory("https://plc.directory"));
+        assert!(!policy.allows_plc_directory("https://plc.example.com"));
+    }
+
+    #[tokio::test]
+    async fn test_policy_resolver_blocks_pds_hosts() {
+        let overrides = ResolverOverrides::new()
+            .account(
+                Handle::new("alice.test".to_string()).unwrap(),
+                did("did:plc:alice"),
+                "https://pds.example.com",
+            )
+            .account(
+                Handle::new("mallory.test".to_string()).unwrap(),
+                did("did:plc:mallory"),
+                "https://PDS.Evil.example",
+            );
+        let policy = DidPolicy::new().block_pds_host("pds.evil.example");
+        let resolver = PolicyResolver::new(
+            OverrideResolver::did_documents(Offline, &overrides),
+            Arc::new(policy),
+        );
+        assert!(resolver.resolve(&did("did:plc:alice")).await.is_ok());
+        assert!(matches!(
+            resolver.resolve(&did("did:plc:mallory")).await,
+            Err(atrium_identity::Error::DidDocument(_))
+        ));
+        assert!(matches!(
+            resolver.resolve(&did("did:key:z6Mkalice")).await,
+            Err(atrium_identity::Error::UnsupportedDidMethod(_))
+        ));
+    }
+}
@@ -0,0 +1,370 @@
+/// PostgreSQL session and state stores for deployments that share storage across instances
+///
+/// These mirror [SqliteSessionStore](crate::storage::SqliteSessionStore) and
+/// [SqliteStateStore](crate::storage::SqliteStateStore): the same `auth_session` and
+/// `auth_state` tables, the same JSON encoding and the same state expiry rules.
+use crate::{
+    db::DEFAULT_SESSION_ID,
+    device::DeviceSessionStore,
+    storage::DEFAULT_STATE_TTL,
+};
+use async_trait::async_trait;
+use atrium_api::types::string::Did;
+use atrium_common::store::Store;
+use atrium_oauth::store::{
+    session::{Session, SessionStore},
+    state::StateStore,
+};
+use chrono::Utc;
+use deadpool_postgres::{Pool, PoolError};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{fmt::Debug, hash::Hash};
+use thiserror::Error;
+use tokio::{task::JoinHandle, time::MissedTickBehavior};
+
+pub use deadpool_postgres;
+
+#[derive(Error, Debug)]
+pub enum PostgresStoreError {
+    #[error("Invalid session: {0}")]
+    InvalidSession(#[source] serde_json::Error),
+    #[error("Invalid state: {0}")]
+    InvalidState(#[source] serde_json::Error),
+    #[error("State expired")]
+    StateExpired,
+    #[error("Connection pool error: {0}")]
+    PoolError(#[from] PoolError),
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] deadpool_postgres::tokio_postgres::Error),
+}
+
+/// Creates the OAuth tables in PostgreSQL if they do not exist yet
+pub async fn create_postgres_oauth_tables(pool: &Pool) -> Result<(), PostgresStoreError> {
+    let client = pool.get().await?;
+    client
+        .batch_execute(
+            "CREATE TABLE IF NOT EXISTS auth_session (
+                key TEXT PRIMARY KEY,
+                session TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS auth_state (
+                key TEXT PRIMARY KEY,
+                state TEXT NOT NULL,
+                created_at BIGINT NOT NULL DEFAULT 0
+            );",
+        )
+        .await?;
+    Ok(())
+}
+
+/// Spawns a task that deletes authorization states older than `ttl` every `interval`
+pub fn spawn_postgres_state_sweeper(
+    pool: Pool,
+    ttl: chrono::Duration,
+    interval: std::time::Duration,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            let cutoff = (Utc::now() - ttl).timestamp();
+            let result = match pool.get().await {
+                Ok(client) => client
+                    .execute("DELETE FROM auth_state WHERE created_at < $1", &[&cutoff])
+                    .await
+                    .map_err(PostgresStoreError::from),
+                Err(e) => Err(e.into()),
+            };
+            match result {
+                Ok(0) => {}
+                Ok(removed) => log::debug!("Removed {removed} expired OAuth states"),
+                Err(e) => log::error!("Failed to remove expired OAuth states: {e}"),
+            }
+        }
+    })
+}
+
+///Persistent session store in postgres
+impl SessionStore for PostgresSessionStore {}
+
+pub struct PostgresSessionStore {
+    pool: Pool,
+}
+
+impl PostgresSessionStore {
+    pub fn new(pool: Pool) -> Self {
+        Self { pool }
+    }
+}
+
+impl<K, V> Store<K, V> for PostgresSessionStore
+where
+    K: Debug + Eq + Hash + Send + Sync + 'static + From<Did> + AsRef<str>,
+    V: Debug + Clone + Send + Sync + 'static + Serialize + DeserializeOwned,
+{
+    type Error = PostgresStoreError;
+    async fn get(&self, key: &K) -> Result<Option<V>, Self::Error> {
+        let client = self.pool.get().await?;
+        let row = client
+            .query_opt(
+                "SELECT session FROM auth_session WHERE key = $1",
+                &[&key.as_ref()],
+            )
+            .await?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        let session: String = row.get(0);
+        let deserialized_session: V =
+            serde_json::from_str(&session).map_err(PostgresStoreError::InvalidSession)?;
+        Ok(Some(deserialized_session))
+    }
+
+    async fn set(&self, key: K, value: V) -> Result<(), Self::Error> {
+        let session =
+            serde_json::to_string(&value).map_err(PostgresStoreError::InvalidSession)?;
+        let client = self.pool.get().await?;
+        client
+            .execute(
+                "INSERT INTO auth_session (key, session) VALUES ($1, $2)
+                ON CONFLICT (key) DO UPDATE SET session = EXCLUDED.session",
+                &[&key.as_ref(), &session],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn del(&self, key: &K) -> Result<(), Self::Error> {
+        let client = self.pool.get().await?;
+        client
+            .execute("DELETE FROM auth_session WHERE key = $1", &[&key.as_ref()])
+            .await?;
+        Ok(())
+    }
+
+    async fn clear(&self) -> Result<(), Self::Error> {
+        let client = self.pool.get().await?;
+        client.execute("DELETE FROM auth_session", &[]).await?;
+        Ok(())
+    }
+}
+
+/// Sessions are keyed by DID only, so every device id refers to the DID's one session
+#[async_trait]
+impl DeviceSessionStore for PostgresSessionStore {
+    type Error = PostgresStoreError;
+
+    async fn device_session(
+        &self,
+        did: &Did,
+        _session_id: &str,
+    ) -> Result<Option<Session>, Self::Error> {
+        self.get(did).await
+    }
+
+    async fn device_session_ids(&self, did: &Did) -> Result<Vec<String>, Self::Error> {
+        let session: Option<Session> = self.get(did).await?;
+        Ok(session
+            .map(|_| DEFAULT_SESSION_ID.to_string())
+            .into_iter()
+            .collect())
+    }
+
+    async fn delete_device_session(&self, did: &Did, _session_id: &str) -> Result<(), Self::Error> {
+        Store::<Did, Session>::del(self, did).await
+    }
+}
+
+///Persistent session state in postgres
+impl StateStore for PostgresStateStore {}
+
+pub struct PostgresStateStore {
+    pool: Pool,
+    ttl: chrono::Duration,
+}
+
+impl PostgresStateStore {
+    pub fn new(pool: Pool) -> Self {
+        Self {
+            pool,
+            ttl: DEFAULT_STATE_TTL,
+        }
+    }
+
+    /// Set how long a state stays valid after the authorization request (default: 1 hour)
+    pub fn with_ttl(mut self, ttl: chrono::Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+}
+
+impl<K, V> Store<K, V> for PostgresStateStore
+where
+    K: Debug + Eq + Hash + Send + Sync + 'static + From<Did> + AsRef<str>,
+    V: Debug + Clone + Send + Sync + 'static + Serialize + DeserializeOwned,
+{
+    type Error = PostgresStoreError;
+    async fn get(&self, key: &K) -> Result<Option<V>, Self::Error> {
+        let client = self.pool.get().await?;
+        let row = client
+            .query_opt(
+                "SELECT state, created_at FROM auth_state WHERE key = $1",
+                &[&key.as_ref()],
+            )
+            .await?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        let state: String = row.get(0);
+        let created_at: i64 = row.get(1);
+        if Utc::now().timestamp() - created_at > self.ttl.num_seconds() {
+            client
+                .execute("DELETE FROM auth_state WHERE key = $1", &[&key.as_ref()])
+                .await?;
+            return Err(PostgresStoreError::StateExpired);
+        }
+        let deserialized_state: V =
+            serde_json::from_str(&state).map_err(PostgresStoreError::InvalidState)?;
+        Ok(Some(deserialized_state))
+    }
+
+    async fn set(&self, key: K, value: V) -> Result<(), Self::Error> {
+        let state =
+            serde_json::to_string(&value).map_err(PostgresStoreError::InvalidState)?;
+        let client = self.pool.get().await?;
+        client
+            .execute(
+                "INSERT INTO auth_state (key, state, created_at) VALUES ($1, $2, $3)
+                ON CONFLICT (key) DO UPDATE SET state = EXCLUDED.state, created_at = EXCLUDED.created_at",
+                &[&key.as_ref(), &state, &Utc::now().timestamp()],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn del(&self, key: &K) -> Result<(), Self::Error> {
+        let client = self.pool.get().await?;
+        client
+            .execute("DELETE FROM auth_state WHERE key = $1", &[&key.as_ref()])
+            .await?;
+        Ok(())
+    }
+
+    async fn clear(&self) -> Result<(), Self::Error> {
+        let client = self.pool.get().await?;
+        client.execute("DELETE FROM auth_state", &[]).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::StoreConformance;
+    use deadpool_postgres::{tokio_postgres::NoTls, Config, Runtime};
+
+    /// Connection URL of a scratch database, e.g. `postgres://postgres@localhost/oauth_test`.
+    /// The tests are ignored by default; run them with
+    /// `cargo test --features postgres-storage -- --ignored`.
+    const TEST_URL_VAR: &str = "POSTGRES_TEST_URL";
+
+    async fn test_pool() -> Pool {
+        let url = std::env::var(TEST_URL_VAR)
+            .unwrap_or_else(|_| panic!("{TEST_URL_VAR} must point to a scratch database"));
+        let config = Config {
+            url: Some(url),
+            ..Default::default()
+        };
+        let pool = config.create_pool(Some(Runtime::Tokio1), NoTls).unwrap();
+        create_postgres_oauth_tables(&pool).await.unwrap();
+        pool
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database in POSTGRES_TEST_URL"]
+    async fn test_postgres_stores() {
+        let pool = test_pool().await;
+
+        let sessions = PostgresSessionStore::new(pool.clone());
+        let did = "did:plc:pgtest".to_string();
+        Store::<String, String>::set(&sessions, did.clone(), "first".to_string())
+            .await
+            .unwrap();
+        Store::<String, String>::set(&sessions, did.clone(), "second".to_string())
+            .await
+            .unwrap();
+        let value: Option<String> = sessions.get(&did).await.unwrap();
+        assert_eq!(value.as_deref(), Some("second"));
+        let mistyped: Result<Option<u32>, _> = sessions.get(&did).await;
+        assert!(matches!(mistyped, Err(PostgresStoreError::InvalidSession(_))));
+        Store::<String, String>::del(&sessions, &did).await.unwrap();
+        let missing: Result<Option<String>, _> = sessions.get(&did).await;
+        assert!(matches!(missing, Ok(None)));
+
+        let states = PostgresStateStore::new(pool.clone()).with_ttl(chrono::Duration::seconds(-1));
+        let key = "pgtest-state".to_string();
+        Store::<String, String>::set(&states, key.clone(), "state".to_string())
+            .await
+            .unwrap();
+        let expired: Result<Option<String>, _> = states.get(&key).await;
+        assert!(matches!(expired, Err(PostgresStoreError::StateExpired)));
+
+        let states = PostgresStateStore::new(pool);
+        Store::<String, String>::set(&states, key.clone(), "state".to_string())
+            .await
+            .unwrap();
+        let value: Option<String> = states.get(&key).await.unwrap();
+        assert_eq!(value.as_deref(), Some("state"));
+        let mistyped: Result<Option<u32>, _> = states.get(&key).await;
+        assert!(matches!(mistyped, Err(PostgresStoreError::InvalidState(_))));
+        Store::<String, String>::del(&states, &key).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database in POSTGRES_TEST_URL"]
+    async fn test_failed_build_starts_no_state_sweeper() {
+        let pool = test_pool().await;
+        let key = "pgtest-stale-state";
+        pool.get()
+            .await
+            .unwrap()
+            .execute(
+                "INSERT INTO auth_state (key, state, created_at) VALUES ($1, '{}', 0)
+                ON CONFLICT (key) DO UPDATE SET created_at = 0",
+                &[&key],
+            )
+            .await
+            .unwrap();
+
+        let result = crate::OAuthClientBuilder::new()
+            .client_id("https://app.example.com/oauth/client-metadata.json")
+            .state_sweep_interval(std::time::Duration::from_millis(10))
+            .build_postgres(pool.clone());
+        assert!(result.is_err());
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        let client = pool.get().await.unwrap();
+        let row = client
+            .query_opt("SELECT key FROM auth_state WHERE key = $1", &[&key])
+            .await
+            .unwrap();
+        assert!(row.is_some());
+        client
+            .execute("DELETE FROM auth_state WHERE key = $1", &[&key])
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a PostgreSQL database in POSTGRES_TEST_URL"]
+    async fn test_postgres_stores_conform() {
+        let pool = test_pool().await;
+        StoreConformance::new()
+            .run(&PostgresSessionStore::new(pool.clone()))
+            .await;
+        StoreConformance::new()
+            .key_prefix("")
+            .run(&PostgresStateStore::new(pool))
+            .await;
+    }
+}
@@ -0,0 +1,455 @@
+/// Redis session and state stores that let the server expire entries
+///
+/// States are written with `SETEX` using the state TTL. A session expires with its access token
+/// when it cannot be refreshed, and otherwise after the session TTL, which restarts on every
+/// token refresh because atrium writes the refreshed session back to the store.
+use crate::{
+    db::DEFAULT_SESSION_ID,
+    device::DeviceSessionStore,
+    storage::DEFAULT_STATE_TTL,
+};
+use async_trait::async_trait;
+use atrium_api::types::string::Did;
+use atrium_common::store::Store;
+use atrium_oauth::store::{
+    session::{Session, SessionStore},
+    state::StateStore,
+};
+use chrono::{DateTime, Utc};
+use redis::{aio::ConnectionManager, AsyncCommands};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{fmt::Debug, hash::Hash};
+use thiserror::Error;
+
+pub use redis;
+
+/// Default key prefix for sessions
+pub const DEFAULT_SESSION_PREFIX: &str = "atproto_oauth:session:";
+/// Default key prefix for authorization states
+pub const DEFAULT_STATE_PREFIX: &str = "atproto_oauth:state:";
+/// Default lifetime of a refreshable session, matching the refresh token lifetime of public clients
+pub const DEFAULT_SESSION_TTL: chrono::Duration = chrono::Duration::days(14);
+
+#[derive(Error, Debug)]
+pub enum RedisStoreError {
+    #[error("Invalid session: {0}")]
+    InvalidSession(#[source] serde_json::Error),
+    #[error("Invalid state: {0}")]
+    InvalidState(#[source] serde_json::Error),
+    #[error("Redis error: {0}")]
+    DatabaseError(#[from] redis::RedisError),
+}
+
+/// Seconds until a session should expire: at the access token expiry if the session has no
+/// refresh token, otherwise after `ttl`
+fn session_expiry(session: &serde_json::Value, ttl: chrono::Duration) -> u64 {
+    let token_set = &session["token_set"];
+    let refreshable = token_set["refresh_token"].is_string();
+    let expires_at = token_set["expires_at"]
+        .as_str()
+        .and_then(|expires_at| DateTime::parse_from_rfc3339(expires_at).ok());
+    let seconds = match expires_at {
+        Some(expires_at) if !refreshable => {
+            (expires_at.with_timezone(&Utc) - Utc::now()).num_seconds()
+        }
+        _ => ttl.num_seconds(),
+    };
+    seconds.max(1) as u64
+}
+
+/// Deletes every key starting with `prefix`
+async fn delete_prefixed(
+    connection: &ConnectionManager,
+    prefix: &str,
+) -> Result<(), RedisStoreError> {
+    let mut connection = connection.clone();
+    let mut cursor = 0u64;
+    loop {
+        let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+            .arg(cursor)
+            .arg("MATCH")
+            .arg(format!("{prefix}*"))
+            .arg("COUNT")
+            .arg(100)
+            .query_async(&mut connection)
+            .await?;
+        if !keys.is_empty() {
+            let _: () = connection.del(keys).await?;
+        }
+        if next == 0 {
+            return Ok(());
+        }
+        cursor = next;
+    }
+}
+
+///Persistent session store in redis
+impl SessionStore for RedisSessionStore {}
+
+pub struct RedisSessionStore {
+    connection: ConnectionManager,
+    prefix: String,
+    ttl: chrono::Duration,
+}
+
+impl RedisSessionStore {
+    pub fn new(connection: ConnectionManager) -> Self {
+        Self {
+            connection,
+            prefix: DEFAULT_SESSION_PREFIX.to_string(),
+            ttl: DEFAULT_SESSION_TTL,
+        }
+    }
+
+    /// Set the key prefix (default: "atproto_oauth:session:")
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// Set how long a refreshable session is kept after it was last written (default: 14 days)
+    pub fn with_ttl(mut self, ttl: chrono::Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    fn key(&self, did: &str) -> String {
+        format!("{}{}", self.prefix, did)
+    }
+}
+
+impl<K, V> Store<K, V> for RedisSessionStore
+where
+    K: Debug + Eq + Hash + Send + Sync + 'static + From<Did> + AsRef<str>,
+    V: Debug + Clone + Send + Sync + 'static + Serialize + DeserializeOwned,
+{
+    type Error = RedisStoreError;
+    async fn get(&self, key: &K) -> Result<Option<V>, Self::Error> {
+        let session: Option<String> = self.connection.clone().get(self.key(key.as_ref())).await?;
+        let Some(session) = session else {
+            return Ok(None);
+        };
+        let deserialized_session: V =
+            serde_json::from_str(&session).map_err(RedisStoreError::InvalidSession)?;
+        Ok(Some(deserialized_session))
+    }
+
+    async fn set(&self, key: K, value: V) -> Result<(), Self::Error> {
+        let session = serde_json::to_value(&value).map_err(RedisStoreError::InvalidSession)?;
+        let expiry = session_expiry(&session, self.ttl);
+        let _: () = self
+            .connection
+            .clone()
+            .set_ex(self.key(key.as_ref()), session.to_string(), expiry)
+            .await?;
+        Ok(())
+    }
+
+    async fn del(&self, key: &K) -> Result<(), Self::Error> {
+        let _: () = self.connection.clone().del(self.key(key.as_ref())).await?;
+        Ok(())
+    }
+
+    async fn clear(&self) -> Result<(), Self::Error> {
+        delete_prefixed(&self.connection, &self.prefix).await
+    }
+}
+
+/// Sessions are keyed by DID only, so every device id refers to the DID's one session
+#[async_trait]
+impl DeviceSessionStore for RedisSessionStore {
+    type Error = RedisStoreError;
+
+    async fn device_session(
+        &self,
+        did: &Did,
+        _session_id: &str,
+    ) -> Result<Option<Session>, Self::Error> {
+        self.get(did).await
+    }
+
+    async fn device_session_ids(&self, did: &Did) -> Result<Vec<String>, Self::Error> {
+        let session: Option<Session> = self.get(did).await?;
+        Ok(session
+            .map(|_| DEFAULT_SESSION_ID.to_string())
+            .into_iter()
+            .collect())
+    }
+
+    async fn delete_device_session(&self, did: &Did, _session_id: &str) -> Result<(), Self::Error> {
+        Store::<Did, Session>::del(self, did).await
+    }
+}
+
+///Persistent session state in redis
+impl StateStore for RedisStateStore {}
+
+pub struct RedisStateStore {
+    connection: ConnectionManager,
+    prefix: String,
+    ttl: chrono::Duration,
+}
+
+impl RedisStateStore {
+    pub fn new(connection: ConnectionManager) -> Self {
+        Self {
+            connection,
+            prefix: DEFAULT_STATE_PREFIX.to_string(),
+            ttl: DEFAULT_STATE_TTL,
+        }
+    }
+
+    /// Set the key prefix (default: "atproto_oauth:state:")
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// Set how long a state stays valid after the authorization request (default: 1 hour)
+    pub fn with_ttl(mut self, ttl: chrono::Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    fn key(&self, state: &str) -> String {
+        format!("{}{}", self.prefix, state)
+    }
+}
+
+impl<K, V> Store<K, V> for RedisStateStore
+where
+    K: Debug + Eq + Hash + Send + Sync + 'static + From<Did> + AsRef<str>,
+    V: Debug + Clone + Send + Sync + 'static + Serialize + DeserializeOwned,
+{
+    type Error = RedisStoreError;
+    async fn get(&self, key: &K) -> Result<Option<V>, Self::Error> {
+        let state: Option<String> = self.connection.clone().get(self.key(key.as_ref())).await?;
+        let Some(state) = state else {
+            return Ok(None);
+        };
+        let deserialized_state: V =
+            serde_json::from_str(&state).map_err(RedisStoreError::InvalidState)?;
+        Ok(Some(deserialized_state))
+    }
+
+    async fn set(&self, key: K, value: V) -> Result<(), Self::Error> {
+        let state = serde_json::to_string(&value).map_err(RedisStoreError::InvalidState)?;
+        let expiry = self.ttl.num_seconds().max(1) as u64;
+        let _: () = self
+            .connection
+            .clone()
+            .set_ex(self.key(key.as_ref()), state, expiry)
+            .await?;
+        Ok(())
+    }
+
+    async fn del(&self, key: &K) -> Result<(), Self::Error> {
+        let _: () = self.connection.clone().del(self.key(key.as_ref())).await?;
+        Ok(())
+    }
+
+    async fn clear(&self) -> Result<(), Self::Error> {
+        delete_prefixed(&self.connection, &self.prefix).await
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::test_util::StoreConformance;
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::{TcpListener, TcpStream},
+    };
+
+    /// Connection URL of a scratch redis-server, e.g. `redis://127.0.0.1/15`.
+    /// Without it the tests run against an in-process stand-in.
+    const TEST_URL_VAR: &str = "REDIS_TEST_URL";
+
+    type Entries = Arc<Mutex<HashMap<String, (String, Option<Instant>)>>>;
+
+    /// Minimal in-process Redis speaking RESP2, supporting the commands used by the stores
+    async fn spawn_stand_in() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("redis://{}", listener.local_addr().unwrap());
+        let entries = Entries::default();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream, entries.clone()));
+            }
+        });
+        url
+    }
+
+    async fn serve(stream: TcpStream, entries: Entries) {
+        let mut stream = BufReader::new(stream);
+        while let Some(command) = read_command(&mut stream).await {
+            let reply = execute(&command, &entries);
+            if stream.get_mut().write_all(reply.as_bytes()).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    async fn read_command(stream: &mut BufReader<TcpStream>) -> Option<Vec<String>> {
+        let mut line = String::new();
+        stream.read_line(&mut line).await.ok()?;
+        let count: usize = line.trim().strip_prefix('*')?.parse().ok()?;
+        let mut args = Vec::with_capacity(count);
+        for _ in 0..count {
+            line.clear();
+            stream.read_line(&mut line).await.ok()?;
+            let len: usize = line.trim().strip_prefix('$')?.parse().ok()?;
+            let mut buf = vec![0; len + 2];
+            stream.read_exact(&mut buf).await.ok()?;
+            buf.truncate(len);
+            args.push(String::from_utf8(buf).ok()?);
+        }
+        Some(args)
+    }
+
+    fn bulk(value: &str) -> String {
+        format!("${}\r\n{}\r\n", value.len(), value)
+    }
+
+    fn execute(command: &[String], entries: &Entries) -> String {
+        let mut entries = entries.lock().unwrap();
+        let now = Instant::now();
+        entries.retain(|_, (_, expires)| expires.is_none_or(|expires| expires > now));
+        match command[0].to_ascii_uppercase().as_str() {
+            "PING" => "+PONG\r\n".to_string(),
+            "GET" => match entries.get(&command[1]) {
+                Some((value, _)) => bulk(value),
+                None => "$-1\r\n".to_string(),
+            },
+            "SET" => {
+                entries.insert(command[1].clone(), (command[2].clone(), None));
+                "+OK\r\n".to_string()
+            }
+            "SETEX" => {
+                let seconds: u64 = command[2].parse().unwrap();
+                let expires = now + Duration::from_secs(seconds);
+                entries.insert(command[1].clone(), (command[3].clone(), Some(expires)));
+                "+OK\r\n".to_string()
+            }
+            "DEL" => {
+                let removed = command[1..]
+                    .iter()
+                    .filter(|key| entries.remove(*key).is_some())
+                    .count();
+                format!(":{removed}\r\n")
+            }
+            "TTL" => match entries.get(&command[1]) {
+                Some((_, Some(expires))) => {
+                    format!(":{}\r\n", (*expires - now).as_secs_f64().round())
+                }
+                Some((_, None)) => ":-1\r\n".to_string(),
+                None => ":-2\r\n".to_string(),
+            },
+            "SCAN" => {
+                let prefix = command
+                    .iter()
+                    .position(|arg| arg.eq_ignore_ascii_case("MATCH"))
+                    .map(|i| command[i + 1].trim_end_matches('*'))
+                    .unwrap_or("");
+                let keys: Vec<String> = entries
+                    .keys()
+                    .filter(|key| key.starts_with(prefix))
+                    .map(|key| bulk(key))
+                    .collect();
+                format!("*2\r\n{}*{}\r\n{}", bulk("0"), keys.len(), keys.concat())
+            }
+            other => format!("-ERR unknown command '{other}'\r\n"),
+        }
+    }
+
+    pub(crate) async fn test_connection() -> ConnectionManager {
+        let url = match std::env::var(TEST_URL_VAR) {
+            Ok(url) => url,
+            Err(_) => spawn_stand_in().await,
+        };
+        let client = redis::Client::open(url).unwrap();
+        ConnectionManager::new(client).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_redis_stores() {
+        let connection = test_connection().await;
+        let prefix = format!("test:{}:", rand::random::<u32>());
+
+        let sessions = RedisSessionStore::new(connection.clone())
+            .with_prefix(format!("{prefix}session:"))
+            .with_ttl(chrono::Duration::days(1));
+        let did = "did:plc:redistest".to_string();
+        Store::<String, String>::set(&sessions, did.clone(), "first".to_string())
+            .await
+            .unwrap();
+        Store::<String, String>::set(&sessions, did.clone(), "second".to_string())
+            .await
+            .unwrap();
+        let value: Option<String> = sessions.get(&did).await.unwrap();
+        assert_eq!(value.as_deref(), Some("second"));
+        let mistyped: Result<Option<u32>, _> = sessions.get(&did).await;
+        assert!(matches!(mistyped, Err(RedisStoreError::InvalidSession(_))));
+        let ttl: i64 = connection
+            .clone()
+            .ttl(format!("{prefix}session:{did}"))
+            .await
+            .unwrap();
+        assert!(ttl > 86_000 && ttl <= 86_400);
+        Store::<String, String>::del(&sessions, &did).await.unwrap();
+        let missing: Result<Option<String>, _> = sessions.get(&did).await;
+        assert!(matches!(missing, Ok(None)));
+
+        let states =
+            RedisStateStore::new(connection.clone()).with_prefix(format!("{prefix}state:"));
+        for key in ["a", "b"] {
+            Store::<String, String>::set(&states, key.to_string(), "state".to_string())
+                .await
+                .unwrap();
+        }
+        let ttl: i64 = connection
+            .clone()
+            .ttl(format!("{prefix}state:a"))
+            .await
+            .unwrap();
+        assert!(ttl > 3500 && ttl <= 3600);
+        let mistyped: Result<Option<u32>, _> = states.get(&"a".to_string()).await;
+        assert!(matches!(mistyped, Err(RedisStoreError::InvalidState(_))));
+        Store::<String, String>::clear(&states).await.unwrap();
+        let cleared: Result<Option<String>, _> = states.get(&"a".to_string()).await;
+        assert!(matches!(cleared, Ok(None)));
+    }
+
+    #[tokio::test]
+    async fn test_redis_stores_conform() {
+        let connection = test_connection().await;
+        let prefix = format!("test:{}:", rand::random::<u32>());
+        let sessions =
+            RedisSessionStore::new(connection.clone()).with_prefix(format!("{prefix}session:"));
+        StoreConformance::new().run(&sessions).await;
+        let states = RedisStateStore::new(connection).with_prefix(format!("{prefix}state:"));
+        StoreConformance::new().key_prefix("").run(&states).await;
+    }
+
+    #[test]
+    fn test_session_expiry() {
+        let ttl = chrono::Duration::days(14);
+        let expires_at = (Utc::now() + chrono::Duration::minutes(30)).to_rfc3339();
+        let access_only = serde_json::json!({"token_set": {"expires_at": expires_at}});
+        assert!((1790..=1800).contains(&session_expiry(&access_only, ttl)));
+
+        let refreshable = serde_json::json!({
+            "token_set": {"expires_at": expires_at, "refresh_token": "refresh"}
+        });
+        assert_eq!(session_expiry(&refreshable, ttl), 14 * 86_400);
+
+        let expired = serde_json::json!({"token_set": {"expires_at": "2000-01-01T00:00:00Z"}});
+        assert_eq!(session_expiry(&expired, ttl), 1);
+    }
+}
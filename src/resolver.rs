@@ -1,17 +1,294 @@
-use atrium_identity::handle::DnsTxtResolver;
-use hickory_resolver::TokioAsyncResolver;
+/// DNS and identity resolvers used by the OAuth client
+///
+/// [HickoryDnsTxtResolver::builder] configures the nameservers used for handle TXT lookups,
+/// either from the system configuration or explicit plain, DNS-over-HTTPS or DNS-over-TLS
+/// upstreams.
+///
+/// Handle and DID resolution runs on every login. [ResolverCacheConfig] configures caches for
+/// TXT lookups, which honor the TTL of the DNS records, and for resolved handles and DID
+/// documents via [CachingResolver].
+use crate::cache::{CacheStats, TtlCache};
+use atrium_common::resolver::Resolver;
+use atrium_identity::{did::DidResolver, handle::DnsTxtResolver, handle::HandleResolver};
+use hickory_resolver::{
+    config::{NameServerConfig, NameServerConfigGroup, Protocol, ResolverConfig, ResolverOpts},
+    error::{ResolveErrorKind, ResolveResult},
+    system_conf, TokioAsyncResolver,
+};
+use std::hash::Hash;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// Default number of entries kept by each resolver cache
+pub const DEFAULT_CACHE_ENTRIES: usize = 1024;
+/// Default time resolved handles and DID documents are cached, and the upper bound for TXT
+/// record TTLs
+pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(10 * 60);
+/// Default time a handle or DID that does not exist is cached
+pub const DEFAULT_NEGATIVE_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// Hit and miss counters of the resolver caches
+#[derive(Debug, Clone, Default)]
+pub struct ResolverCacheStats {
+    pub txt: Arc<CacheStats>,
+    pub handle: Arc<CacheStats>,
+    pub did: Arc<CacheStats>,
+}
+
+/// Configuration of the resolver caches
+#[derive(Debug, Clone)]
+pub struct ResolverCacheConfig {
+    max_entries: usize,
+    ttl: Duration,
+    negative_ttl: Duration,
+    stats: ResolverCacheStats,
+}
+
+impl ResolverCacheConfig {
+    /// Creates a configuration with the default limits
+    pub fn new() -> Self {
+        Self {
+            max_entries: DEFAULT_CACHE_ENTRIES,
+            ttl: DEFAULT_CACHE_TTL,
+            negative_ttl: DEFAULT_NEGATIVE_CACHE_TTL,
+            stats: ResolverCacheStats::default(),
+        }
+    }
+
+    /// A configuration that caches nothing
+    pub fn disabled() -> Self {
+        Self::new().max_entries(0)
+    }
+
+    /// Set the number of entries kept by each cache (default: 1024, 0 disables caching)
+    pub fn max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = max_entries;
+        self
+    }
+
+    /// Set how long resolved handles and DID documents are cached, which also caps TXT record
+    /// TTLs (default: 10 minutes)
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Set how long handles and DIDs that do not exist are cached (default: 1 minute)
+    pub fn negative_ttl(mut self, negative_ttl: Duration) -> Self {
+        self.negative_ttl = negative_ttl;
+        self
+    }
+
+    /// Counters of the caches created from this configuration. Keep a clone before passing
+    /// the configuration on to read them later.
+    pub fn stats(&self) -> &ResolverCacheStats {
+        &self.stats
+    }
+}
+
+impl Default for ResolverCacheConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum DnsResolverError {
+    #[error("Failed to read the system DNS configuration: {0}")]
+    SystemConfig(String),
+    #[error("Invalid DNS resolver configuration: {0}")]
+    InvalidConfiguration(String),
+}
 
 /// Setup for dns resolver for the handle resolver
 pub struct HickoryDnsTxtResolver {
     resolver: hickory_resolver::TokioAsyncResolver,
+    cache: Option<TxtCache>,
+}
+
+/// Upstream nameserver of a [HickoryDnsTxtResolverBuilder]
+#[derive(Debug, Clone)]
+enum Upstream {
+    /// Plain DNS over UDP, falling back to TCP for truncated responses
+    Plain(SocketAddr),
+    #[cfg(feature = "dns-over-https")]
+    Https { addr: SocketAddr, tls_name: String },
+    #[cfg(feature = "dns-over-tls")]
+    Tls { addr: SocketAddr, tls_name: String },
+}
+
+/// Builder for [HickoryDnsTxtResolver]
+///
+/// Without upstreams the nameservers of the system configuration (`/etc/resolv.conf` on Unix)
+/// are used; building fails if it cannot be read, e.g. in minimal containers.
+#[derive(Debug, Clone, Default)]
+pub struct HickoryDnsTxtResolverBuilder {
+    upstreams: Vec<Upstream>,
+    timeout: Option<Duration>,
+    attempts: Option<usize>,
+}
+
+impl HickoryDnsTxtResolverBuilder {
+    /// Creates a builder using the system configuration
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a plain DNS nameserver, e.g. `8.8.8.8:53`
+    pub fn nameserver(mut self, addr: SocketAddr) -> Self {
+        self.upstreams.push(Upstream::Plain(addr));
+        self
+    }
+
+    /// Add plain DNS nameservers
+    pub fn nameservers(mut self, addrs: impl IntoIterator<Item = SocketAddr>) -> Self {
+        self.upstreams.extend(addrs.into_iter().map(Upstream::Plain));
+        self
+    }
+
+    /// Add a DNS-over-HTTPS upstream, e.g. `1.1.1.1:443` with the TLS name `cloudflare-dns.com`
+    #[cfg(feature = "dns-over-https")]
+    pub fn https_nameserver(mut self, addr: SocketAddr, tls_name: impl Into<String>) -> Self {
+        self.upstreams.push(Upstream::Https {
+            addr,
+            tls_name: tls_name.into(),
+        });
+        self
+    }
+
+    /// Add a DNS-over-TLS upstream, e.g. `1.1.1.1:853` with the TLS name `cloudflare-dns.com`
+    #[cfg(feature = "dns-over-tls")]
+    pub fn tls_nameserver(mut self, addr: SocketAddr, tls_name: impl Into<String>) -> Self {
+        self.upstreams.push(Upstream::Tls {
+            addr,
+            tls_name: tls_name.into(),
+        });
+        self
+    }
+
+    /// Set how long to wait for a response to each query (default: system configuration,
+    /// otherwise 5 seconds)
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Set how many times a failed query is sent (default: system configuration, otherwise 2)
+    pub fn attempts(mut self, attempts: usize) -> Self {
+        self.attempts = Some(attempts);
+        self
+    }
+
+    /// Builds the resolver. Requires a Tokio runtime when resolving.
+    pub fn build(self) -> Result<HickoryDnsTxtResolver, DnsResolverError> {
+        self.build_with_system_conf(system_conf::read_system_conf)
+    }
+
+    /// Builds the resolver, reading the system configuration with `read_system_conf` when no
+    /// upstreams are configured
+    fn build_with_system_conf(
+        self,
+        read_system_conf: impl FnOnce() -> ResolveResult<(ResolverConfig, ResolverOpts)>,
+    ) -> Result<HickoryDnsTxtResolver, DnsResolverError> {
+        if self.attempts == Some(0) {
+            return Err(DnsResolverError::InvalidConfiguration(
+                "At least one attempt is required".to_string(),
+            ));
+        }
+        if self.timeout == Some(Duration::ZERO) {
+            return Err(DnsResolverError::InvalidConfiguration(
+                "Timeout must not be zero".to_string(),
+            ));
+        }
+        let (config, mut opts) = if self.upstreams.is_empty() {
+            read_system_conf().map_err(|e| DnsResolverError::SystemConfig(e.to_string()))?
+        } else {
+            let nameservers: Vec<NameServerConfig> = self
+                .upstreams
+                .iter()
+                .flat_map(Upstream::nameserver_configs)
+                .collect();
+            let nameservers = NameServerConfigGroup::from(nameservers);
+            let config = ResolverConfig::from_parts(None, Vec::new(), nameservers);
+            (config, Default::default())
+        };
+        if let Some(timeout) = self.timeout {
+            opts.timeout = timeout;
+        }
+        if let Some(attempts) = self.attempts {
+            opts.attempts = attempts;
+        }
+        Ok(HickoryDnsTxtResolver {
+            resolver: TokioAsyncResolver::tokio(config, opts),
+            cache: None,
+        })
+    }
+}
+
+impl Upstream {
+    fn nameserver_configs(&self) -> Vec<NameServerConfig> {
+        match self {
+            Upstream::Plain(addr) => vec![
+                NameServerConfig::new(*addr, Protocol::Udp),
+                NameServerConfig::new(*addr, Protocol::Tcp),
+            ],
+            #[cfg(feature = "dns-over-https")]
+            Upstream::Https { addr, tls_name } => {
+                let mut config = NameServerConfig::new(*addr, Protocol::Https);
+                config.tls_dns_name = Some(tls_name.clone());
+                vec![config]
+            }
+            #[cfg(feature = "dns-over-tls")]
+            Upstream::Tls { addr, tls_name } => {
+                let mut config = NameServerConfig::new(*addr, Protocol::Tls);
+                config.tls_dns_name = Some(tls_name.clone());
+                vec![config]
+            }
+        }
+    }
+}
+
+struct TxtCache {
+    entries: TtlCache<String, Vec<String>>,
+    max_ttl: Duration,
+    negative_ttl: Duration,
+}
+
+impl HickoryDnsTxtResolver {
+    /// Creates a builder for a resolver with explicit nameservers, timeouts and retries
+    pub fn builder() -> HickoryDnsTxtResolverBuilder {
+        HickoryDnsTxtResolverBuilder::new()
+    }
+
+    /// Creates a resolver from the system configuration
+    pub fn from_system_conf() -> Result<Self, DnsResolverError> {
+        HickoryDnsTxtResolverBuilder::new().build()
+    }
+
+    /// Cache TXT lookups for the TTL of their records, capped at the configured TTL. Names
+    /// without TXT records are cached for the negative TTL of their zone, capped at the
+    /// configured negative TTL.
+    pub fn with_cache(mut self, config: &ResolverCacheConfig) -> Self {
+        self.cache = Some(TxtCache {
+            entries: TtlCache::new(config.max_entries, config.stats.txt.clone()),
+            max_ttl: config.ttl,
+            negative_ttl: config.negative_ttl,
+        });
+        self
+    }
 }
 
+/// Creates a resolver from the system configuration, kept for compatibility with releases that
+/// had no builder. Prefer [HickoryDnsTxtResolver::from_system_conf], which returns an error.
+///
+/// # Panics
+///
+/// Panics if the system DNS configuration cannot be read.
 impl Default for HickoryDnsTxtResolver {
     fn default() -> Self {
-        Self {
-            resolver: TokioAsyncResolver::tokio_from_system_conf()
-                .expect("failed to create resolver"),
-        }
+        Self::from_system_conf().expect("failed to read the system DNS configuration")
     }
 }
 
@@ -20,13 +297,225 @@ impl DnsTxtResolver for HickoryDnsTxtResolver {
         &self,
         query: &str,
     ) -> core::result::Result<Vec<String>, Box<dyn std::error::Error + Send + Sync + 'static>> {
-        println!("Resolving TXT for: {}", query);
-        Ok(self
-            .resolver
-            .txt_lookup(query)
-            .await?
-            .iter()
-            .map(|txt| txt.to_string())
-            .collect())
+        let cached = self
+            .cache
+            .as_ref()
+            .and_then(|cache| cache.entries.get(&query.to_string()));
+        if let Some(records) = cached {
+            return Ok(records.unwrap_or_default());
+        }
+        log::debug!("Resolving TXT for: {}", query);
+        match self.resolver.txt_lookup(query).await {
+            Ok(lookup) => {
+                let records: Vec<String> = lookup.iter().map(|txt| txt.to_string()).collect();
+                if let Some(cache) = &self.cache {
+                    let expires_at = lookup.valid_until().min(Instant::now() + cache.max_ttl);
+                    cache
+                        .entries
+                        .insert_until(query.to_string(), Some(records.clone()), expires_at);
+                }
+                Ok(records)
+            }
+            // A name without TXT records has no handle record, which is not an error
+            Err(e) => match e.kind() {
+                ResolveErrorKind::NoRecordsFound { negative_ttl, .. } => {
+                    if let Some(cache) = &self.cache {
+                        let ttl = negative_ttl
+                            .map(|secs| Duration::from_secs(secs.into()))
+                            .map_or(cache.negative_ttl, |ttl| ttl.min(cache.negative_ttl));
+                        cache.entries.insert(query.to_string(), None, ttl);
+                    }
+                    Ok(Vec::new())
+                }
+                _ => Err(e.into()),
+            },
+        }
+    }
+}
+
+/// Caches the results of a handle or DID resolver.
+///
+/// Results are cached for the configured TTL. Handles and DIDs that do not exist are cached
+/// for the negative TTL and answered with [atrium_identity::Error::NotFound]; other errors,
+/// e.g. unreachable servers, are not cached.
+pub struct CachingResolver<R>
+where
+    R: Resolver,
+    R::Input: Sized,
+{
+    inner: R,
+    cache: TtlCache<R::Input, R::Output>,
+    ttl: Duration,
+    negative_ttl: Duration,
+}
+
+impl<R> CachingResolver<R>
+where
+    R: Resolver,
+    R::Input: Sized + Clone + Eq + Hash,
+    R::Output: Clone,
+{
+    /// Wraps `inner`, counting cache hits and misses in `stats`
+    pub fn new(inner: R, config: &ResolverCacheConfig, stats: Arc<CacheStats>) -> Self {
+        Self {
+            inner,
+            cache: TtlCache::new(config.max_entries, stats),
+            ttl: config.ttl,
+            negative_ttl: config.negative_ttl,
+        }
+    }
+}
+
+impl<R> Resolver for CachingResolver<R>
+where
+    R: Resolver<Error = atrium_identity::Error> + Send + Sync,
+    R::Input: Sized + Clone + Eq + Hash + Send + Sync,
+    R::Output: Clone + Send + Sync,
+{
+    type Input = R::Input;
+    type Output = R::Output;
+    type Error = atrium_identity::Error;
+
+    async fn resolve(&self, input: &Self::Input) -> Result<Self::Output, Self::Error> {
+        match self.cache.get(input) {
+            Some(Some(output)) => return Ok(output),
+            Some(None) => return Err(atrium_identity::Error::NotFound),
+            None => {}
+        }
+        match self.inner.resolve(input).await {
+            Ok(output) => {
+                self.cache.insert(input.clone(), Some(output.clone()), self.ttl);
+                Ok(output)
+            }
+            Err(e) if is_not_found(&e) => {
+                self.cache.insert(input.clone(), None, self.negative_ttl);
+                Err(e)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl<R> DidResolver for CachingResolver<R> where R: DidResolver + Send + Sync {}
+
+impl<R> HandleResolver for CachingResolver<R> where R: HandleResolver + Send + Sync {}
+
+fn is_not_found(error: &atrium_identity::Error) -> bool {
+    match error {
+        atrium_identity::Error::NotFound => true,
+        atrium_identity::Error::HttpStatus(status) => status.as_u16() == 404,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use atrium_api::types::string::{Did, Handle};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Resolves `alice.test` and counts the lookups reaching it
+    #[derive(Default)]
+    struct CountingHandleResolver {
+        calls: AtomicUsize,
+    }
+
+    impl Resolver for CountingHandleResolver {
+        type Input = Handle;
+        type Output = Did;
+        type Error = atrium_identity::Error;
+
+        async fn resolve(&self, handle: &Handle) -> Result<Did, Self::Error> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            match handle.as_str() {
+                "alice.test" => Ok(Did::new("did:plc:alice".to_string()).unwrap()),
+                "down.test" => Err(atrium_identity::Error::DnsResolver("timeout".into())),
+                _ => Err(atrium_identity::Error::NotFound),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_caching_resolver_caches_results_and_missing_handles() {
+        let config = ResolverCacheConfig::new();
+        let stats = config.stats().handle.clone();
+        let resolver =
+            CachingResolver::new(CountingHandleResolver::default(), &config, stats.clone());
+        let alice = Handle::new("alice.test".to_string()).unwrap();
+        let missing = Handle::new("missing.test".to_string()).unwrap();
+        let down = Handle::new("down.test".to_string()).unwrap();
+
+        for _ in 0..2 {
+            assert_eq!(resolver.resolve(&alice).await.unwrap().as_str(), "did:plc:alice");
+            assert!(matches!(
+                resolver.resolve(&missing).await,
+                Err(atrium_identity::Error::NotFound)
+            ));
+            assert!(resolver.resolve(&down).await.is_err());
+        }
+        // Only the failed lookup of down.test is repeated
+        assert_eq!(resolver.inner.calls.load(Ordering::SeqCst), 4);
+        assert_eq!((stats.hits(), stats.misses()), (2, 4));
+    }
+
+    #[tokio::test]
+    async fn test_builder_rejects_invalid_options() {
+        let nameserver: SocketAddr = "127.0.0.1:53".parse().unwrap();
+        let zero_attempts = HickoryDnsTxtResolver::builder()
+            .nameserver(nameserver)
+            .attempts(0)
+            .build();
+        assert!(matches!(
+            zero_attempts,
+            Err(DnsResolverError::InvalidConfiguration(_))
+        ));
+        let zero_timeout = HickoryDnsTxtResolver::builder()
+            .nameserver(nameserver)
+            .timeout(Duration::ZERO)
+            .build();
+        assert!(matches!(
+            zero_timeout,
+            Err(DnsResolverError::InvalidConfiguration(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_missing_system_config_is_an_error() {
+        let result = HickoryDnsTxtResolver::builder()
+            .build_with_system_conf(|| Err("No such file or directory: /etc/resolv.conf".into()));
+        assert!(matches!(result, Err(DnsResolverError::SystemConfig(_))));
+        // Explicit upstreams never read the system configuration
+        let result = HickoryDnsTxtResolver::builder()
+            .nameserver("127.0.0.1:53".parse().unwrap())
+            .build_with_system_conf(|| panic!("system configuration read"));
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_nameserver_is_an_error() {
+        // Nothing listens on the discard port
+        let resolver = HickoryDnsTxtResolver::builder()
+            .nameserver("127.0.0.1:9".parse().unwrap())
+            .timeout(Duration::from_millis(100))
+            .attempts(1)
+            .build()
+            .unwrap();
+        assert!(DnsTxtResolver::resolve(&resolver, "_atproto.alice.test")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_disabled_cache_resolves_every_time() {
+        let config = ResolverCacheConfig::disabled();
+        let resolver = CachingResolver::new(
+            CountingHandleResolver::default(),
+            &config,
+            config.stats().handle.clone(),
+        );
+        let alice = Handle::new("alice.test".to_string()).unwrap();
+        resolver.resolve(&alice).await.unwrap();
+        resolver.resolve(&alice).await.unwrap();
+        assert_eq!(resolver.inner.calls.load(Ordering::SeqCst), 2);
     }
 }
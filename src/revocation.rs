@@ -0,0 +1,191 @@
+/// Signing out by revoking tokens at the authorization server
+///
+/// Deleting the local session leaves its tokens valid at the user's PDS. These functions first
+/// revoke the session's refresh token and then its access token at the revocation endpoint
+/// (RFC 7009) of the authorization server that issued them, then delete the local session. They
+/// work with any [DeviceSessionStore]. The local session is deleted even if the server cannot be
+/// reached, so signing out always takes effect locally.
+use crate::{device::DeviceSessionStore, token::TokenClient, TokenError};
+use atrium_api::types::string::Did;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum RevocationError {
+    #[error("No stored OAuth session")]
+    SessionNotFound,
+    #[error("Signed out locally, but the authorization server did not revoke the tokens: {0}")]
+    ServerRevocationFailed(TokenError),
+    #[error("Session store error: {0}")]
+    StoreError(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl RevocationError {
+    fn store<E: std::error::Error + Send + Sync + 'static>(error: E) -> Self {
+        Self::StoreError(Box::new(error))
+    }
+}
+
+/// Outcome of revoking all sessions of a DID
+#[derive(Debug, Default)]
+pub struct RevocationSummary {
+    /// Device sessions revoked at the authorization server and deleted locally
+    pub revoked: Vec<String>,
+    /// Device sessions deleted locally whose server revocation failed
+    pub failed: Vec<(String, RevocationError)>,
+}
+
+impl RevocationSummary {
+    /// Returns true if every session was also revoked at the authorization server
+    pub fn is_complete(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+/// Revokes the tokens of one device session of `did` at its authorization server and deletes
+/// the session from `store`
+pub async fn revoke_session<S: DeviceSessionStore>(
+    tokens: &TokenClient,
+    store: &S,
+    did: &Did,
+    session_id: &str,
+) -> Result<(), RevocationError> {
+    let session = match store.device_session(did, session_id).await {
+        Ok(Some(session)) => session,
+        Ok(None) => return Err(RevocationError::SessionNotFound),
+        Err(e) => {
+            // A session that cannot be read cannot be revoked, but still signs out locally
+            store
+                .delete_device_session(did, session_id)
+                .await
+                .map_err(RevocationError::store)?;
+            return Err(RevocationError::store(e));
+        }
+    };
+
+    let revoked = tokens.revoke(&session).await;
+    store
+        .delete_device_session(did, session_id)
+        .await
+        .map_err(RevocationError::store)?;
+    revoked.map_err(|e| {
+        log::warn!(
+            "Failed to revoke session {session_id} of {}: {e}",
+            did.as_str()
+        );
+        RevocationError::ServerRevocationFailed(e)
+    })
+}
+
+/// Revokes every device session of `did` ("log out everywhere")
+pub async fn revoke_all_sessions<S: DeviceSessionStore>(
+    tokens: &TokenClient,
+    store: &S,
+    did: &Did,
+) -> Result<RevocationSummary, RevocationError> {
+    let session_ids = store
+        .device_session_ids(did)
+        .await
+        .map_err(RevocationError::store)?;
+    let mut summary = RevocationSummary::default();
+    for session_id in session_ids {
+        match revoke_session(tokens, store, did, &session_id).await {
+            Ok(()) => summary.revoked.push(session_id),
+            // Removed concurrently, e.g. by a logout on that device
+            Err(RevocationError::SessionNotFound) => {}
+            Err(e @ RevocationError::StoreError(_)) => return Err(e),
+            Err(e) => summary.failed.push((session_id, e)),
+        }
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::AuthSession,
+        storage::SqliteSessionStore,
+        test_util::sqlite_test_pool,
+        token::tests::{public_client, MockAuthorizationServer},
+    };
+    use async_sqlite::Pool;
+    use atrium_oauth::store::session::Session;
+
+    async fn store_session(pool: &Pool, session: &Session, session_id: &str) {
+        AuthSession::new(session.token_set.sub.to_string(), session)
+            .unwrap()
+            .with_session_id(session_id)
+            .save_or_update(pool)
+            .await
+            .unwrap();
+    }
+
+    fn revoked_tokens(server: &MockAuthorizationServer) -> Vec<(String, String)> {
+        let requests = server.revocation_requests.lock().unwrap();
+        requests
+            .iter()
+            .map(|params| (params["token"].clone(), params["token_type_hint"].clone()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_revoke_session_revokes_refresh_token() {
+        let server = MockAuthorizationServer::spawn().await;
+        let pool = sqlite_test_pool().await;
+        let store = SqliteSessionStore::new(pool.clone());
+        let did = Did::new("did:plc:abc123".to_string()).unwrap();
+
+        assert!(matches!(
+            revoke_session(&public_client(), &store, &did, "laptop").await,
+            Err(RevocationError::SessionNotFound)
+        ));
+
+        let session = server.session(60);
+        store_session(&pool, &session, "laptop").await;
+        store_session(&pool, &session, "phone").await;
+        revoke_session(&public_client(), &store, &did, "laptop")
+            .await
+            .unwrap();
+        assert_eq!(
+            revoked_tokens(&server),
+            vec![
+                ("refresh-0".to_string(), "refresh_token".to_string()),
+                ("access-0".to_string(), "access_token".to_string()),
+            ]
+        );
+        assert_eq!(
+            store.device_session_ids(&did).await.unwrap(),
+            vec!["phone".to_string()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_revoke_all_sessions_deletes_unrevoked_sessions() {
+        let server = MockAuthorizationServer::spawn().await;
+        let pool = sqlite_test_pool().await;
+        let store = SqliteSessionStore::new(pool.clone());
+        let did = Did::new("did:plc:abc123".to_string()).unwrap();
+
+        let mut unreachable = server.session(60);
+        unreachable.token_set.iss = "http://127.0.0.1:9".to_string();
+        store_session(&pool, &server.session(60), "laptop").await;
+        store_session(&pool, &unreachable, "phone").await;
+        store_session(&pool, &server.session(60), "tablet").await;
+
+        let summary = revoke_all_sessions(&public_client(), &store, &did)
+            .await
+            .unwrap();
+        assert!(!summary.is_complete());
+        let mut revoked = summary.revoked.clone();
+        revoked.sort();
+        assert_eq!(revoked, vec!["laptop", "tablet"]);
+        assert_eq!(summary.failed.len(), 1);
+        assert_eq!(summary.failed[0].0, "phone");
+        assert!(matches!(
+            summary.failed[0].1,
+            RevocationError::ServerRevocationFailed(_)
+        ));
+        assert_eq!(revoked_tokens(&server).len(), 4);
+        assert!(store.device_session_ids(&did).await.unwrap().is_empty());
+    }
+}
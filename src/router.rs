@@ -0,0 +1,705 @@
+/// Axum routers for the OAuth flow and for publishing OAuth client documents
+///
+/// Confidential atproto OAuth clients are identified by a `client_id` URL that must resolve to
+/// the client metadata document, and their public keys must be reachable by the authorization
+/// server. [client_metadata_router] serves both documents from the configuration of a built
+/// client, and [oauth_routes] provides the login, callback and logout handlers.
+use crate::{
+    device::{self, ClientInfo, DeviceSessionStore},
+    oauth::AtprotoOAuthClient,
+    revocation,
+    session::SessionCookies,
+    storage::{SqliteSessionStore, SqliteStateStore},
+    token::TokenClient,
+};
+use async_trait::async_trait;
+use atrium_api::{
+    agent::SessionManager,
+    types::string::{Did, Handle},
+};
+use atrium_common::store::Store;
+use atrium_oauth::{
+    store::{
+        session::{Session, SessionStore},
+        state::StateStore,
+    },
+    AuthorizeOptions, CallbackParams, KnownScope, Scope,
+};
+use axum::{
+    extract::{ConnectInfo, Query, State},
+    http::{header, HeaderMap, HeaderValue},
+    response::{IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Router,
+};
+use serde::Deserialize;
+use std::{net::SocketAddr, sync::Arc};
+use thiserror::Error;
+
+/// Path the client metadata document is served from
+pub const CLIENT_METADATA_PATH: &str = "/oauth/client-metadata.json";
+/// Path the public JWKS is served from
+pub const JWKS_PATH: &str = "/oauth/jwks.json";
+
+/// Cache policy for the published documents; authorization servers cache them anyway
+const CACHE_CONTROL: &str = "public, max-age=600";
+
+/// Creates a router serving [CLIENT_METADATA_PATH] and [JWKS_PATH] for the given client.
+///
+/// The documents are generated from the client's redirect URIs, scopes and public signing keys
+/// and are rendered once, so the router can be merged into any application router.
+pub fn client_metadata_router<S, S0, S1>(client: &AtprotoOAuthClient<S0, S1>) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+    S0: StateStore + Send + Sync + 'static,
+    S1: SessionStore + Send + Sync + 'static,
+    S0::Error: std::error::Error + Send + Sync + 'static,
+    S1::Error: std::error::Error + Send + Sync + 'static,
+{
+    let metadata = serde_json::to_string(&client.client_metadata)
+        .expect("client metadata is always serializable");
+    let jwks = serde_json::to_string(&client.jwks()).expect("JWKS is always serializable");
+
+    Router::new()
+        .route(
+            CLIENT_METADATA_PATH,
+            get(move || json_document(metadata.clone())),
+        )
+        .route(JWKS_PATH, get(move || json_document(jwks.clone())))
+}
+
+async fn json_document(body: String) -> impl IntoResponse {
+    (
+        [
+            (header::CONTENT_TYPE, "application/json"),
+            (header::CACHE_CONTROL, CACHE_CONTROL),
+        ],
+        body,
+    )
+}
+
+/// Errors surfaced by the [oauth_routes] handlers
+#[derive(Error, Debug)]
+pub enum OAuthFlowError {
+    #[error("Handle parameter required")]
+    MissingHandle,
+    #[error("Invalid handle or DID: {0}")]
+    InvalidIdentifier(String),
+    #[error("Failed to start OAuth flow: {0}")]
+    Authorize(atrium_oauth::Error),
+    #[error("Authorization denied: {0}")]
+    AuthorizationDenied(String),
+    #[error("Missing authorization code")]
+    MissingCode,
+    #[error("Failed to process OAuth callback: {0}")]
+    Callback(atrium_oauth::Error),
+    #[error("OAuth session has no DID")]
+    MissingDid,
+}
+
+/// Application hooks invoked by the [oauth_routes] handlers
+///
+/// Every hook may return a response to replace the default redirect, e.g. to set
+/// application cookies or render an error page.
+#[async_trait]
+pub trait OAuthHooks: Send + Sync + 'static {
+    /// Called after a successful callback with the authenticated DID
+    async fn on_login(&self, _did: &Did, _headers: &HeaderMap) -> Option<Response> {
+        None
+    }
+
+    /// Called when starting the flow or processing the callback fails
+    async fn on_failure(&self, _error: &OAuthFlowError, _headers: &HeaderMap) -> Option<Response> {
+        None
+    }
+
+    /// Called when the logout route is requested
+    async fn on_logout(&self, _headers: &HeaderMap) -> Option<Response> {
+        None
+    }
+}
+
+/// Hooks that do nothing, leaving the default redirects in place
+pub struct NoopHooks;
+
+impl OAuthHooks for NoopHooks {}
+
+/// Configuration for [oauth_routes], for a client storing states in `S0` and sessions in `S1`
+pub struct OAuthRoutesConfig<S0 = SqliteStateStore, S1 = SqliteSessionStore>
+where
+    S1: SessionStore + Send + Sync + 'static,
+    S1::Error: std::error::Error + Send + Sync + 'static,
+{
+    client: Arc<AtprotoOAuthClient<S0, S1>>,
+    tokens: TokenClient,
+    scopes: Vec<Scope>,
+    success_redirect: String,
+    failure_redirect: String,
+    logout_redirect: String,
+    hooks: Arc<dyn OAuthHooks>,
+    session_cookies: Option<Arc<SessionCookies<S1>>>,
+    trust_forwarded_for: bool,
+}
+
+impl<S0, S1> OAuthRoutesConfig<S0, S1>
+where
+    S1: SessionStore + Send + Sync + 'static,
+    S1::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Create a new routes configuration for the given client
+    pub fn new(client: Arc<AtprotoOAuthClient<S0, S1>>) -> Self {
+        Self {
+            tokens: TokenClient::for_client(&client.client_metadata),
+            client,
+            scopes: vec![
+                Scope::Known(KnownScope::Atproto),
+                Scope::Known(KnownScope::TransitionGeneric),
+            ],
+            success_redirect: "/".to_string(),
+            failure_redirect: "/".to_string(),
+            logout_redirect: "/".to_string(),
+            hooks: Arc::new(NoopHooks),
+            session_cookies: None,
+            trust_forwarded_for: false,
+        }
+    }
+
+    /// Set the scopes requested at login (default: Atproto + TransitionGeneric)
+    pub fn scopes(mut self, scopes: Vec<Scope>) -> Self {
+        self.scopes = scopes;
+        self
+    }
+
+    /// Set where to redirect after a successful login (default: "/")
+    pub fn success_redirect(mut self, target: impl Into<String>) -> Self {
+        self.success_redirect = target.into();
+        self
+    }
+
+    /// Set where to redirect after a failed login, with the message in an `error` query parameter (default: "/")
+    pub fn failure_redirect(mut self, target: impl Into<String>) -> Self {
+        self.failure_redirect = target.into();
+        self
+    }
+
+    /// Set where to redirect after logout (default: "/")
+    pub fn logout_redirect(mut self, target: impl Into<String>) -> Self {
+        self.logout_redirect = target.into();
+        self
+    }
+
+    /// Set the application hooks
+    pub fn hooks(mut self, hooks: impl OAuthHooks) -> Self {
+        self.hooks = Arc::new(hooks);
+        self
+    }
+
+    /// Set the token client logout revokes tokens with (default: the one the client was built
+    /// with, see [TokenClient::for_client]). Confidential clients need their signing keys to
+    /// authenticate revocation requests.
+    pub fn token_client(mut self, tokens: TokenClient) -> Self {
+        self.tokens = tokens;
+        self
+    }
+
+    /// Issue a signed session cookie on login and clear it on logout. The cookies check sessions
+    /// in a store of the client's session store type, which logout revokes sessions from.
+    pub fn session_cookies(mut self, session_cookies: Arc<SessionCookies<S1>>) -> Self {
+        self.session_cookies = Some(session_cookies);
+        self
+    }
+
+    /// Record the client address from the first `X-Forwarded-For` entry instead of the peer
+    /// address. Only enable this behind a proxy that sets the header.
+    pub fn trust_forwarded_for(mut self) -> Self {
+        self.trust_forwarded_for = true;
+        self
+    }
+
+    /// Describes the client completing a login, for the session metadata
+    fn client_info(&self, headers: &HeaderMap, peer: Option<SocketAddr>) -> ClientInfo {
+        let user_agent = headers
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let forwarded_for = headers
+            .get("x-forwarded-for")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(',').next())
+            .map(|address| address.trim().to_string())
+            .filter(|address| !address.is_empty());
+        let ip_address = match forwarded_for {
+            Some(address) if self.trust_forwarded_for => Some(address),
+            _ => peer.map(|peer| peer.ip().to_string()),
+        };
+        ClientInfo {
+            user_agent,
+            ip_address,
+        }
+    }
+
+    async fn fail(&self, error: OAuthFlowError, headers: &HeaderMap) -> Response {
+        log::warn!("OAuth flow failed: {error}");
+        if let Some(response) = self.hooks.on_failure(&error, headers).await {
+            return response;
+        }
+        let separator = if self.failure_redirect.contains('?') {
+            '&'
+        } else {
+            '?'
+        };
+        let query = form_urlencoded::Serializer::new(String::new())
+            .append_pair("error", &error.to_string())
+            .finish();
+        Redirect::to(&format!("{}{separator}{query}", self.failure_redirect)).into_response()
+    }
+}
+
+/// Path of the login route, taking a `handle` query parameter
+pub const LOGIN_PATH: &str = "/login";
+/// Path of the OAuth callback route
+pub const CALLBACK_PATH: &str = "/oauth/callback";
+/// Path of the logout route
+pub const LOGOUT_PATH: &str = "/logout";
+
+/// Creates a router with [LOGIN_PATH], [CALLBACK_PATH] and [LOGOUT_PATH] handlers.
+///
+/// `GET /login?handle=<handle or DID>` starts the flow, `GET /oauth/callback` completes it and
+/// stores the session, and `POST /logout` runs the logout hook. Each route redirects to the
+/// configured targets unless a hook returns its own response. Logout only accepts `POST`, so a
+/// cross-site link or image cannot sign a user out.
+///
+/// Sessions record the client's user agent and address. The address is only known when the
+/// application is served with `into_make_service_with_connect_info::<SocketAddr>()`.
+pub fn oauth_routes<S, S0, S1>(config: OAuthRoutesConfig<S0, S1>) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+    S0: StateStore + Send + Sync + 'static,
+    S1: SessionStore + DeviceSessionStore + Send + Sync + 'static,
+    S0::Error: std::error::Error + Send + Sync + 'static,
+    <S1 as Store<Did, Session>>::Error: std::error::Error + Send + Sync + 'static,
+{
+    Router::new()
+        .route(LOGIN_PATH, get(login))
+        .route(CALLBACK_PATH, get(callback))
+        .route(LOGOUT_PATH, post(logout))
+        .with_state(Arc::new(config))
+}
+
+#[derive(Deserialize)]
+struct LoginQuery {
+    handle: Option<String>,
+}
+
+async fn login<S0, S1>(
+    State(config): State<Arc<OAuthRoutesConfig<S0, S1>>>,
+    headers: HeaderMap,
+    Query(query): Query<LoginQuery>,
+) -> Response
+where
+    S0: StateStore + Send + Sync + 'static,
+    S1: SessionStore + DeviceSessionStore + Send + Sync + 'static,
+    S0::Error: std::error::Error + Send + Sync + 'static,
+    <S1 as Store<Did, Session>>::Error: std::error::Error + Send + Sync + 'static,
+{
+    let Some(input) = query
+        .handle
+        .map(|h| h.trim().to_string())
+        .filter(|h| !h.is_empty())
+    else {
+        return config.fail(OAuthFlowError::MissingHandle, &headers).await;
+    };
+    let valid = if input.starts_with("did:") {
+        Did::new(input.clone()).is_ok()
+    } else {
+        Handle::new(input.clone()).is_ok()
+    };
+    if !valid {
+        return config
+            .fail(OAuthFlowError::InvalidIdentifier(input), &headers)
+            .await;
+    }
+
+    let options = AuthorizeOptions {
+        scopes: config.scopes.clone(),
+        ..Default::default()
+    };
+    match config.client.authorize(&input, options).await {
+        Ok(url) => Redirect::to(&url).into_response(),
+        Err(e) => config.fail(OAuthFlowError::Authorize(e), &headers).await,
+    }
+}
+
+#[derive(Deserialize)]
+struct CallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    iss: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+async fn callback<S0, S1>(
+    State(config): State<Arc<OAuthRoutesConfig<S0, S1>>>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    Query(query): Query<CallbackQuery>,
+) -> Response
+where
+    S0: StateStore + Send + Sync + 'static,
+    S1: SessionStore + DeviceSessionStore + Send + Sync + 'static,
+    S0::Error: std::error::Error + Send + Sync + 'static,
+    <S1 as Store<Did, Session>>::Error: std::error::Error + Send + Sync + 'static,
+{
+    if let Some(error) = query.error {
+        let message = query.error_description.unwrap_or(error);
+        return config
+            .fail(OAuthFlowError::AuthorizationDenied(message), &headers)
+            .await;
+    }
+    let Some(code) = query.code else {
+        return config.fail(OAuthFlowError::MissingCode, &headers).await;
+    };
+    let params = CallbackParams {
+        code,
+        state: query.state,
+        iss: query.iss,
+    };
+
+    // Every login becomes its own device session, leaving the DID's other devices signed in
+    let session_id = device::new_session_id();
+    let client_info = config.client_info(&headers, peer.map(|ConnectInfo(peer)| peer));
+    let callback = device::with_client_info(client_info, config.client.callback(params));
+    let session = match device::with_session_id(session_id.clone(), callback).await {
+        Ok((session, _)) => session,
+        Err(e) => return config.fail(OAuthFlowError::Callback(e), &headers).await,
+    };
+    let Some(did) = session.did().await else {
+        return config.fail(OAuthFlowError::MissingDid, &headers).await;
+    };
+    log::info!("OAuth login completed for {}", did.as_str());
+
+    let mut response = match config.hooks.on_login(&did, &headers).await {
+        Some(response) => response,
+        None => Redirect::to(&config.success_redirect).into_response(),
+    };
+    if let Some(session_cookies) = &config.session_cookies {
+        append_set_cookie(&mut response, session_cookies.issue(&did, &session_id));
+    }
+    response
+}
+
+async fn logout<S0, S1>(
+    State(config): State<Arc<OAuthRoutesConfig<S0, S1>>>,
+    headers: HeaderMap,
+) -> Response
+where
+    S0: StateStore + Send + Sync + 'static,
+    S1: SessionStore + DeviceSessionStore + Send + Sync + 'static,
+    S0::Error: std::error::Error + Send + Sync + 'static,
+    <S1 as Store<Did, Session>>::Error: std::error::Error + Send + Sync + 'static,
+{
+    let mut response = match config.hooks.on_logout(&headers).await {
+        Some(response) => response,
+        None => Redirect::to(&config.logout_redirect).into_response(),
+    };
+    if let Some(session_cookies) = &config.session_cookies {
+        // Only this device is signed out, the DID's other device sessions stay valid
+        if let Ok(session) = session_cookies.session_from_headers(&headers).await {
+            let revoked = revocation::revoke_session(
+                &config.tokens,
+                session_cookies.store(),
+                &session.did,
+                &session.session_id,
+            )
+            .await;
+            if let Err(e) = revoked {
+                log::error!("Failed to revoke session {}: {e}", session.session_id);
+            }
+        }
+        append_set_cookie(&mut response, session_cookies.clear());
+    }
+    response
+}
+
+fn append_set_cookie(response: &mut Response, cookie: String) {
+    match HeaderValue::try_from(cookie) {
+        Ok(value) => {
+            response.headers_mut().append(header::SET_COOKIE, value);
+        }
+        Err(e) => log::error!("Invalid session cookie header: {e}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oauth::OAuthClientBuilder;
+    use axum::{body::Body, http::Request, http::StatusCode};
+    use tower::ServiceExt;
+
+    async fn get_json(router: Router, path: &str) -> serde_json::Value {
+        let response = router
+            .oneshot(Request::get(path).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+        assert_eq!(response.headers()[header::CACHE_CONTROL], CACHE_CONTROL);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    async fn test_client() -> Arc<AtprotoOAuthClient> {
+        let pool = async_sqlite::PoolBuilder::new().open().await.unwrap();
+        OAuthClientBuilder::new().db_pool(pool).build().unwrap()
+    }
+
+    async fn get_redirect(router: Router, path: &str) -> String {
+        redirect_location(router, Request::get(path).body(Body::empty()).unwrap()).await
+    }
+
+    async fn post_redirect(router: Router, path: &str) -> String {
+        redirect_location(router, Request::post(path).body(Body::empty()).unwrap()).await
+    }
+
+    async fn redirect_location(router: Router, request: Request<Body>) -> String {
+        let response = router.oneshot(request).await.unwrap();
+        assert!(response.status().is_redirection());
+        response.headers()[header::LOCATION]
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn test_login_requires_handle() {
+        let router: Router =
+            oauth_routes(OAuthRoutesConfig::new(test_client().await).failure_redirect("/failed"));
+        assert_eq!(
+            get_redirect(router.clone(), LOGIN_PATH).await,
+            "/failed?error=Handle+parameter+required"
+        );
+        assert!(get_redirect(router, "/login?handle=not%20a%20handle")
+            .await
+            .starts_with("/failed?error=Invalid+handle"));
+    }
+
+    #[tokio::test]
+    async fn test_callback_denied() {
+        let router: Router = oauth_routes(
+            OAuthRoutesConfig::new(test_client().await).failure_redirect("/?view=login"),
+        );
+        let location = get_redirect(
+            router,
+            "/oauth/callback?error=access_denied&error_description=User%20denied",
+        )
+        .await;
+        assert_eq!(
+            location,
+            "/?view=login&error=Authorization+denied%3A+User+denied"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_logout_hook() {
+        struct LogoutHooks;
+
+        #[async_trait]
+        impl OAuthHooks for LogoutHooks {
+            async fn on_logout(&self, _headers: &HeaderMap) -> Option<Response> {
+                Some(Redirect::to("/bye").into_response())
+            }
+        }
+
+        let client = test_client().await;
+        let router: Router = oauth_routes(OAuthRoutesConfig::new(client.clone()));
+        assert_eq!(post_redirect(router, LOGOUT_PATH).await, "/");
+
+        let router: Router = oauth_routes(OAuthRoutesConfig::new(client).hooks(LogoutHooks));
+        assert_eq!(post_redirect(router, LOGOUT_PATH).await, "/bye");
+    }
+
+    #[tokio::test]
+    async fn test_logout_rejects_get() {
+        let router: Router = oauth_routes(OAuthRoutesConfig::new(test_client().await));
+        let response = router
+            .oneshot(Request::get(LOGOUT_PATH).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn test_logout_clears_session_cookie() {
+        let pool = async_sqlite::PoolBuilder::new().open().await.unwrap();
+        let session_cookies = Arc::new(SessionCookies::new(
+            pool,
+            crate::session::SessionCookieKey::generate("k1").unwrap(),
+        ));
+        let router: Router = oauth_routes(
+            OAuthRoutesConfig::new(test_client().await).session_cookies(session_cookies),
+        );
+        let response = router
+            .oneshot(Request::post(LOGOUT_PATH).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let cookie = response.headers()[header::SET_COOKIE].to_str().unwrap();
+        assert!(cookie.starts_with("session=;"));
+        assert!(cookie.contains("Max-Age=0"));
+    }
+
+    #[tokio::test]
+    async fn test_logout_revokes_session() {
+        let server = crate::token::tests::MockAuthorizationServer::spawn().await;
+        let pool = crate::test_util::sqlite_test_pool().await;
+        let session = server.session(60);
+        crate::db::AuthSession::new(session.token_set.sub.to_string(), &session)
+            .unwrap()
+            .with_session_id("laptop")
+            .save_or_update(&pool)
+            .await
+            .unwrap();
+        let session_cookies = Arc::new(SessionCookies::new(
+            pool.clone(),
+            crate::session::SessionCookieKey::generate("k1").unwrap(),
+        ));
+        let token = session_cookies.token_for(&session.token_set.sub, "laptop");
+        let router: Router = oauth_routes(
+            OAuthRoutesConfig::new(test_client().await).session_cookies(session_cookies),
+        );
+        let request = Request::post(LOGOUT_PATH)
+            .header(header::COOKIE, format!("session={token}"))
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(redirect_location(router, request).await, "/");
+
+        let revoked = server.revocation_requests.lock().unwrap().clone();
+        assert_eq!(revoked[0]["token"], "refresh-0");
+        assert_eq!(revoked[0]["token_type_hint"], "refresh_token");
+        assert!(crate::db::AuthSession::list_for_did(&pool, session.token_set.sub.to_string())
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[cfg(feature = "redis-storage")]
+    #[tokio::test]
+    async fn test_logout_revokes_session_from_other_stores() {
+        use crate::redis_store::{tests::test_connection, RedisSessionStore};
+
+        let server = crate::token::tests::MockAuthorizationServer::spawn().await;
+        let connection = test_connection().await;
+        let session = server.session(60);
+        let did = session.token_set.sub.clone();
+        let store = RedisSessionStore::new(connection.clone());
+        store.set(did.clone(), session).await.unwrap();
+
+        let client = OAuthClientBuilder::new()
+            .build_redis(connection.clone())
+            .unwrap();
+        let session_cookies = Arc::new(SessionCookies::with_store(
+            RedisSessionStore::new(connection),
+            crate::session::SessionCookieKey::generate("k1").unwrap(),
+        ));
+        let token = session_cookies.token_for(&did, crate::db::DEFAULT_SESSION_ID);
+        let router: Router =
+            oauth_routes(OAuthRoutesConfig::new(client).session_cookies(session_cookies));
+        let request = Request::post(LOGOUT_PATH)
+            .header(header::COOKIE, format!("session={token}"))
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(redirect_location(router, request).await, "/");
+
+        assert_eq!(server.revocation_requests.lock().unwrap().len(), 2);
+        let stored: Option<Session> = store.get(&did).await.unwrap();
+        assert!(stored.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_logout_authenticates_confidential_clients() {
+        let server = crate::token::tests::MockAuthorizationServer::spawn().await;
+        let pool = crate::test_util::sqlite_test_pool().await;
+        let session = server.session(60);
+        crate::db::AuthSession::new(session.token_set.sub.to_string(), &session)
+            .unwrap()
+            .with_session_id("laptop")
+            .save_or_update(&pool)
+            .await
+            .unwrap();
+        let client = OAuthClientBuilder::new()
+            .db_pool(pool.clone())
+            .client_id("https://logout.example.com/oauth/client-metadata.json")
+            .signing_key_pem("kid00", crate::keys::tests::TEST_PRIVATE_KEY)
+            .build()
+            .unwrap();
+        let session_cookies = Arc::new(SessionCookies::new(
+            pool,
+            crate::session::SessionCookieKey::generate("k1").unwrap(),
+        ));
+        let token = session_cookies.token_for(&session.token_set.sub, "laptop");
+        let router: Router =
+            oauth_routes(OAuthRoutesConfig::new(client).session_cookies(session_cookies));
+        let request = Request::post(LOGOUT_PATH)
+            .header(header::COOKIE, format!("session={token}"))
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(redirect_location(router, request).await, "/");
+
+        let revoked = server.revocation_requests.lock().unwrap().clone();
+        assert_eq!(revoked.len(), 2);
+        assert!(revoked.iter().all(|request| request.contains_key("client_assertion")));
+    }
+
+    #[tokio::test]
+    async fn test_serves_client_documents() {
+        let pool = async_sqlite::PoolBuilder::new().open().await.unwrap();
+        let client = OAuthClientBuilder::new()
+            .db_pool(pool)
+            .client_id("https://app.example.com/oauth/client-metadata.json")
+            .signing_key_pem("kid00", crate::keys::tests::TEST_PRIVATE_KEY)
+            .build()
+            .unwrap();
+        let router: Router = client_metadata_router(&client);
+
+        let metadata = get_json(router.clone(), CLIENT_METADATA_PATH).await;
+        assert_eq!(
+            metadata["client_id"],
+            "https://app.example.com/oauth/client-metadata.json"
+        );
+        assert_eq!(
+            metadata["redirect_uris"][0],
+            "https://app.example.com/oauth/callback"
+        );
+        assert_eq!(metadata["scope"], "atproto transition:generic");
+
+        let jwks = get_json(router, JWKS_PATH).await;
+        let keys = jwks["keys"].as_array().unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0]["kid"], "kid00");
+        assert!(keys[0].get("d").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_client_info_only_trusts_forwarded_for_when_enabled() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::USER_AGENT, HeaderValue::from_static("Firefox"));
+        headers.insert(
+            "x-forwarded-for",
+            HeaderValue::from_static("203.0.113.7, 10.0.0.1"),
+        );
+        let peer: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+
+        let config = OAuthRoutesConfig::new(test_client().await);
+        let info = config.client_info(&headers, Some(peer));
+        assert_eq!(info.user_agent.as_deref(), Some("Firefox"));
+        assert_eq!(info.ip_address.as_deref(), Some("10.0.0.1"));
+
+        let config = config.trust_forwarded_for();
+        let info = config.client_info(&headers, Some(peer));
+        assert_eq!(info.ip_address.as_deref(), Some("203.0.113.7"));
+        assert_eq!(config.client_info(&HeaderMap::new(), None), ClientInfo::default());
+    }
+}
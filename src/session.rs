@@ -0,0 +1,472 @@
+/// Signed application session cookies bound to stored OAuth sessions
+///
+/// After a completed OAuth callback the application needs to remember who the browser belongs
+/// to. Storing the raw DID in a cookie lets anyone impersonate any user, so [SessionCookies]
+/// issues an HMAC-SHA256 signed token instead and only accepts it while the matching device
+/// session is still stored, in any [DeviceSessionStore]. Tokens carry the id of the key that
+/// signed them, so keys can be rotated by keeping the previous key around for verification.
+use crate::db::DEFAULT_SESSION_ID;
+use crate::device::DeviceSessionStore;
+use crate::storage::SqliteSessionStore;
+use async_sqlite::Pool;
+use atrium_api::types::string::Did;
+use axum::http::{header, HeaderMap};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Duration, Utc};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use thiserror::Error;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Minimum accepted length of a signing secret in bytes
+const MIN_SECRET_LEN: usize = 32;
+
+#[derive(Error, Debug)]
+pub enum SessionCookieError {
+    #[error("Signing secret must be at least {MIN_SECRET_LEN} bytes")]
+    WeakKey,
+    #[error("Key id must be non-empty and must not contain '.'")]
+    InvalidKeyId,
+    #[error("No session token presented")]
+    Missing,
+    #[error("Session token is malformed")]
+    Malformed,
+    #[error("Session token was signed with an unknown key")]
+    UnknownKey,
+    #[error("Session token signature is invalid")]
+    InvalidSignature,
+    #[error("Session token has expired")]
+    Expired,
+    #[error("No stored OAuth session for this token")]
+    SessionNotFound,
+    #[error("Session store error: {0}")]
+    StoreError(Box<dyn std::error::Error + Send + Sync>),
+}
+
+/// HMAC key used to sign session tokens
+#[derive(Clone)]
+pub struct SessionCookieKey {
+    id: String,
+    secret: Vec<u8>,
+}
+
+impl SessionCookieKey {
+    /// Creates a key from an id and a secret of at least 32 bytes
+    pub fn new(
+        id: impl Into<String>,
+        secret: impl Into<Vec<u8>>,
+    ) -> Result<Self, SessionCookieError> {
+        let id = id.into();
+        let secret = secret.into();
+        if id.is_empty() || id.contains('.') {
+            return Err(SessionCookieError::InvalidKeyId);
+        }
+        if secret.len() < MIN_SECRET_LEN {
+            return Err(SessionCookieError::WeakKey);
+        }
+        Ok(Self { id, secret })
+    }
+
+    /// Creates a key with a random 32 byte secret.
+    /// Tokens signed with it stop verifying once the process restarts.
+    pub fn generate(id: impl Into<String>) -> Result<Self, SessionCookieError> {
+        let mut secret = vec![0u8; MIN_SECRET_LEN];
+        rand::thread_rng().fill_bytes(&mut secret);
+        Self::new(id, secret)
+    }
+
+    /// The id embedded in tokens signed with this key
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn mac(&self, message: &str) -> HmacSha256 {
+        let mut mac =
+            HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts keys of any length");
+        mac.update(message.as_bytes());
+        mac
+    }
+}
+
+impl std::fmt::Debug for SessionCookieKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionCookieKey")
+            .field("id", &self.id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// An authenticated application session, only obtainable from a valid signed token
+#[derive(Clone, Debug)]
+pub struct SessionData {
+    pub did: Did,
+    /// The device session the token is bound to
+    pub session_id: String,
+    pub issued_at: DateTime<Utc>,
+}
+
+/// Issues and verifies signed session cookies, checking their sessions in a store of type `S`
+pub struct SessionCookies<S = SqliteSessionStore> {
+    store: S,
+    signing_key: SessionCookieKey,
+    previous_keys: Vec<SessionCookieKey>,
+    cookie_name: String,
+    max_age: Duration,
+    secure: bool,
+}
+
+impl SessionCookies {
+    /// Create a session cookie layer signing with `signing_key` and checking sessions in `db_pool`
+    pub fn new(db_pool: Pool, signing_key: SessionCookieKey) -> Self {
+        Self::with_store(SqliteSessionStore::new(db_pool), signing_key)
+    }
+}
+
+impl<S> SessionCookies<S> {
+    /// Create a session cookie layer checking sessions in `store`. Use a store configured like
+    /// the client's, e.g. from [session_store](crate::OAuthClientBuilder::session_store), so
+    /// encrypted sessions can be read when they are revoked.
+    pub fn with_store(store: S, signing_key: SessionCookieKey) -> Self {
+        Self {
+            store,
+            signing_key,
+            previous_keys: Vec::new(),
+            cookie_name: "session".to_string(),
+            max_age: Duration::days(30),
+            secure: true,
+        }
+    }
+
+    /// Accept tokens signed with a previous key, so sessions survive a key rotation
+    pub fn previous_key(mut self, key: SessionCookieKey) -> Self {
+        self.previous_keys.push(key);
+        self
+    }
+
+    /// Set the cookie name (default: "session")
+    pub fn cookie_name(mut self, name: impl Into<String>) -> Self {
+        self.cookie_name = name.into();
+        self
+    }
+
+    /// Set how long issued tokens stay valid (default: 30 days)
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    /// Set whether cookies carry the `Secure` attribute (default: true)
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    /// Creates a signed token for the given DID and device session
+    pub fn token_for(&self, did: &Did, session_id: &str) -> String {
+        let mut nonce = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let payload = format!(
+            "{}|{}|{}|{}",
+            did.as_str(),
+            Utc::now().timestamp(),
+            URL_SAFE_NO_PAD.encode(nonce),
+            session_id
+        );
+        let signed = format!(
+            "{}.{}",
+            self.signing_key.id,
+            URL_SAFE_NO_PAD.encode(payload)
+        );
+        let signature = self.signing_key.mac(&signed).finalize().into_bytes();
+        format!("{signed}.{}", URL_SAFE_NO_PAD.encode(signature))
+    }
+
+    /// Creates a `Set-Cookie` header value carrying a signed token for the given DID and device
+    /// session
+    pub fn issue(&self, did: &Did, session_id: &str) -> String {
+        self.set_cookie(&self.token_for(did, session_id), self.max_age.num_seconds())
+    }
+
+    /// Creates a `Set-Cookie` header value that removes the session cookie
+    pub fn clear(&self) -> String {
+        self.set_cookie("", 0)
+    }
+
+    fn set_cookie(&self, value: &str, max_age: i64) -> String {
+        let mut cookie = format!(
+            "{}={value}; Path=/; HttpOnly; SameSite=Lax; Max-Age={max_age}",
+            self.cookie_name
+        );
+        if self.secure {
+            cookie.push_str("; Secure");
+        }
+        cookie
+    }
+
+    /// Verifies a token's signature and age without consulting the database
+    pub fn verify_token(&self, token: &str) -> Result<SessionData, SessionCookieError> {
+        let (signed, signature) = token
+            .rsplit_once('.')
+            .ok_or(SessionCookieError::Malformed)?;
+        let (key_id, payload) = signed
+            .split_once('.')
+            .ok_or(SessionCookieError::Malformed)?;
+        let key = std::iter::once(&self.signing_key)
+            .chain(&self.previous_keys)
+            .find(|key| key.id == key_id)
+            .ok_or(SessionCookieError::UnknownKey)?;
+
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| SessionCookieError::Malformed)?;
+        key.mac(signed)
+            .verify_slice(&signature)
+            .map_err(|_| SessionCookieError::InvalidSignature)?;
+
+        let payload = URL_SAFE_NO_PAD
+            .decode(payload)
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .ok_or(SessionCookieError::Malformed)?;
+        let mut parts = payload.split('|');
+        let (Some(did), Some(issued_at)) = (parts.next(), parts.next()) else {
+            return Err(SessionCookieError::Malformed);
+        };
+        // Tokens issued before sessions were kept per device belong to the default session
+        let session_id = parts.nth(1).unwrap_or(DEFAULT_SESSION_ID).to_string();
+        let did = Did::new(did.to_string()).map_err(|_| SessionCookieError::Malformed)?;
+        let issued_at = issued_at
+            .parse::<i64>()
+            .ok()
+            .and_then(|secs| DateTime::from_timestamp(secs, 0))
+            .ok_or(SessionCookieError::Malformed)?;
+        if Utc::now() - issued_at > self.max_age {
+            return Err(SessionCookieError::Expired);
+        }
+        Ok(SessionData {
+            did,
+            session_id,
+            issued_at,
+        })
+    }
+
+    /// Verifies a token and checks that its OAuth session is still stored. Marks the session as
+    /// used, see [touch_device_session](DeviceSessionStore::touch_device_session).
+    pub async fn verify(&self, token: &str) -> Result<SessionData, SessionCookieError>
+    where
+        S: DeviceSessionStore,
+    {
+        let session = self.verify_token(token)?;
+        let stored = self
+            .store
+            .touch_device_session(&session.did, &session.session_id)
+            .await
+            .map_err(|e| SessionCookieError::StoreError(Box::new(e)))?;
+        if !stored {
+            return Err(SessionCookieError::SessionNotFound);
+        }
+        Ok(session)
+    }
+
+    /// Deletes the stored OAuth session of the token's device, signing out that device only.
+    /// Tokens issued for the DID's other devices stay valid. This does not contact the
+    /// authorization server, see [revoke_session](crate::revocation::revoke_session) for that.
+    pub async fn delete_session(&self, session: &SessionData) -> Result<(), SessionCookieError>
+    where
+        S: DeviceSessionStore,
+    {
+        self.store
+            .delete_device_session(&session.did, &session.session_id)
+            .await
+            .map_err(|e| SessionCookieError::StoreError(Box::new(e)))
+    }
+
+    /// The store sessions are checked in
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Finds the session token in the request, from the session cookie or an
+    /// `Authorization: Bearer` header
+    pub fn token_from_headers<'a>(&self, headers: &'a HeaderMap) -> Option<&'a str> {
+        let bearer = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        bearer.or_else(|| {
+            headers
+                .get_all(header::COOKIE)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|cookies| cookies.split(';'))
+                .filter_map(|cookie| cookie.trim().split_once('='))
+                .find(|(name, _)| *name == self.cookie_name)
+                .map(|(_, value)| value)
+        })
+    }
+
+    /// Authenticates a request from its headers
+    pub async fn session_from_headers(
+        &self,
+        headers: &HeaderMap,
+    ) -> Result<SessionData, SessionCookieError>
+    where
+        S: DeviceSessionStore,
+    {
+        let token = self
+            .token_from_headers(headers)
+            .filter(|token| !token.is_empty())
+            .ok_or(SessionCookieError::Missing)?;
+        self.verify(token).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{create_oauth_tables, AuthSession};
+    use axum::http::HeaderValue;
+
+    const SECRET: &[u8; 32] = b"0123456789abcdef0123456789abcdef";
+
+    async fn test_pool() -> Pool {
+        let pool = async_sqlite::PoolBuilder::new()
+            .num_conns(1)
+            .open()
+            .await
+            .unwrap();
+        create_oauth_tables(&pool).await.unwrap();
+        pool
+    }
+
+    fn did() -> Did {
+        Did::new("did:plc:abc123".to_string()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_token_roundtrip_requires_stored_session() {
+        let pool = test_pool().await;
+        let cookies =
+            SessionCookies::new(pool.clone(), SessionCookieKey::new("k1", *SECRET).unwrap());
+        let token = cookies.token_for(&did(), "laptop");
+
+        assert_eq!(cookies.verify_token(&token).unwrap().did, did());
+        assert!(matches!(
+            cookies.verify(&token).await,
+            Err(SessionCookieError::SessionNotFound)
+        ));
+
+        // Another device's session does not authenticate this token
+        AuthSession::new(did().to_string(), "{}")
+            .unwrap()
+            .with_session_id("phone")
+            .save_or_update(&pool)
+            .await
+            .unwrap();
+        assert!(matches!(
+            cookies.verify(&token).await,
+            Err(SessionCookieError::SessionNotFound)
+        ));
+
+        AuthSession::new(did().to_string(), "{}")
+            .unwrap()
+            .with_session_id("laptop")
+            .save_or_update(&pool)
+            .await
+            .unwrap();
+        let session = cookies.verify(&token).await.unwrap();
+        assert_eq!(session.did, did());
+        assert_eq!(session.session_id, "laptop");
+    }
+
+    #[tokio::test]
+    async fn test_rejects_tampered_tokens() {
+        let cookies = SessionCookies::new(
+            test_pool().await,
+            SessionCookieKey::new("k1", *SECRET).unwrap(),
+        );
+        let token = cookies.token_for(&did(), DEFAULT_SESSION_ID);
+
+        let (key_id, rest) = token.split_once('.').unwrap();
+        let (_, signature) = rest.split_once('.').unwrap();
+        let forged_payload = URL_SAFE_NO_PAD.encode("did:plc:someoneelse|0|x");
+        let forged = format!("{key_id}.{forged_payload}.{signature}");
+        assert!(matches!(
+            cookies.verify_token(&forged),
+            Err(SessionCookieError::InvalidSignature)
+        ));
+        assert!(matches!(
+            cookies.verify_token("did:plc:abc123"),
+            Err(SessionCookieError::Malformed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_key_rotation() {
+        let pool = test_pool().await;
+        let old_key = SessionCookieKey::new("k1", *SECRET).unwrap();
+        let old = SessionCookies::new(pool.clone(), old_key.clone());
+        let token = old.token_for(&did(), DEFAULT_SESSION_ID);
+
+        let rotated = SessionCookies::new(pool.clone(), SessionCookieKey::generate("k2").unwrap());
+        assert!(matches!(
+            rotated.verify_token(&token),
+            Err(SessionCookieError::UnknownKey)
+        ));
+        let rotated = rotated.previous_key(old_key);
+        assert_eq!(rotated.verify_token(&token).unwrap().did, did());
+    }
+
+    #[tokio::test]
+    async fn test_expired_token() {
+        let cookies = SessionCookies::new(
+            test_pool().await,
+            SessionCookieKey::new("k1", *SECRET).unwrap(),
+        )
+        .max_age(Duration::seconds(-1));
+        let token = cookies.token_for(&did(), DEFAULT_SESSION_ID);
+        assert!(matches!(
+            cookies.verify_token(&token),
+            Err(SessionCookieError::Expired)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_token_from_headers() {
+        let cookies = SessionCookies::new(
+            test_pool().await,
+            SessionCookieKey::new("k1", *SECRET).unwrap(),
+        )
+        .cookie_name("sid");
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::COOKIE,
+            HeaderValue::from_static("theme=dark; sid=abc.def.ghi"),
+        );
+        assert_eq!(cookies.token_from_headers(&headers), Some("abc.def.ghi"));
+
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer tok"),
+        );
+        assert_eq!(cookies.token_from_headers(&headers), Some("tok"));
+        assert!(cookies
+            .issue(&did(), DEFAULT_SESSION_ID)
+            .starts_with("sid="));
+        assert!(cookies.clear().contains("Max-Age=0"));
+    }
+
+    #[test]
+    fn test_weak_key_rejected() {
+        assert!(matches!(
+            SessionCookieKey::new("k1", b"short".to_vec()),
+            Err(SessionCookieError::WeakKey)
+        ));
+        assert!(matches!(
+            SessionCookieKey::new("k.1", *SECRET),
+            Err(SessionCookieError::InvalidKeyId)
+        ));
+    }
+}
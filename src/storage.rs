@@ -1,37 +1,199 @@
 /// Storage impls to persis OAuth sessions if you are not using the memory stores
 /// https://github.com/bluesky-social/statusphere-example-app/blob/main/src/auth/storage.ts
-use crate::db::{AuthSession, AuthState};
-use async_sqlite::Pool;
+use crate::db::{AuthSession, AuthState, DEFAULT_SESSION_ID};
+use crate::device::{self, DeviceSessionStore};
+use crate::encryption::{self, EncryptionError, StorageEncryption};
+use crate::error::DbError;
+use async_sqlite::{rusqlite, Pool};
 use atrium_api::types::string::Did;
 use atrium_common::store::Store;
-use atrium_oauth::store::session::SessionStore;
+use async_trait::async_trait;
+use atrium_oauth::store::session::{Session, SessionStore};
 use atrium_oauth::store::state::StateStore;
 use serde::Serialize;
 use serde::de::DeserializeOwned;
 use std::fmt::Debug;
 use std::hash::Hash;
+use std::sync::Arc;
 use thiserror::Error;
 
+/// Errors of the SQLite stores. A missing session or state is not an error, the stores return
+/// `Ok(None)` for it as the [Store] contract expects.
 #[derive(Error, Debug)]
 pub enum SqliteStoreError {
-    #[error("Invalid session")]
-    InvalidSession,
-    #[error("No session found")]
-    NoSessionFound,
+    #[error("Stored value does not match the expected type: {0}")]
+    DeserializationError(serde_json::Error),
+    #[error("Corrupt row: {0}")]
+    CorruptRow(String),
+    #[error("State expired")]
+    StateExpired,
+    #[error("Session was changed or removed by another writer")]
+    Conflict,
     #[error("Database error: {0}")]
-    DatabaseError(async_sqlite::Error),
+    DatabaseError(DbError),
+    #[error("Encryption error: {0}")]
+    EncryptionError(#[from] EncryptionError),
+}
+
+/// How stale `last_used_at` may get before touching a session updates it
+const TOUCH_INTERVAL: chrono::Duration = chrono::Duration::minutes(1);
+
+/// Maps an error from reading a row, telling columns that cannot be decoded apart from
+/// failures of the database itself
+fn read_error(db_error: DbError) -> SqliteStoreError {
+    match db_error {
+        DbError::DatabaseError(async_sqlite::Error::Rusqlite(
+            e @ (rusqlite::Error::IntegralValueOutOfRange(..)
+            | rusqlite::Error::InvalidColumnType(..)
+            | rusqlite::Error::FromSqlConversionFailure(..)),
+        )) => SqliteStoreError::CorruptRow(e.to_string()),
+        db_error => {
+            log::error!("Database error: {db_error}");
+            SqliteStoreError::DatabaseError(db_error)
+        }
+    }
+}
+
+/// Encryption context of a session, binding it to its DID and device session
+fn session_context(did: &str, session_id: &str) -> String {
+    format!("auth_session:{did}:{session_id}")
+}
+
+/// Seals the session `value` of the device session `session_id` of `did` for storage
+pub(crate) fn seal_session(
+    encryption: Option<&StorageEncryption>,
+    did: &str,
+    session_id: &str,
+    value: String,
+) -> Result<String, EncryptionError> {
+    encryption::seal(encryption, &session_context(did, session_id), value)
+}
+
+/// Opens the stored session of the device session `session_id` of `did`. Sessions sealed before
+/// the context included the session id are bound to the DID only and still open.
+pub(crate) fn open_session(
+    encryption: Option<&StorageEncryption>,
+    did: &str,
+    session_id: &str,
+    stored: String,
+) -> Result<String, EncryptionError> {
+    match encryption::open(encryption, &session_context(did, session_id), stored.clone()) {
+        Err(EncryptionError::DecryptionFailed) => {
+            encryption::open(encryption, &format!("auth_session:{did}"), stored)
+        }
+        result => result,
+    }
+}
+
+/// Deserializes a stored value. Values that are not JSON at all are reported as corrupt, JSON
+/// of another shape as a deserialization error.
+fn deserialize<V: DeserializeOwned>(stored: &str) -> Result<V, SqliteStoreError> {
+    let value: serde_json::Value =
+        serde_json::from_str(stored).map_err(|e| SqliteStoreError::CorruptRow(e.to_string()))?;
+    serde_json::from_value(value).map_err(SqliteStoreError::DeserializationError)
 }
 
 ///Persistent session store in sqlite
+///
+/// Sessions are kept per device. Inside a [with_session_id](crate::device::with_session_id)
+/// scope the store reads and writes that device session of the DID. Outside of a scope it keeps
+/// atrium's DID-keyed contract: reads and writes use the DID's most recently updated session, or
+/// the default session if there is none, and deletes remove every session of the DID.
+///
+/// Every scoped write checks the version of the row in the same immediate transaction that
+/// writes it. It expects the version read or written earlier in that scope, and a write without
+/// one expects no row at all. So of two concurrent token refreshes, even on different
+/// instances, the later one fails with [SqliteStoreError::Conflict] instead of overwriting the
+/// rotated refresh token.
+///
+/// New sessions record the client of a surrounding
+/// [with_client_info](crate::device::with_client_info) scope. With an idle timeout, sessions
+/// not used for longer are deleted when they are read.
 impl SessionStore for SqliteSessionStore {}
 
 pub struct SqliteSessionStore {
     db_pool: Pool,
+    encryption: Option<Arc<StorageEncryption>>,
+    idle_timeout: Option<chrono::Duration>,
 }
 
 impl SqliteSessionStore {
     pub fn new(db: Pool) -> Self {
-        Self { db_pool: db }
+        Self {
+            db_pool: db,
+            encryption: None,
+            idle_timeout: None,
+        }
+    }
+
+    /// Treat sessions not used for `timeout` as signed out (default: sessions never idle out).
+    /// Sessions are used when [SessionCookies](crate::session::SessionCookies) verifies one of
+    /// their tokens or through [AuthSession::touch].
+    pub fn with_idle_timeout(mut self, timeout: chrono::Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Encrypt sessions before they are written to the database
+    pub fn with_encryption(mut self, encryption: Arc<StorageEncryption>) -> Self {
+        self.encryption = Some(encryption);
+        self
+    }
+}
+
+#[async_trait]
+impl DeviceSessionStore for SqliteSessionStore {
+    type Error = SqliteStoreError;
+
+    async fn device_session(
+        &self,
+        did: &Did,
+        session_id: &str,
+    ) -> Result<Option<Session>, Self::Error> {
+        let auth_session = AuthSession::get(&self.db_pool, did.to_string(), session_id.to_string())
+            .await
+            .map_err(read_error)?;
+        let Some(auth_session) = auth_session else {
+            return Ok(None);
+        };
+        let session = open_session(
+            self.encryption.as_deref(),
+            &auth_session.key,
+            &auth_session.session_id,
+            auth_session.session,
+        )?;
+        deserialize(&session).map(Some)
+    }
+
+    async fn device_session_ids(&self, did: &Did) -> Result<Vec<String>, Self::Error> {
+        let sessions = AuthSession::list_for_did(&self.db_pool, did.to_string())
+            .await
+            .map_err(read_error)?;
+        Ok(sessions.into_iter().map(|session| session.session_id).collect())
+    }
+
+    async fn delete_device_session(&self, did: &Did, session_id: &str) -> Result<(), Self::Error> {
+        AuthSession::delete(&self.db_pool, did.to_string(), session_id.to_string())
+            .await
+            .map_err(SqliteStoreError::DatabaseError)
+    }
+
+    /// Updates `last_used_at` at most once a minute, without decrypting the session
+    async fn touch_device_session(&self, did: &Did, session_id: &str) -> Result<bool, Self::Error> {
+        let auth_session = AuthSession::get(&self.db_pool, did.to_string(), session_id.to_string())
+            .await
+            .map_err(read_error)?;
+        let Some(auth_session) = auth_session else {
+            return Ok(false);
+        };
+        if chrono::Utc::now() - auth_session.last_used_at > TOUCH_INTERVAL {
+            if let Err(db_error) =
+                AuthSession::touch(&self.db_pool, auth_session.key, auth_session.session_id).await
+            {
+                log::warn!("Failed to record use of session: {db_error}");
+            }
+        }
+        Ok(true)
     }
 }
 
@@ -43,36 +205,95 @@ where
     type Error = SqliteStoreError;
     async fn get(&self, key: &K) -> Result<Option<V>, Self::Error> {
         let did = key.as_ref().to_string();
-        match AuthSession::get_by_did(&self.db_pool, did).await {
-            Ok(Some(auth_session)) => {
-                let deserialized_session: V = serde_json::from_str(&auth_session.session)
-                    .map_err(|_| SqliteStoreError::InvalidSession)?;
-                Ok(Some(deserialized_session))
-            }
-            Ok(None) => Err(SqliteStoreError::NoSessionFound),
-            Err(db_error) => {
-                log::error!("Database error: {db_error}");
-                Err(SqliteStoreError::DatabaseError(db_error))
-            }
+        let auth_session = match device::current_session_id() {
+            Some(session_id) => AuthSession::get(&self.db_pool, did, session_id).await,
+            None => AuthSession::get_by_did(&self.db_pool, did).await,
         }
+        .map_err(read_error)?;
+        let Some(auth_session) = auth_session else {
+            return Ok(None);
+        };
+        if self
+            .idle_timeout
+            .is_some_and(|timeout| auth_session.is_idle(timeout))
+        {
+            log::debug!(
+                "Removing idle session {} of {}",
+                auth_session.session_id,
+                auth_session.key
+            );
+            AuthSession::delete(&self.db_pool, auth_session.key, auth_session.session_id)
+                .await
+                .map_err(SqliteStoreError::DatabaseError)?;
+            return Ok(None);
+        }
+        device::record_session_version(&auth_session.key, auth_session.version);
+        let session = open_session(
+            self.encryption.as_deref(),
+            &auth_session.key,
+            &auth_session.session_id,
+            auth_session.session,
+        )?;
+        deserialize(&session).map(Some)
     }
 
     async fn set(&self, key: K, value: V) -> Result<(), Self::Error> {
         let did = key.as_ref().to_string();
-        let auth_session = AuthSession::new(did, value);
-        auth_session
-            .save_or_update(&self.db_pool)
+        let scope = device::current_session_id();
+        // Outside of a scope the write replaces the session an unscoped read returns
+        let session_id = match &scope {
+            Some(session_id) => session_id.clone(),
+            None => AuthSession::get_by_did(&self.db_pool, did.clone())
+                .await
+                .map_err(read_error)?
+                .map_or_else(|| DEFAULT_SESSION_ID.to_string(), |active| active.session_id),
+        };
+        let mut auth_session = AuthSession::new(did, value)
+            .map_err(SqliteStoreError::DatabaseError)?
+            .with_session_id(session_id);
+        if let Some(client_info) = device::current_client_info() {
+            auth_session = auth_session.with_client_info(client_info);
+        }
+        auth_session.session = seal_session(
+            self.encryption.as_deref(),
+            &auth_session.key,
+            &auth_session.session_id,
+            auth_session.session,
+        )?;
+        if scope.is_none() {
+            return auth_session
+                .save_or_update(&self.db_pool)
+                .await
+                .map_err(SqliteStoreError::DatabaseError);
+        }
+        // Inside a scope a write without an earlier read expects no row at all
+        let version = device::take_session_version(&auth_session.key).unwrap_or(0);
+        auth_session.version = version;
+        let swapped = auth_session
+            .compare_and_swap(&self.db_pool)
             .await
             .map_err(SqliteStoreError::DatabaseError)?;
+        if !swapped {
+            log::warn!(
+                "Discarding write to session {} of {}: changed by another writer",
+                auth_session.session_id,
+                auth_session.key
+            );
+            return Err(SqliteStoreError::Conflict);
+        }
+        device::record_session_version(&auth_session.key, version + 1);
         Ok(())
     }
 
     async fn del(&self, _key: &K) -> Result<(), Self::Error> {
         let did = _key.as_ref().to_string();
-        AuthSession::delete_by_did(&self.db_pool, did)
-            .await
-            .map_err(SqliteStoreError::DatabaseError)?;
-        Ok(())
+        // Outside of a scope the DID is signed out on every device, as the DID-keyed contract
+        // expects
+        match device::current_session_id() {
+            Some(session_id) => AuthSession::delete(&self.db_pool, did, session_id).await,
+            None => AuthSession::delete_by_did(&self.db_pool, did).await,
+        }
+        .map_err(SqliteStoreError::DatabaseError)
     }
 
     async fn clear(&self) -> Result<(), Self::Error> {
@@ -83,16 +304,37 @@ where
     }
 }
 
+/// Default lifetime of an authorization state before it is rejected
+pub const DEFAULT_STATE_TTL: chrono::Duration = chrono::Duration::hours(1);
+
 ///Persistent session state in sqlite
 impl StateStore for SqliteStateStore {}
 
 pub struct SqliteStateStore {
     db_pool: Pool,
+    ttl: chrono::Duration,
+    encryption: Option<Arc<StorageEncryption>>,
 }
 
 impl SqliteStateStore {
     pub fn new(db: Pool) -> Self {
-        Self { db_pool: db }
+        Self {
+            db_pool: db,
+            ttl: DEFAULT_STATE_TTL,
+            encryption: None,
+        }
+    }
+
+    /// Encrypt states before they are written to the database
+    pub fn with_encryption(mut self, encryption: Arc<StorageEncryption>) -> Self {
+        self.encryption = Some(encryption);
+        self
+    }
+
+    /// Set how long a state stays valid after the authorization request (default: 1 hour)
+    pub fn with_ttl(mut self, ttl: chrono::Duration) -> Self {
+        self.ttl = ttl;
+        self
     }
 }
 
@@ -104,23 +346,29 @@ where
     type Error = SqliteStoreError;
     async fn get(&self, key: &K) -> Result<Option<V>, Self::Error> {
         let key = key.as_ref().to_string();
-        match AuthState::get_by_key(&self.db_pool, key).await {
-            Ok(Some(auth_state)) => {
-                let deserialized_state: V = serde_json::from_str(&auth_state.state)
-                    .map_err(|_| SqliteStoreError::InvalidSession)?;
-                Ok(Some(deserialized_state))
-            }
-            Ok(None) => Err(SqliteStoreError::NoSessionFound),
-            Err(db_error) => {
-                log::error!("Database error: {db_error}");
-                Err(SqliteStoreError::DatabaseError(db_error))
-            }
+        let auth_state = AuthState::get_by_key(&self.db_pool, key)
+            .await
+            .map_err(read_error)?;
+        let Some(auth_state) = auth_state else {
+            return Ok(None);
+        };
+        if auth_state.is_expired(self.ttl) {
+            AuthState::delete_by_key(&self.db_pool, auth_state.key)
+                .await
+                .map_err(SqliteStoreError::DatabaseError)?;
+            return Err(SqliteStoreError::StateExpired);
         }
+        let context = format!("auth_state:{}", auth_state.key);
+        let state = encryption::open(self.encryption.as_deref(), &context, auth_state.state)?;
+        deserialize(&state).map(Some)
     }
 
     async fn set(&self, key: K, value: V) -> Result<(), Self::Error> {
         let did = key.as_ref().to_string();
-        let auth_state = AuthState::new(did, value);
+        let mut auth_state = AuthState::new(did, value).map_err(SqliteStoreError::DatabaseError)?;
+        let context = format!("auth_state:{}", auth_state.key);
+        auth_state.state =
+            encryption::seal(self.encryption.as_deref(), &context, auth_state.state)?;
         auth_state
             .save_or_update(&self.db_pool)
             .await
@@ -143,3 +391,337 @@ where
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::create_oauth_tables;
+
+    #[tokio::test]
+    async fn test_state_store_rejects_expired_state() {
+        let pool = async_sqlite::PoolBuilder::new()
+            .num_conns(1)
+            .open()
+            .await
+            .unwrap();
+        create_oauth_tables(&pool).await.unwrap();
+        let store = SqliteStateStore::new(pool.clone()).with_ttl(chrono::Duration::minutes(10));
+
+        let mut state = AuthState::new("state-key".to_string(), "value").unwrap();
+        state.created_at = chrono::Utc::now() - chrono::Duration::minutes(11);
+        state.save_or_update(&pool).await.unwrap();
+
+        let result: Result<Option<String>, _> = store.get(&"state-key".to_string()).await;
+        assert!(matches!(result, Err(SqliteStoreError::StateExpired)));
+        assert!(AuthState::get_by_key(&pool, "state-key".to_string())
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_session_store_encrypts_at_rest() {
+        let pool = async_sqlite::PoolBuilder::new()
+            .num_conns(1)
+            .open()
+            .await
+            .unwrap();
+        create_oauth_tables(&pool).await.unwrap();
+        let encryption = Arc::new(StorageEncryption::new(
+            crate::encryption::EncryptionKey::generate("k1").unwrap(),
+        ));
+        let store = SqliteSessionStore::new(pool.clone()).with_encryption(encryption);
+        let did = "did:plc:abc123".to_string();
+
+        // Sessions written before encryption was enabled stay readable
+        AuthSession::new(did.clone(), "legacy")
+            .unwrap()
+            .save_or_update(&pool)
+            .await
+            .unwrap();
+        let legacy: Option<String> = store.get(&did).await.unwrap();
+        assert_eq!(legacy.as_deref(), Some("legacy"));
+
+        Store::<String, String>::set(&store, did.clone(), "refresh-token".to_string())
+            .await
+            .unwrap();
+        let row = AuthSession::get_by_did(&pool, did.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(row.session.starts_with("enc1.k1."));
+        assert!(!row.session.contains("refresh-token"));
+        let value: Option<String> = store.get(&did).await.unwrap();
+        assert_eq!(value.as_deref(), Some("refresh-token"));
+
+        let unkeyed: Result<Option<String>, _> = SqliteSessionStore::new(pool).get(&did).await;
+        assert!(matches!(
+            unkeyed,
+            Err(SqliteStoreError::EncryptionError(EncryptionError::NotConfigured))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_session_store_binds_encrypted_sessions_to_their_device() {
+        let pool = crate::test_util::sqlite_test_pool().await;
+        let encryption = Arc::new(StorageEncryption::new(
+            crate::encryption::EncryptionKey::generate("k1").unwrap(),
+        ));
+        let store = SqliteSessionStore::new(pool.clone()).with_encryption(encryption.clone());
+        let did = "did:plc:abc123".to_string();
+        let set = |session_id, value: &str| {
+            let value = value.to_string();
+            device::with_session_id(
+                session_id,
+                Store::<String, String>::set(&store, did.clone(), value),
+            )
+        };
+        set("laptop", "laptop-token").await.unwrap();
+        set("phone", "phone-token").await.unwrap();
+
+        // A ciphertext copied to another device session of the DID does not open there
+        let laptop = AuthSession::get(&pool, did.clone(), "laptop".to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        let mut phone = AuthSession::get(&pool, did.clone(), "phone".to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        phone.session = laptop.session;
+        phone.save_or_update(&pool).await.unwrap();
+        let replayed: Result<Option<String>, _> =
+            device::with_session_id("phone", store.get(&did)).await;
+        assert!(matches!(
+            replayed,
+            Err(SqliteStoreError::EncryptionError(EncryptionError::DecryptionFailed))
+        ));
+
+        // Sessions sealed before the context included the session id still open
+        let mut legacy = AuthSession::new(did.clone(), "")
+            .unwrap()
+            .with_session_id("tablet");
+        legacy.session = encryption
+            .encrypt(&format!("auth_session:{did}"), "\"tablet-token\"")
+            .unwrap();
+        legacy.save_or_update(&pool).await.unwrap();
+        let value: Option<String> = device::with_session_id("tablet", store.get(&did))
+            .await
+            .unwrap();
+        assert_eq!(value.as_deref(), Some("tablet-token"));
+    }
+
+    #[tokio::test]
+    async fn test_session_store_keeps_sessions_per_device() {
+        let pool = async_sqlite::PoolBuilder::new()
+            .num_conns(1)
+            .open()
+            .await
+            .unwrap();
+        create_oauth_tables(&pool).await.unwrap();
+        let store = SqliteSessionStore::new(pool.clone());
+        let did = "did:plc:abc123".to_string();
+
+        for device in ["laptop", "phone"] {
+            device::with_session_id(
+                device,
+                Store::<String, String>::set(&store, did.clone(), format!("{device}-tokens")),
+            )
+            .await
+            .unwrap();
+        }
+        let laptop: Option<String> = device::with_session_id("laptop", store.get(&did))
+            .await
+            .unwrap();
+        assert_eq!(laptop.as_deref(), Some("laptop-tokens"));
+
+        // Without a scope the most recently updated session is used
+        let active: Option<String> = store.get(&did).await.unwrap();
+        assert_eq!(active.as_deref(), Some("phone-tokens"));
+
+        device::with_session_id("phone", Store::<String, String>::del(&store, &did))
+            .await
+            .unwrap();
+        let remaining = AuthSession::list_for_did(&pool, did).await.unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].session_id, "laptop");
+    }
+
+    #[tokio::test]
+    async fn test_unscoped_calls_keep_the_did_keyed_contract() {
+        let pool = crate::test_util::sqlite_test_pool().await;
+        let store = SqliteSessionStore::new(pool.clone());
+        let did = "did:plc:abc123".to_string();
+
+        // Without any session, unscoped writes replace one default session
+        Store::<String, String>::set(&store, did.clone(), "a".to_string())
+            .await
+            .unwrap();
+        Store::<String, String>::set(&store, did.clone(), "b".to_string())
+            .await
+            .unwrap();
+        let sessions = AuthSession::list_for_did(&pool, did.clone()).await.unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].session_id, DEFAULT_SESSION_ID);
+        Store::<String, String>::del(&store, &did).await.unwrap();
+        let deleted: Option<String> = store.get(&did).await.unwrap();
+        assert_eq!(deleted, None);
+
+        // With device sessions, an unscoped write updates the one an unscoped read returns
+        for device in ["laptop", "phone"] {
+            device::with_session_id(
+                device,
+                Store::<String, String>::set(&store, did.clone(), format!("{device}-tokens")),
+            )
+            .await
+            .unwrap();
+        }
+        Store::<String, String>::set(&store, did.clone(), "refreshed".to_string())
+            .await
+            .unwrap();
+        let phone: Option<String> = device::with_session_id("phone", store.get(&did))
+            .await
+            .unwrap();
+        assert_eq!(phone.as_deref(), Some("refreshed"));
+        let laptop: Option<String> = device::with_session_id("laptop", store.get(&did))
+            .await
+            .unwrap();
+        assert_eq!(laptop.as_deref(), Some("laptop-tokens"));
+        assert_eq!(AuthSession::list_for_did(&pool, did.clone()).await.unwrap().len(), 2);
+
+        Store::<String, String>::del(&store, &did).await.unwrap();
+        assert!(AuthSession::list_for_did(&pool, did).await.unwrap().is_empty());
+    }
+
+    /// Reads the default device session of `did`, waits until the other reader read it too and
+    /// writes `tokens`, like a token refresh
+    async fn refresh(
+        store: &SqliteSessionStore,
+        did: &str,
+        both_read: &tokio::sync::Barrier,
+        tokens: &str,
+    ) -> Result<(), SqliteStoreError> {
+        device::with_session_id(DEFAULT_SESSION_ID, async {
+            let _: Option<String> = store.get(&did.to_string()).await?;
+            both_read.wait().await;
+            Store::<String, String>::set(store, did.to_string(), tokens.to_string()).await
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_session_store_rejects_concurrent_overwrite() {
+        let pool = async_sqlite::PoolBuilder::new()
+            .num_conns(1)
+            .open()
+            .await
+            .unwrap();
+        create_oauth_tables(&pool).await.unwrap();
+        let did = "did:plc:abc123".to_string();
+        AuthSession::new(did.clone(), "initial")
+            .unwrap()
+            .save_or_update(&pool)
+            .await
+            .unwrap();
+
+        // Two instances refresh the same device session concurrently: both read it before
+        // either writes
+        let first = SqliteSessionStore::new(pool.clone());
+        let second = SqliteSessionStore::new(pool.clone());
+        let both_read = tokio::sync::Barrier::new(2);
+        let (rotated, stale) = tokio::join!(
+            refresh(&first, &did, &both_read, "rotated"),
+            refresh(&second, &did, &both_read, "stale"),
+        );
+        let mut results = [rotated, stale];
+        results.sort_by_key(Result::is_err);
+        assert!(results[0].is_ok());
+        assert!(matches!(results[1], Err(SqliteStoreError::Conflict)));
+
+        // A scope that wrote the session may write it again, a scope that did not read it may not
+        let stored: Option<String> = device::with_session_id(DEFAULT_SESSION_ID, async {
+            let _: Option<String> = second.get(&did).await.unwrap();
+            Store::<String, String>::set(&second, did.clone(), "again".to_string())
+                .await
+                .unwrap();
+            Store::<String, String>::set(&second, did.clone(), "twice".to_string())
+                .await
+                .unwrap();
+            second.get(&did).await.unwrap()
+        })
+        .await;
+        assert_eq!(stored.as_deref(), Some("twice"));
+        let unread = device::with_session_id(
+            DEFAULT_SESSION_ID,
+            Store::<String, String>::set(&first, did.clone(), "blind".to_string()),
+        )
+        .await;
+        assert!(matches!(unread, Err(SqliteStoreError::Conflict)));
+    }
+
+    #[tokio::test]
+    async fn test_session_store_reports_unreadable_rows() {
+        let pool = async_sqlite::PoolBuilder::new()
+            .num_conns(1)
+            .open()
+            .await
+            .unwrap();
+        create_oauth_tables(&pool).await.unwrap();
+        let store = SqliteSessionStore::new(pool.clone());
+
+        let mut row = AuthSession::new("did:plc:corrupt".to_string(), "").unwrap();
+        row.session = "not json".to_string();
+        row.save_or_update(&pool).await.unwrap();
+        let corrupt: Result<Option<String>, _> = store.get(&row.key).await;
+        assert!(matches!(corrupt, Err(SqliteStoreError::CorruptRow(_))));
+
+        AuthSession::new("did:plc:mismatch".to_string(), 42)
+            .unwrap()
+            .save_or_update(&pool)
+            .await
+            .unwrap();
+        let mismatch: Result<Option<String>, _> = store.get(&"did:plc:mismatch".to_string()).await;
+        assert!(matches!(mismatch, Err(SqliteStoreError::DeserializationError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_session_store_records_client_and_expires_idle_sessions() {
+        let pool = async_sqlite::PoolBuilder::new()
+            .num_conns(1)
+            .open()
+            .await
+            .unwrap();
+        create_oauth_tables(&pool).await.unwrap();
+        let store =
+            SqliteSessionStore::new(pool.clone()).with_idle_timeout(chrono::Duration::days(14));
+        let did = "did:plc:abc123".to_string();
+
+        let client_info = device::ClientInfo {
+            user_agent: Some("Firefox".to_string()),
+            ip_address: Some("203.0.113.1".to_string()),
+        };
+        device::with_client_info(
+            client_info.clone(),
+            Store::<String, String>::set(&store, did.clone(), "tokens".to_string()),
+        )
+        .await
+        .unwrap();
+        let stored = AuthSession::get_by_did(&pool, did.clone()).await.unwrap().unwrap();
+        assert_eq!(stored.user_agent, client_info.user_agent);
+        assert_eq!(stored.ip_address, client_info.ip_address);
+        let active: Option<String> = store.get(&did).await.unwrap();
+        assert_eq!(active.as_deref(), Some("tokens"));
+
+        pool.conn(|conn| {
+            conn.execute(
+                "UPDATE auth_session SET last_used_at = ?1",
+                [(chrono::Utc::now() - chrono::Duration::days(15)).timestamp()],
+            )
+        })
+        .await
+        .unwrap();
+        let idle: Option<String> = store.get(&did).await.unwrap();
+        assert_eq!(idle, None);
+        assert!(AuthSession::list_for_did(&pool, did).await.unwrap().is_empty());
+    }
+}
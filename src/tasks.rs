@@ -0,0 +1,383 @@
+/// Background maintenance tasks for the OAuth tables
+use crate::{
+    db::{AuthSession, AuthState},
+    device::SessionLock,
+    encryption::StorageEncryption,
+    error::DbError,
+    storage::{open_session, seal_session},
+    token::{TokenClient, TokenError},
+};
+use async_sqlite::Pool;
+use atrium_api::types::string::Did;
+use atrium_oauth::store::session::Session;
+use chrono::{DateTime, Utc};
+use std::sync::Arc;
+use tokio::{task::JoinHandle, time::MissedTickBehavior};
+
+/// Spawns a task that deletes authorization states older than `ttl` every `interval`.
+///
+/// Abandoned login attempts otherwise leave their PKCE verifier and DPoP key in `auth_state`
+/// forever. The task runs until the returned handle is aborted or the runtime shuts down.
+pub fn spawn_state_sweeper(
+    pool: Pool,
+    ttl: chrono::Duration,
+    interval: std::time::Duration,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            match AuthState::delete_created_before(&pool, Utc::now() - ttl).await {
+                Ok(0) => {}
+                Ok(removed) => log::debug!("Removed {removed} expired OAuth states"),
+                Err(e) => log::error!("Failed to remove expired OAuth states: {e}"),
+            }
+        }
+    })
+}
+
+/// Default time before its access token expires at which the session refresher picks up a session
+pub const DEFAULT_REFRESH_MARGIN: chrono::Duration = chrono::Duration::minutes(1);
+
+/// Called with the DID, device session id and error of a session whose tokens could not be
+/// refreshed, e.g. to flag users who have to log in again
+pub type RefreshFailureHandler = Arc<dyn Fn(&Did, &str, &TokenError) + Send + Sync>;
+
+/// Access token expiry of a stored session, if it has a refresh token to renew it with
+fn refreshable_expiry(session: &Session) -> Option<DateTime<Utc>> {
+    let token_set = &session.token_set;
+    token_set.refresh_token.as_ref()?;
+    token_set
+        .expires_at
+        .as_ref()
+        .map(|expires_at| expires_at.as_ref().with_timezone(&Utc))
+}
+
+/// Decrypts and parses a stored session
+fn open_stored(
+    stored: &AuthSession,
+    encryption: Option<&StorageEncryption>,
+) -> Result<Session, String> {
+    let session = open_session(
+        encryption,
+        &stored.key,
+        &stored.session_id,
+        stored.session.clone(),
+    )
+    .map_err(|e| e.to_string())?;
+    serde_json::from_str(&session).map_err(|e| e.to_string())
+}
+
+/// Refreshes every session whose access token expires within `margin` at its authorization
+/// server, returning how many were refreshed.
+///
+/// Sessions in use by a [with_session_id](crate::device::with_session_id) scope are skipped, as
+/// atrium may be refreshing them lazily with the same refresh token, and picked up on a later run.
+/// Scopes entered while a session is refreshed wait for the refresh and read the new tokens.
+async fn refresh_expiring_sessions(
+    tokens: &TokenClient,
+    pool: &Pool,
+    encryption: Option<&StorageEncryption>,
+    margin: chrono::Duration,
+    on_failure: Option<&RefreshFailureHandler>,
+) -> Result<usize, DbError> {
+    let cutoff = Utc::now() + margin;
+    let expiring = |session: &Session| {
+        refreshable_expiry(session).is_some_and(|expires_at| expires_at <= cutoff)
+    };
+    let mut refreshed = 0;
+    for listed in AuthSession::list_all(pool).await? {
+        match open_stored(&listed, encryption) {
+            Ok(session) if expiring(&session) => {}
+            Ok(_) => continue,
+            Err(e) => {
+                log::warn!(
+                    "Skipping refresh of session {} of {}: {e}",
+                    listed.session_id,
+                    listed.key
+                );
+                continue;
+            }
+        }
+        let Some(_refreshing) = SessionLock::try_refreshing(&listed.session_id) else {
+            log::debug!(
+                "Skipping refresh of session {} of {}: in use",
+                listed.session_id,
+                listed.key
+            );
+            continue;
+        };
+        // A request may have refreshed the session since it was listed
+        let Some(mut stored) = AuthSession::get(pool, listed.key, listed.session_id).await? else {
+            continue;
+        };
+        let session = match open_stored(&stored, encryption) {
+            Ok(session) if expiring(&session) => session,
+            _ => continue,
+        };
+
+        let did = session.token_set.sub.clone();
+        let token_set = match tokens.refresh(&session).await {
+            Ok(token_set) => token_set,
+            Err(e) => {
+                log::warn!(
+                    "Failed to refresh session {} of {}: {e}",
+                    stored.session_id,
+                    did.as_str()
+                );
+                if let Some(on_failure) = on_failure {
+                    on_failure(&did, &stored.session_id, &e);
+                }
+                continue;
+            }
+        };
+        let session = serde_json::to_string(&Session { token_set, ..session })?;
+        stored.session = match seal_session(encryption, &stored.key, &stored.session_id, session) {
+            Ok(session) => session,
+            Err(e) => {
+                log::error!(
+                    "Failed to store refreshed session {} of {}: {e}",
+                    stored.session_id,
+                    did.as_str()
+                );
+                continue;
+            }
+        };
+        stored.updated_at = Utc::now();
+        // The version read above guards against overwriting a session that was refreshed or
+        // signed out while the request was in flight
+        if stored.compare_and_swap(pool).await? {
+            refreshed += 1;
+        } else {
+            log::warn!(
+                "Discarding refreshed tokens of session {} of {}: changed by another writer",
+                stored.session_id,
+                did.as_str()
+            );
+        }
+    }
+    Ok(refreshed)
+}
+
+/// Spawns a task that refreshes stored sessions every `interval` before their access token
+/// expires.
+///
+/// atrium only refreshes tokens lazily, once they expired and an agent makes a request, so the
+/// refresh token of an idle user eventually expires too. Every session with a refresh token
+/// whose access token expires within `margin` is refreshed at its authorization server through
+/// `tokens`, and the new token set replaces the stored one unless the session changed in the
+/// meantime. Sessions in use by a request are left to a later run. Sessions that fail to refresh
+/// are reported to `on_failure`. The task runs until
+/// the returned handle is aborted or the runtime shuts down.
+pub fn spawn_session_refresher(
+    tokens: TokenClient,
+    pool: Pool,
+    encryption: Option<Arc<StorageEncryption>>,
+    margin: chrono::Duration,
+    interval: std::time::Duration,
+    on_failure: Option<RefreshFailureHandler>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            let result = refresh_expiring_sessions(
+                &tokens,
+                &pool,
+                encryption.as_deref(),
+                margin,
+                on_failure.as_ref(),
+            )
+            .await;
+            match result {
+                Ok(0) => {}
+                Ok(refreshed) => log::debug!("Refreshed {refreshed} OAuth sessions"),
+                Err(e) => log::error!("Failed to refresh OAuth sessions: {e}"),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::create_oauth_tables, encryption::EncryptionKey,
+        token::tests::MockAuthorizationServer,
+    };
+    use atrium_oauth::OAuthClientMetadata;
+    use std::{sync::Mutex, time::Duration};
+
+    #[tokio::test]
+    async fn test_state_sweeper_removes_expired_states() {
+        let pool = async_sqlite::PoolBuilder::new()
+            .num_conns(1)
+            .open()
+            .await
+            .unwrap();
+        create_oauth_tables(&pool).await.unwrap();
+
+        let mut stale = AuthState::new("stale".to_string(), "{}").unwrap();
+        stale.created_at = Utc::now() - chrono::Duration::hours(2);
+        stale.save_or_update(&pool).await.unwrap();
+        AuthState::new("fresh".to_string(), "{}")
+            .unwrap()
+            .save_or_update(&pool)
+            .await
+            .unwrap();
+
+        let handle = spawn_state_sweeper(
+            pool.clone(),
+            chrono::Duration::hours(1),
+            Duration::from_millis(10),
+        );
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        handle.abort();
+
+        assert!(AuthState::get_by_key(&pool, "stale".to_string())
+            .await
+            .unwrap()
+            .is_none());
+        assert!(AuthState::get_by_key(&pool, "fresh".to_string())
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_session_refresher_refreshes_expiring_sessions() {
+        let pool = async_sqlite::PoolBuilder::new()
+            .num_conns(1)
+            .open()
+            .await
+            .unwrap();
+        create_oauth_tables(&pool).await.unwrap();
+        let server = MockAuthorizationServer::spawn().await;
+        let encryption = Arc::new(StorageEncryption::new(EncryptionKey::generate("k1").unwrap()));
+
+        let did = "did:plc:abc123".to_string();
+        for (session_id, expires_in, refresh_token) in [
+            ("expired", -5, "refresh-0"),
+            ("revoked", -5, "revoked"),
+            ("fresh", 60, "refresh-0"),
+        ] {
+            let mut session = server.session(expires_in);
+            session.token_set.refresh_token = Some(refresh_token.to_string());
+            let sealed = seal_session(
+                Some(&encryption),
+                &did,
+                session_id,
+                serde_json::to_string(&session).unwrap(),
+            )
+            .unwrap();
+            let mut stored = AuthSession::new(did.clone(), "")
+                .unwrap()
+                .with_session_id(session_id);
+            stored.session = sealed;
+            stored.save_or_update(&pool).await.unwrap();
+        }
+
+        let failures = Arc::new(Mutex::new(Vec::new()));
+        let reported = failures.clone();
+        let tokens = TokenClient::new(&OAuthClientMetadata {
+            client_id: "http://localhost".to_string(),
+            ..Default::default()
+        });
+        let handle = spawn_session_refresher(
+            tokens,
+            pool.clone(),
+            Some(encryption.clone()),
+            DEFAULT_REFRESH_MARGIN,
+            Duration::from_secs(3600),
+            Some(Arc::new(move |did: &Did, session_id: &str, _: &TokenError| {
+                reported
+                    .lock()
+                    .unwrap()
+                    .push((did.to_string(), session_id.to_string()));
+            })),
+        );
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        handle.abort();
+
+        assert_eq!(
+            *failures.lock().unwrap(),
+            vec![(did.clone(), "revoked".to_string())]
+        );
+        let stored_session = |session_id: &'static str| {
+            let (pool, did, encryption) = (pool.clone(), did.clone(), encryption.clone());
+            async move {
+                let stored = AuthSession::get(&pool, did.clone(), session_id.to_string())
+                    .await
+                    .unwrap()
+                    .unwrap();
+                let session =
+                    open_session(Some(&encryption), &did, session_id, stored.session).unwrap();
+                let session: Session = serde_json::from_str(&session).unwrap();
+                (stored.version, session.token_set)
+            }
+        };
+        let (version, token_set) = stored_session("expired").await;
+        assert_eq!(version, 2);
+        assert_eq!(token_set.access_token, "access-1");
+        assert_eq!(token_set.refresh_token.as_deref(), Some("refresh-1"));
+        assert!(refreshable_expiry(&Session {
+            dpop_key: server.session(0).dpop_key,
+            token_set,
+        })
+        .is_some_and(|expires_at| expires_at > Utc::now() + chrono::Duration::minutes(30)));
+
+        let (version, token_set) = stored_session("fresh").await;
+        assert_eq!(version, 1);
+        assert_eq!(token_set.access_token, "access-0");
+        assert_eq!(server.token_requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_session_refresher_skips_sessions_refreshed_by_requests() {
+        use crate::storage::SqliteSessionStore;
+        use atrium_common::store::Store;
+        use tokio::sync::oneshot;
+
+        let pool = crate::test_util::sqlite_test_pool().await;
+        let server = MockAuthorizationServer::spawn().await;
+        let store = SqliteSessionStore::new(pool.clone());
+        let session = server.session(-5);
+        let did = session.token_set.sub.clone();
+        crate::device::with_session_id("laptop", store.set(did.clone(), session))
+            .await
+            .unwrap();
+        let tokens = crate::token::tests::public_client();
+
+        // A request refreshes its expired session like atrium does, while the refresher runs
+        let (read_tx, read_rx) = oneshot::channel();
+        let (done_tx, done_rx) = oneshot::channel();
+        let request = crate::device::with_session_id("laptop", async {
+            let session = store.get(&did).await.unwrap().unwrap();
+            read_tx.send(()).unwrap();
+            done_rx.await.unwrap();
+            let token_set = tokens.refresh(&session).await.unwrap();
+            store.set(did.clone(), Session { token_set, ..session }).await
+        });
+        let refresher = async {
+            read_rx.await.unwrap();
+            let refreshed =
+                refresh_expiring_sessions(&tokens, &pool, None, DEFAULT_REFRESH_MARGIN, None).await;
+            done_tx.send(()).unwrap();
+            refreshed
+        };
+        let (stored, refreshed) = tokio::join!(request, refresher);
+        stored.unwrap();
+        assert_eq!(refreshed.unwrap(), 0);
+
+        let requests = server.token_requests.lock().unwrap().clone();
+        assert_eq!(requests.len(), 1, "the refresh token must be used once");
+        assert_eq!(requests[0]["refresh_token"], "refresh-0");
+        let stored: Session = crate::device::with_session_id("laptop", store.get(&did))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.token_set.refresh_token.as_deref(), Some("refresh-1"));
+    }
+}
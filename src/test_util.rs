@@ -0,0 +1,283 @@
+/// Test support for applications and store implementations
+///
+/// [StoreConformance] checks that a [Store] behaves the way atrium expects from session and
+/// state stores. Enable the `test-util` feature in `dev-dependencies` to run it against your own
+/// stores:
+///
+/// ```ignore
+/// #[tokio::test]
+/// async fn my_store_conforms() {
+///     StoreConformance::new().run(&MyStore::connect().await).await;
+/// }
+/// ```
+use crate::db::{create_oauth_tables, open_in_memory_database};
+use async_sqlite::Pool;
+use atrium_common::store::Store;
+use std::fmt::Debug;
+
+/// Default size of the value written by the large payload check
+pub const DEFAULT_LARGE_VALUE_BYTES: usize = 1024 * 1024;
+
+/// Names of the keys written by the unicode key check
+const UNICODE_KEY_NAMES: [&str; 4] = ["ключ", "鍵", "🔑", "cafe\u{301}"];
+
+/// Opens an in-memory SQLite database with the OAuth tables
+pub async fn sqlite_test_pool() -> Pool {
+    let pool = open_in_memory_database()
+        .await
+        .expect("failed to open in-memory SQLite database");
+    create_oauth_tables(&pool)
+        .await
+        .expect("failed to create OAuth tables");
+    pool
+}
+
+/// A conformance suite for `Store<String, String>` implementations.
+/// Every check panics with a description of the violated expectation.
+pub struct StoreConformance {
+    key_prefix: String,
+    unicode_keys: bool,
+    large_value_bytes: usize,
+}
+
+impl StoreConformance {
+    /// Creates a suite running every check
+    pub fn new() -> Self {
+        Self {
+            key_prefix: "did:plc:".to_string(),
+            unicode_keys: true,
+            large_value_bytes: DEFAULT_LARGE_VALUE_BYTES,
+        }
+    }
+
+    /// Set the prefix of every key (default: `did:plc:`, as session stores are keyed by DID)
+    pub fn key_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.key_prefix = prefix.into();
+        self
+    }
+
+    /// Skip the unicode key check, e.g. for stores that only accept DIDs as keys
+    pub fn skip_unicode_keys(mut self) -> Self {
+        self.unicode_keys = false;
+        self
+    }
+
+    /// Set the size of the value written by the large payload check (default: 1 MiB)
+    pub fn large_value_bytes(mut self, bytes: usize) -> Self {
+        self.large_value_bytes = bytes;
+        self
+    }
+
+    fn key(&self, name: &str) -> String {
+        format!("{}{name}", self.key_prefix)
+    }
+
+    /// Runs every check against `store`, clearing it in between
+    pub async fn run<S>(&self, store: &S)
+    where
+        S: Store<String, String>,
+        S::Error: Debug,
+    {
+        self.check_get_set_del(store).await;
+        self.check_overwrite(store).await;
+        self.check_clear(store).await;
+        self.check_concurrent_writes(store).await;
+        if self.unicode_keys {
+            self.check_unicode_keys(store).await;
+        }
+        self.check_large_values(store).await;
+        store.clear().await.expect("clear failed");
+        for key in self.written_keys() {
+            assert_eq!(get(store, &key).await, None, "clear must leave nothing behind");
+        }
+    }
+
+    /// Every key the checks write to
+    fn written_keys(&self) -> Vec<String> {
+        let mut names = vec![
+            "getsetdel".to_string(),
+            "overwrite".to_string(),
+            "overwritedel".to_string(),
+            "clearone".to_string(),
+            "cleartwo".to_string(),
+            "concurrentsame".to_string(),
+            "large".to_string(),
+        ];
+        names.extend((0..4).map(|i| format!("concurrent{i}")));
+        if self.unicode_keys {
+            names.extend(UNICODE_KEY_NAMES.map(str::to_string));
+        }
+        names.iter().map(|name| self.key(name)).collect()
+    }
+
+    /// Missing keys read as `None`, written values read back and deleted keys are gone
+    pub async fn check_get_set_del<S>(&self, store: &S)
+    where
+        S: Store<String, String>,
+        S::Error: Debug,
+    {
+        let key = self.key("getsetdel");
+        assert_eq!(get(store, &key).await, None, "missing key must read as None");
+        store.del(&key).await.expect("deleting a missing key must succeed");
+
+        set(store, &key, "value").await;
+        assert_eq!(get(store, &key).await.as_deref(), Some("value"));
+        store.del(&key).await.expect("del failed");
+        assert_eq!(get(store, &key).await, None, "deleted key must read as None");
+    }
+
+    /// A second write replaces the first one
+    pub async fn check_overwrite<S>(&self, store: &S)
+    where
+        S: Store<String, String>,
+        S::Error: Debug,
+    {
+        let key = self.key("overwrite");
+        set(store, &key, "first").await;
+        set(store, &key, "second").await;
+        assert_eq!(get(store, &key).await.as_deref(), Some("second"));
+        // A read between writes must not keep the next write from succeeding
+        set(store, &key, "third").await;
+        assert_eq!(get(store, &key).await.as_deref(), Some("third"));
+
+        // Deleting an overwritten key must not leave an earlier value behind
+        let key = self.key("overwritedel");
+        set(store, &key, "first").await;
+        set(store, &key, "second").await;
+        store.del(&key).await.expect("del failed");
+        assert_eq!(get(store, &key).await, None, "deleted key must read as None");
+    }
+
+    /// Clearing removes every key, including overwritten ones
+    pub async fn check_clear<S>(&self, store: &S)
+    where
+        S: Store<String, String>,
+        S::Error: Debug,
+    {
+        let (first, second) = (self.key("clearone"), self.key("cleartwo"));
+        set(store, &first, "one").await;
+        set(store, &second, "two").await;
+        set(store, &second, "three").await;
+        store.clear().await.expect("clear failed");
+        assert_eq!(get(store, &first).await, None, "clear must remove every key");
+        assert_eq!(get(store, &second).await, None, "clear must remove every key");
+    }
+
+    /// Concurrent writes to different keys all land, and concurrent writes to one key leave one
+    /// of the written values
+    pub async fn check_concurrent_writes<S>(&self, store: &S)
+    where
+        S: Store<String, String>,
+        S::Error: Debug,
+    {
+        let keys = [0, 1, 2, 3].map(|i| self.key(&format!("concurrent{i}")));
+        tokio::join!(
+            set(store, &keys[0], "0"),
+            set(store, &keys[1], "1"),
+            set(store, &keys[2], "2"),
+            set(store, &keys[3], "3"),
+        );
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(get(store, key).await, Some(i.to_string()));
+        }
+
+        let key = self.key("concurrentsame");
+        tokio::join!(
+            set(store, &key, "a"),
+            set(store, &key, "b"),
+            set(store, &key, "c"),
+        );
+        let value = get(store, &key).await;
+        assert!(
+            matches!(value.as_deref(), Some("a" | "b" | "c")),
+            "concurrent writes left {value:?}"
+        );
+    }
+
+    /// Keys and values outside of ASCII round-trip unchanged and stay distinct
+    pub async fn check_unicode_keys<S>(&self, store: &S)
+    where
+        S: Store<String, String>,
+        S::Error: Debug,
+    {
+        let keys = UNICODE_KEY_NAMES.map(|name| self.key(name));
+        for key in &keys {
+            set(store, key, &format!("{key} → žluťoučký kůň 🐎")).await;
+        }
+        for key in &keys {
+            assert_eq!(
+                get(store, key).await,
+                Some(format!("{key} → žluťoučký kůň 🐎"))
+            );
+        }
+    }
+
+    /// Large values round-trip unchanged
+    pub async fn check_large_values<S>(&self, store: &S)
+    where
+        S: Store<String, String>,
+        S::Error: Debug,
+    {
+        let key = self.key("large");
+        let value: String = "0123456789abcdef"
+            .chars()
+            .cycle()
+            .take(self.large_value_bytes)
+            .collect();
+        set(store, &key, &value).await;
+        let stored = get(store, &key).await.expect("large value must be stored");
+        assert!(stored == value, "large value changed in the store");
+    }
+}
+
+impl Default for StoreConformance {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+async fn get<S>(store: &S, key: &str) -> Option<String>
+where
+    S: Store<String, String>,
+    S::Error: Debug,
+{
+    store
+        .get(&key.to_string())
+        .await
+        .unwrap_or_else(|e| panic!("get({key}) failed: {e:?}"))
+}
+
+async fn set<S>(store: &S, key: &str, value: &str)
+where
+    S: Store<String, String>,
+    S::Error: Debug,
+{
+    store
+        .set(key.to_string(), value.to_string())
+        .await
+        .unwrap_or_else(|e| panic!("set({key}) failed: {e:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{SqliteSessionStore, SqliteStateStore};
+    use atrium_common::store::memory::MemoryStore;
+
+    #[tokio::test]
+    async fn test_sqlite_session_store_conforms() {
+        let store = SqliteSessionStore::new(sqlite_test_pool().await);
+        StoreConformance::new().skip_unicode_keys().run(&store).await;
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_state_store_conforms() {
+        let store = SqliteStateStore::new(sqlite_test_pool().await);
+        StoreConformance::new().key_prefix("").run(&store).await;
+    }
+
+    #[tokio::test]
+    async fn test_memory_store_conforms() {
+        StoreConformance::new().run(&MemoryStore::default()).await;
+    }
+}
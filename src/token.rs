@@ -0,0 +1,514 @@
+/// Refresh and revocation requests to the authorization server of a stored session
+///
+/// atrium only refreshes a token set once it has expired, while an agent makes a request, and
+/// it only revokes access tokens. [TokenClient] talks to the token and revocation endpoints of
+/// the server that issued a session directly, so sessions can be refreshed ahead of expiry and
+/// their refresh tokens revoked (RFC 7009). Requests carry a DPoP proof made with the session's
+/// key and authenticate the client like atrium does.
+use atrium_api::{
+    types::string::Datetime,
+    xrpc::{
+        http::{self, Method, Request, Response, StatusCode},
+        HttpClient,
+    },
+};
+use atrium_oauth::{
+    store::session::Session, DefaultHttpClient, DpopClient, OAuthClientMetadata, TokenSet,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::Utc;
+use jose_jwk::{Jwk, Key};
+use p256::ecdsa::{signature::Signer, Signature, SigningKey};
+use serde::{de::DeserializeOwned, Deserialize};
+use std::{
+    collections::HashMap,
+    sync::{Arc, LazyLock, Mutex},
+};
+use thiserror::Error;
+
+/// Path of the authorization server metadata document (RFC 8414)
+const SERVER_METADATA_PATH: &str = "/.well-known/oauth-authorization-server";
+
+/// Client assertion type of `private_key_jwt` authentication (RFC 7523)
+const CLIENT_ASSERTION_TYPE_JWT_BEARER: &str =
+    "urn:ietf:params:oauth:client-assertion-type:jwt-bearer";
+
+/// Token clients created by [OAuthClientBuilder::build](crate::oauth::OAuthClientBuilder::build),
+/// by client id
+static BUILT_CLIENTS: LazyLock<Mutex<HashMap<String, TokenClient>>> =
+    LazyLock::new(Default::default);
+
+#[derive(Error, Debug)]
+pub enum TokenError {
+    #[error("Session has no refresh token")]
+    NoRefreshToken,
+    #[error("Authorization server {0} has no revocation endpoint")]
+    NoRevocationEndpoint(String),
+    #[error("Metadata of authorization server {0} names issuer {1}")]
+    IssuerMismatch(String, String),
+    #[error("Token response is for {0}, expected {1}")]
+    SubjectMismatch(String, String),
+    #[error("Authorization server {0} does not accept client authentication method {1}")]
+    UnsupportedAuthMethod(String, String),
+    #[error("No ES256 signing key for private_key_jwt client authentication")]
+    MissingSigningKey,
+    #[error("DPoP error: {0}")]
+    Dpop(String),
+    #[error("Invalid request: {0}")]
+    InvalidRequest(#[from] http::Error),
+    #[error("Request failed: {0}")]
+    Http(Box<dyn std::error::Error + Send + Sync + 'static>),
+    #[error("Authorization server responded with {0}: {1}")]
+    HttpStatus(StatusCode, String),
+    #[error("Invalid response: {0}")]
+    InvalidResponse(#[from] serde_json::Error),
+}
+
+/// Fields of the authorization server metadata used by [TokenClient]
+#[derive(Deserialize)]
+struct ServerMetadata {
+    issuer: String,
+    token_endpoint: String,
+    revocation_endpoint: Option<String>,
+    token_endpoint_auth_methods_supported: Option<Vec<String>>,
+    dpop_signing_alg_values_supported: Option<Vec<String>>,
+}
+
+/// Successful token endpoint response (RFC 6749 section 5.1)
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<i64>,
+    refresh_token: Option<String>,
+    scope: Option<String>,
+    sub: Option<String>,
+}
+
+/// Sends refresh and revocation requests on behalf of an OAuth client
+#[derive(Clone)]
+pub struct TokenClient {
+    client_id: String,
+    auth_method: String,
+    signing_keys: Vec<Jwk>,
+    http_client: Arc<DefaultHttpClient>,
+}
+
+impl TokenClient {
+    /// Creates a token client for the client described by `client_metadata`, e.g. the
+    /// `client_metadata` of a built [AtprotoOAuthClient](crate::oauth::AtprotoOAuthClient).
+    /// Confidential clients also need their [signing keys](TokenClient::signing_key);
+    /// [for_client](TokenClient::for_client) returns a token client that has them.
+    pub fn new(client_metadata: &OAuthClientMetadata) -> Self {
+        Self {
+            client_id: client_metadata.client_id.clone(),
+            auth_method: client_metadata
+                .token_endpoint_auth_method
+                .clone()
+                .unwrap_or_else(|| "none".to_string()),
+            signing_keys: Vec::new(),
+            http_client: Arc::new(DefaultHttpClient::default()),
+        }
+    }
+
+    /// The token client of the [AtprotoOAuthClient](crate::oauth::AtprotoOAuthClient) described by
+    /// `client_metadata`, with the signing keys it was built with. Clients not built by
+    /// [OAuthClientBuilder](crate::oauth::OAuthClientBuilder) get one without signing keys.
+    pub fn for_client(client_metadata: &OAuthClientMetadata) -> Self {
+        let built = BUILT_CLIENTS.lock().unwrap();
+        built
+            .get(&client_metadata.client_id)
+            .cloned()
+            .unwrap_or_else(|| Self::new(client_metadata))
+    }
+
+    /// Makes this token client the one [for_client](TokenClient::for_client) returns for its
+    /// client id
+    pub(crate) fn register(&self) {
+        let mut built = BUILT_CLIENTS.lock().unwrap();
+        built.insert(self.client_id.clone(), self.clone());
+    }
+
+    /// Add an ES256 key to sign `private_key_jwt` client assertions with
+    pub fn signing_key(mut self, jwk: Jwk) -> Self {
+        self.signing_keys.push(jwk);
+        self
+    }
+
+    /// Exchanges the refresh token of `session` for a new token set at the issuer of the
+    /// session. A refresh token that the server did not rotate is kept.
+    pub async fn refresh(&self, session: &Session) -> Result<TokenSet, TokenError> {
+        let token_set = &session.token_set;
+        let refresh_token = token_set
+            .refresh_token
+            .as_deref()
+            .ok_or(TokenError::NoRefreshToken)?;
+        let metadata = self.server_metadata(&token_set.iss).await?;
+        let response = self
+            .post(
+                session,
+                &metadata,
+                &metadata.token_endpoint,
+                &[("grant_type", "refresh_token"), ("refresh_token", refresh_token)],
+            )
+            .await?;
+        let response: TokenResponse = parse_json(response)?;
+        if let Some(sub) = response.sub.filter(|sub| sub != token_set.sub.as_str()) {
+            return Err(TokenError::SubjectMismatch(sub, token_set.sub.to_string()));
+        }
+        let expires_at = response.expires_in.map(|expires_in| {
+            Datetime::new((Utc::now() + chrono::Duration::seconds(expires_in)).fixed_offset())
+        });
+        Ok(TokenSet {
+            access_token: response.access_token,
+            refresh_token: response
+                .refresh_token
+                .or_else(|| token_set.refresh_token.clone()),
+            scope: response.scope.or_else(|| token_set.scope.clone()),
+            expires_at,
+            ..token_set.clone()
+        })
+    }
+
+    /// Revokes the refresh token and then the access token of `session` at its issuer, passing
+    /// the matching `token_type_hint`
+    pub async fn revoke(&self, session: &Session) -> Result<(), TokenError> {
+        let token_set = &session.token_set;
+        let metadata = self.server_metadata(&token_set.iss).await?;
+        let endpoint = metadata
+            .revocation_endpoint
+            .clone()
+            .ok_or_else(|| TokenError::NoRevocationEndpoint(metadata.issuer.clone()))?;
+        let tokens = token_set
+            .refresh_token
+            .iter()
+            .map(|token| (token, "refresh_token"))
+            .chain([(&token_set.access_token, "access_token")]);
+        for (token, hint) in tokens {
+            let params = [("token", token.as_str()), ("token_type_hint", hint)];
+            let response = self.post(session, &metadata, &endpoint, &params).await?;
+            // RFC 7009 answers 200, the atproto reference server 204
+            if !matches!(response.status(), StatusCode::OK | StatusCode::NO_CONTENT) {
+                return Err(status_error(response));
+            }
+        }
+        Ok(())
+    }
+
+    /// Fetches the metadata of `issuer`, checking that it describes that issuer
+    async fn server_metadata(&self, issuer: &str) -> Result<ServerMetadata, TokenError> {
+        let request = Request::builder()
+            .uri(format!("{}{SERVER_METADATA_PATH}", issuer.trim_end_matches('/')))
+            .method(Method::GET)
+            .body(Vec::new())?;
+        let response = self
+            .http_client
+            .send_http(request)
+            .await
+            .map_err(TokenError::Http)?;
+        let metadata: ServerMetadata = parse_json(response)?;
+        if metadata.issuer != issuer {
+            return Err(TokenError::IssuerMismatch(issuer.to_string(), metadata.issuer));
+        }
+        Ok(metadata)
+    }
+
+    /// Posts `params` with the client authentication to `endpoint`, with a DPoP proof made with
+    /// the key of `session`
+    async fn post(
+        &self,
+        session: &Session,
+        metadata: &ServerMetadata,
+        endpoint: &str,
+        params: &[(&str, &str)],
+    ) -> Result<Response<Vec<u8>>, TokenError> {
+        let body = {
+            let mut form = form_urlencoded::Serializer::new(String::new());
+            form.append_pair("client_id", &self.client_id);
+            if let Some(assertion) = self.client_assertion(metadata)? {
+                form.append_pair("client_assertion_type", CLIENT_ASSERTION_TYPE_JWT_BEARER);
+                form.append_pair("client_assertion", &assertion);
+            }
+            form.extend_pairs(params).finish()
+        };
+        let request = Request::builder()
+            .uri(endpoint)
+            .method(Method::POST)
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(body.into_bytes())?;
+        let dpop_client = DpopClient::new(
+            session.dpop_key.clone(),
+            self.http_client.clone(),
+            true,
+            &metadata.dpop_signing_alg_values_supported,
+        )
+        .map_err(|e| TokenError::Dpop(e.to_string()))?;
+        dpop_client
+            .send_http(request)
+            .await
+            .map_err(TokenError::Http)
+    }
+
+    /// Creates the `private_key_jwt` client assertion for a request to `metadata.issuer`, or
+    /// none for public clients
+    fn client_assertion(&self, metadata: &ServerMetadata) -> Result<Option<String>, TokenError> {
+        let supported = metadata
+            .token_endpoint_auth_methods_supported
+            .as_ref()
+            .is_none_or(|methods| methods.contains(&self.auth_method));
+        match self.auth_method.as_str() {
+            "none" if supported => Ok(None),
+            "private_key_jwt" if supported => {
+                let (kid, signing_key) = self
+                    .signing_keys
+                    .iter()
+                    .find_map(|jwk| match &jwk.key {
+                        Key::Ec(ec) => Some((
+                            jwk.prm.kid.clone()?,
+                            SigningKey::from(p256::SecretKey::try_from(ec).ok()?),
+                        )),
+                        _ => None,
+                    })
+                    .ok_or(TokenError::MissingSigningKey)?;
+                let iat = Utc::now().timestamp();
+                // https://datatracker.ietf.org/doc/html/rfc7523#section-3
+                let claims = serde_json::json!({
+                    "iss": self.client_id,
+                    "sub": self.client_id,
+                    "aud": metadata.issuer,
+                    "iat": iat,
+                    "exp": iat + 60,
+                    "jti": URL_SAFE_NO_PAD.encode(rand::random::<[u8; 16]>()),
+                });
+                let header = serde_json::json!({"alg": "ES256", "kid": kid});
+                let input = format!(
+                    "{}.{}",
+                    URL_SAFE_NO_PAD.encode(header.to_string()),
+                    URL_SAFE_NO_PAD.encode(claims.to_string())
+                );
+                let signature: Signature = signing_key.sign(input.as_bytes());
+                Ok(Some(format!(
+                    "{input}.{}",
+                    URL_SAFE_NO_PAD.encode(signature.to_bytes())
+                )))
+            }
+            method => Err(TokenError::UnsupportedAuthMethod(
+                metadata.issuer.clone(),
+                method.to_string(),
+            )),
+        }
+    }
+}
+
+/// Deserializes the body of a `200 OK` response
+fn parse_json<T: DeserializeOwned>(response: Response<Vec<u8>>) -> Result<T, TokenError> {
+    if response.status() != StatusCode::OK {
+        return Err(status_error(response));
+    }
+    Ok(serde_json::from_slice(response.body())?)
+}
+
+fn status_error(response: Response<Vec<u8>>) -> TokenError {
+    let body = String::from_utf8_lossy(response.body()).into_owned();
+    TokenError::HttpStatus(response.status(), body)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use axum::{
+        extract::State,
+        http::{HeaderMap, StatusCode as AxumStatusCode},
+        routing::{get, post},
+        Form, Json, Router,
+    };
+    use p256::{
+        ecdsa::{signature::Verifier, VerifyingKey},
+        pkcs8::DecodePrivateKey,
+    };
+    use std::{collections::HashMap, sync::Mutex};
+
+    /// Requests received by a [MockAuthorizationServer], as form parameters
+    pub(crate) type Received = Arc<Mutex<Vec<HashMap<String, String>>>>;
+
+    /// Authorization server on a local port answering metadata, token and revocation requests.
+    /// Every refresh rotates the tokens to `access-<n>` and `refresh-<n>`.
+    pub(crate) struct MockAuthorizationServer {
+        pub issuer: String,
+        pub token_requests: Received,
+        pub revocation_requests: Received,
+    }
+
+    impl MockAuthorizationServer {
+        pub async fn spawn() -> Self {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let issuer = format!("http://{}", listener.local_addr().unwrap());
+            let token_requests = Received::default();
+            let revocation_requests = Received::default();
+            let metadata = serde_json::json!({
+                "issuer": issuer,
+                "token_endpoint": format!("{issuer}/token"),
+                "revocation_endpoint": format!("{issuer}/revoke"),
+                "token_endpoint_auth_methods_supported": ["none", "private_key_jwt"],
+                "dpop_signing_alg_values_supported": ["ES256"],
+            });
+            let router = Router::new()
+                .route(
+                    SERVER_METADATA_PATH,
+                    get(move || async move { Json(metadata) }),
+                )
+                .route("/token", post(token).with_state(token_requests.clone()))
+                .route("/revoke", post(revoke).with_state(revocation_requests.clone()));
+            tokio::spawn(async move { axum::serve(listener, router).await });
+            Self {
+                issuer,
+                token_requests,
+                revocation_requests,
+            }
+        }
+
+        /// A session issued by this server to `did:plc:abc123`, whose access token expires in
+        /// `expires_in` minutes
+        pub fn session(&self, expires_in: i64) -> Session {
+            let dpop_key =
+                crate::keys::jwk_from_pem("dpop", crate::keys::tests::TEST_PRIVATE_KEY).unwrap();
+            serde_json::from_value(serde_json::json!({
+                "dpop_key": dpop_key.key,
+                "token_set": {
+                    "iss": self.issuer,
+                    "sub": "did:plc:abc123",
+                    "aud": "https://pds.example.com",
+                    "access_token": "access-0",
+                    "refresh_token": "refresh-0",
+                    "token_type": "DPoP",
+                    "expires_at": (Utc::now() + chrono::Duration::minutes(expires_in)).to_rfc3339(),
+                }
+            }))
+            .unwrap()
+        }
+    }
+
+    async fn token(
+        State(received): State<Received>,
+        headers: HeaderMap,
+        Form(params): Form<HashMap<String, String>>,
+    ) -> (AxumStatusCode, Json<serde_json::Value>) {
+        assert!(headers.contains_key("dpop"), "token request without DPoP proof");
+        let n = {
+            let mut received = received.lock().unwrap();
+            received.push(params.clone());
+            received.len()
+        };
+        if params.get("refresh_token").map(String::as_str) == Some("revoked") {
+            let error = serde_json::json!({"error": "invalid_grant"});
+            return (AxumStatusCode::BAD_REQUEST, Json(error));
+        }
+        let response = serde_json::json!({
+            "access_token": format!("access-{n}"),
+            "refresh_token": format!("refresh-{n}"),
+            "token_type": "DPoP",
+            "expires_in": 3600,
+            "scope": "atproto transition:generic",
+            "sub": "did:plc:abc123",
+        });
+        (AxumStatusCode::OK, Json(response))
+    }
+
+    async fn revoke(
+        State(received): State<Received>,
+        Form(params): Form<HashMap<String, String>>,
+    ) -> AxumStatusCode {
+        received.lock().unwrap().push(params);
+        AxumStatusCode::NO_CONTENT
+    }
+
+    /// A token client for a public client without signing keys
+    pub(crate) fn public_client() -> TokenClient {
+        TokenClient::new(&OAuthClientMetadata {
+            client_id: "http://localhost".to_string(),
+            token_endpoint_auth_method: Some("none".to_string()),
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_refresh_rotates_tokens() {
+        let server = MockAuthorizationServer::spawn().await;
+        let session = server.session(-5);
+        let token_set = public_client().refresh(&session).await.unwrap();
+        assert_eq!(token_set.access_token, "access-1");
+        assert_eq!(token_set.refresh_token.as_deref(), Some("refresh-1"));
+        assert_eq!(token_set.aud, "https://pds.example.com");
+        assert!(token_set.expires_at.unwrap().as_ref() > &Utc::now().fixed_offset());
+
+        let requests = server.token_requests.lock().unwrap();
+        assert_eq!(requests[0]["grant_type"], "refresh_token");
+        assert_eq!(requests[0]["refresh_token"], "refresh-0");
+        assert_eq!(requests[0]["client_id"], "http://localhost");
+        assert!(!requests[0].contains_key("client_assertion"));
+    }
+
+    #[tokio::test]
+    async fn test_refresh_signs_client_assertion() {
+        let server = MockAuthorizationServer::spawn().await;
+        let client = TokenClient::new(&OAuthClientMetadata {
+            client_id: "https://app.example.com/client-metadata.json".to_string(),
+            token_endpoint_auth_method: Some("private_key_jwt".to_string()),
+            ..Default::default()
+        });
+        let result = client.refresh(&server.session(-5)).await;
+        assert!(matches!(result, Err(TokenError::MissingSigningKey)));
+
+        let key = crate::keys::jwk_from_pem("kid00", crate::keys::tests::TEST_PRIVATE_KEY).unwrap();
+        client.signing_key(key).refresh(&server.session(-5)).await.unwrap();
+        let requests = server.token_requests.lock().unwrap();
+        assert_eq!(
+            requests[0]["client_assertion_type"],
+            CLIENT_ASSERTION_TYPE_JWT_BEARER
+        );
+        let assertion = &requests[0]["client_assertion"];
+        let (input, signature) = assertion.rsplit_once('.').unwrap();
+        let signature =
+            Signature::from_slice(&URL_SAFE_NO_PAD.decode(signature).unwrap()).unwrap();
+        let secret_key =
+            p256::SecretKey::from_pkcs8_pem(crate::keys::tests::TEST_PRIVATE_KEY).unwrap();
+        VerifyingKey::from(secret_key.public_key())
+            .verify(input.as_bytes(), &signature)
+            .unwrap();
+        let claims = input.split('.').nth(1).unwrap();
+        let claims: serde_json::Value =
+            serde_json::from_slice(&URL_SAFE_NO_PAD.decode(claims).unwrap()).unwrap();
+        assert_eq!(claims["iss"], "https://app.example.com/client-metadata.json");
+        assert_eq!(claims["aud"], server.issuer);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_refresh_is_an_error() {
+        let server = MockAuthorizationServer::spawn().await;
+        let mut session = server.session(-5);
+        session.token_set.refresh_token = Some("revoked".to_string());
+        let result = public_client().refresh(&session).await;
+        assert!(matches!(
+            result,
+            Err(TokenError::HttpStatus(StatusCode::BAD_REQUEST, body)) if body.contains("invalid_grant")
+        ));
+
+        session.token_set.refresh_token = None;
+        let result = public_client().refresh(&session).await;
+        assert!(matches!(result, Err(TokenError::NoRefreshToken)));
+    }
+
+    #[tokio::test]
+    async fn test_revoke_revokes_refresh_and_access_token() {
+        let server = MockAuthorizationServer::spawn().await;
+        public_client().revoke(&server.session(60)).await.unwrap();
+        let requests = server.revocation_requests.lock().unwrap();
+        let revoked: Vec<_> = requests
+            .iter()
+            .map(|params| (params["token"].as_str(), params["token_type_hint"].as_str()))
+            .collect();
+        assert_eq!(
+            revoked,
+            vec![("refresh-0", "refresh_token"), ("access-0", "access_token")]
+        );
+    }
+}